@@ -19,6 +19,32 @@ pub fn bench_crypto_puzzle(c: &mut Criterion) {
     }
 }
 
+pub fn bench_memory_hard_puzzle(c: &mut Criterion) {
+    let beacon = get_beacon();
+
+    for size in [4096] {
+        let mut group = c.benchmark_group(format!("memory_hard_puzzle-size={size}"));
+        group.sample_size(10);
+        for difficulty in [0, 2, 4, 6] {
+            group.bench_with_input(
+                BenchmarkId::from_parameter(difficulty),
+                &difficulty,
+                |b, &difficulty| {
+                    let data = vec![0u8; size as usize];
+                    b.iter(|| {
+                        anonycast::puzzle_solve(
+                            anonycast::PuzzleAlgorithm::Argon2id,
+                            &data,
+                            &beacon,
+                            difficulty,
+                        )
+                    });
+                },
+            );
+        }
+    }
+}
+
 pub fn bench_beacon_verify(c: &mut Criterion) {
     c.bench_function("beacon_verify", move |b| {
         let (chain, beacon) = get_chain_and_beacon();
@@ -104,10 +130,13 @@ pub fn bench_verify(c: &mut Criterion) {
 }
 
 fn get_chain_and_beacon() -> (drand::ChainInfo, drand::Beacon) {
+    let client = drand::BeaconSourceConfig::from_env()
+        .unwrap()
+        .caching_client(Default::default());
     run_future(async {
-        let chains = drand::chain_list().await.unwrap();
-        let chain = drand::chain_info(&chains[0]).await.unwrap();
-        let beacon = drand::chain_latest_randomness(&chains[0]).await.unwrap();
+        let chains = client.chain_list().await.unwrap();
+        let chain = client.chain_info(&chains[0]).await.unwrap();
+        let beacon = client.chain_latest_randomness(&chains[0]).await.unwrap();
         (chain, beacon)
     })
 }
@@ -126,6 +155,7 @@ where
 criterion_group!(
     benches,
     bench_crypto_puzzle,
+    bench_memory_hard_puzzle,
     bench_sign,
     bench_sign_ring,
     //bench_beacon_verify,
@@ -1,10 +1,10 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 use crypto::{PrivateKey, PublicKey, RingPublicKey};
 
 use crate::{
-    protocol::{Message, Signed, UpdateAllowedKeys},
-    DeaddropAddr, DeaddropConn, ModeOfOperation,
+    protocol::{Message, Request, RequestContext, Signed, UpdateAllowedKeys},
+    DeaddropAddr, DeaddropConn, ModeOfOperation, DEFAULT_MAX_FRAME_SIZE,
 };
 
 #[derive(Debug)]
@@ -12,24 +12,42 @@ pub struct Config {
     pub mode: ModeOfOperation,
     pub private_key: PrivateKey,
     pub deaddrop_addresses: Vec<DeaddropAddr>,
+    /// Keys the deaddrops must sign their handshake with. A deaddrop missing from it is trusted
+    /// with whatever key it presents.
+    pub deaddrop_keys: HashMap<DeaddropAddr, PublicKey>,
     pub allowed_sender_keys: Vec<RingPublicKey>,
     pub allowed_receiver_keys: Vec<PublicKey>,
+    /// Source of the drand beacons, defaults to the relay at [`drand::DEFAULT_API_URL`].
+    pub drand_client: Option<drand::CachingClient>,
 }
 
 pub async fn run(config: Config) -> std::io::Result<()> {
     tracing::info!("deadrop addresses = {:#?}", config.deaddrop_addresses);
-    let update = Signed::sign(
+    let drand_client = config
+        .drand_client
+        .unwrap_or_else(|| drand::CachingClient::new(drand::DEFAULT_API_URL));
+    let update = create_update_message(
+        &drand_client,
         &config.private_key,
-        Message::UpdateAllowedKeys(UpdateAllowedKeys {
-            allowed_sender_keys: config.allowed_sender_keys,
-            allowed_receiver_keys: config.allowed_receiver_keys,
-            beacon: drand::get_beacon_from_first_chain().await.unwrap(),
-        }),
-    );
+        config.allowed_sender_keys,
+        config.allowed_receiver_keys,
+    )
+    .await;
+    let chain = drand_client
+        .chain_list()
+        .await
+        .map_err(std::io::Error::other)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| std::io::Error::other("no chains found"))?;
 
     let mut streams = Vec::new();
     for addr in &config.deaddrop_addresses {
-        match DeaddropConn::connect(addr).await {
+        let key = config.deaddrop_keys.get(addr);
+        if key.is_none() {
+            tracing::warn!("key of deaddrop {addr:?} is not pinned");
+        }
+        match DeaddropConn::connect(addr, key, DEFAULT_MAX_FRAME_SIZE).await {
             Ok(stream) => streams.push(stream),
             Err(err) => {
                 tracing::error!("failed to connect to deaddrop {:?}: {}", addr, err);
@@ -39,18 +57,40 @@ pub async fn run(config: Config) -> std::io::Result<()> {
     }
 
     loop {
+        let round = match drand_client.chain_latest_randomness(&chain).await {
+            Ok(beacon) => beacon.round_number,
+            Err(err) => {
+                tracing::error!("failed to get latest beacon: {err}");
+                std::thread::sleep(Duration::from_secs(2));
+                continue;
+            }
+        };
+
         tracing::info!("sending allowed keys update to {} deaddrops", streams.len());
         for stream in &mut streams {
-            stream.send(&update).await;
-            // if let Err(err) = rle::serialize_and_write(&mut *stream, &update) {
-            //     tracing::error!("failed to send update message to {:?}: {}", stream, err);
-            // }
+            // every deaddrop gets its own request so it cannot be replayed elsewhere
+            let request = Signed::sign(
+                &config.private_key,
+                Request {
+                    context: RequestContext {
+                        nonce: crypto::random_bytes(),
+                        deaddrop: stream.public_key().clone(),
+                        chain: chain.clone(),
+                        round,
+                    },
+                    message: Message::UpdateAllowedKeys(update.clone()),
+                },
+            );
+            if let Err(err) = stream.send(&request).await {
+                tracing::error!("failed to send update message to {:?}: {}", stream, err);
+            }
         }
         std::thread::sleep(Duration::from_secs(2));
     }
 }
 
 pub async fn create_update_message(
+    drand_client: &drand::CachingClient,
     private_key: &PrivateKey,
     allowed_sender_keys: Vec<RingPublicKey>,
     allowed_receiver_keys: Vec<PublicKey>,
@@ -60,7 +100,7 @@ pub async fn create_update_message(
         UpdateAllowedKeys {
             allowed_sender_keys,
             allowed_receiver_keys,
-            beacon: drand::get_beacon_from_first_chain().await.unwrap(),
+            beacon: drand_client.get_beacon_from_first_chain().await.unwrap(),
         },
     )
 }
@@ -41,10 +41,13 @@ where
 }
 
 fn get_chain_and_beacon() -> (drand::ChainInfo, drand::Beacon) {
+    let client = drand::BeaconSourceConfig::from_env()
+        .unwrap()
+        .caching_client(Default::default());
     run_future(async {
-        let chains = drand::chain_list().await.unwrap();
-        let chain = drand::chain_info(&chains[0]).await.unwrap();
-        let beacon = drand::chain_latest_randomness(&chains[0]).await.unwrap();
+        let chains = client.chain_list().await.unwrap();
+        let chain = client.chain_info(&chains[0]).await.unwrap();
+        let beacon = client.chain_latest_randomness(&chains[0]).await.unwrap();
         (chain, beacon)
     })
 }
@@ -180,7 +183,7 @@ fn main() {
                 }
                 let instant_begin = Instant::now();
                 for pubkey in &pubkeys {
-                    black_box(crypto::encrypt(pubkey, message));
+                    black_box(crypto::encrypt(pubkey, message).unwrap());
                 }
                 let elapsed = instant_begin.elapsed();
                 total_time += elapsed;
@@ -7,18 +7,23 @@ use tokio::{sync::Barrier, task::JoinSet};
 #[tokio::main]
 async fn main() -> Result<()> {
     let (kpub, kpriv) = crypto::generate();
+    let drand_client = drand::BeaconSourceConfig::from_env()?.caching_client(Default::default());
     let mut config = anonycast::client::Config {
         mode: anonycast::ModeOfOperation::Open,
         private_key: Some(kpriv),
         ring_private_key: Default::default(),
         ring: Default::default(),
         receivers_keys: Default::default(),
+        encryption_algorithm: Default::default(),
         deaddrop_addresses: Default::default(),
+        deaddrop_keys: Default::default(),
         difficulty: 0,
+        puzzle_algorithm: Default::default(),
         acceptance_window: 100,
         asset_owner_public_key: Default::default(),
-        drand_chain: Some(drand::chain_list().await.unwrap()[0].clone()),
-        drand_client: Default::default(),
+        drand_chain: Some(drand_client.chain_list().await.unwrap()[0].clone()),
+        drand_client: Some(drand_client),
+        max_frame_size: None,
     };
     let client = anonycast::client::Client::new(config.clone())
         .await
@@ -33,7 +38,10 @@ async fn main() -> Result<()> {
             };
             requests.push(request);
         }
-        client.prepare_messages(requests).await
+        client
+            .prepare_messages(requests)
+            .await
+            .context("preparing messages")?
     };
     drop(client);
 
@@ -69,7 +77,9 @@ async fn main() -> Result<()> {
             client_set.spawn(async move {
                 barrier.wait().await;
                 for message in messages.clone() {
-                    client.send_prepared_message(message).await;
+                    if let Err(err) = client.send_prepared_message(message).await {
+                        tracing::warn!("failed to publish message: {err}");
+                    }
                 }
             });
         }
@@ -0,0 +1,147 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crypto::{Sha256, Sha256Hasher, SymmetricData, SymmetricKey};
+use serde::{Deserialize, Serialize};
+
+use crate::document::{Document, DocumentContent, DocumentKind};
+
+/// Size of the plaintext of every chunk but the last.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Maximum size of an encrypted chunk accepted by deaddrops.
+pub const MAX_CHUNK_SIZE: usize = CHUNK_SIZE + 1024;
+
+/// Content of a [`DocumentKind::Manifest`] document, describing content too large for a single
+/// document. The content is split in chunks that are encrypted with `key` and stored by deaddrops
+/// under their hash, so only readers of the manifest can reassemble it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub size: u64,
+    /// Hash of the reassembled content.
+    pub content_hash: Sha256,
+    pub key: SymmetricKey,
+    /// Hashes of the encrypted chunks, in order.
+    pub chunks: Vec<Sha256>,
+}
+
+impl Manifest {
+    /// Creates the manifest of the content read from `source`, one chunk at a time.
+    pub fn create<R: Read>(mut source: R) -> std::io::Result<Self> {
+        let key = crypto::symmetric_generate();
+        let mut hasher = Sha256Hasher::default();
+        let mut size = 0;
+        let mut chunks = Vec::new();
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        loop {
+            let read = read_chunk(&mut source, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            size += read as u64;
+            let chunk = encrypt_chunk(&key, chunks.len(), &buffer[..read]);
+            chunks.push(crypto::sha256(&chunk));
+            if read < CHUNK_SIZE {
+                break;
+            }
+        }
+        Ok(Self {
+            size,
+            content_hash: hasher.finalize(),
+            key,
+            chunks,
+        })
+    }
+
+    /// Reads the manifest carried by a document, if it is a decrypted manifest document.
+    pub fn from_document(document: &Document) -> Option<Self> {
+        match (document.kind, &document.content) {
+            (DocumentKind::Manifest, DocumentContent::Plaintext(data)) => {
+                bincode::deserialize(data).ok()
+            }
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// Reads and encrypts chunk `index` of `source`, the content the manifest was created from.
+    /// Encryption is deterministic so interrupted uploads can be resumed.
+    pub fn chunk<R: Read + Seek>(&self, index: usize, mut source: R) -> std::io::Result<Vec<u8>> {
+        source.seek(SeekFrom::Start((index * CHUNK_SIZE) as u64))?;
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let read = read_chunk(&mut source, &mut buffer)?;
+        let chunk = encrypt_chunk(&self.key, index, &buffer[..read]);
+        if self.chunks.get(index) != Some(&crypto::sha256(&chunk)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "content does not match the manifest",
+            ));
+        }
+        Ok(chunk)
+    }
+
+    /// Decrypts chunk `index`, returning `None` if it does not match the manifest.
+    pub fn decrypt_chunk(&self, index: usize, chunk: &[u8]) -> Option<Vec<u8>> {
+        if self.chunks.get(index)? != &crypto::sha256(chunk) {
+            return None;
+        }
+        let data: SymmetricData = bincode::deserialize(chunk).ok()?;
+        crypto::symmetric_try_decrypt(&self.key, &data)
+    }
+}
+
+fn encrypt_chunk(key: &SymmetricKey, index: usize, data: &[u8]) -> Vec<u8> {
+    // every chunk index is encrypted once per key
+    let mut hasher = Sha256Hasher::default();
+    hasher.update(key.as_bytes());
+    hasher.update(&(index as u64).to_be_bytes());
+    let hash = hasher.finalize();
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&hash.as_bytes()[..12]);
+    let data = crypto::symmetric_encrypt_with_nonce(key, &nonce, data);
+    bincode::serialize(&data).unwrap()
+}
+
+/// Fills `buffer` unless the end of `source` is reached first, returning the bytes read.
+fn read_chunk<R: Read>(mut source: R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match source.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manifest_chunks() {
+        let content = (0..CHUNK_SIZE * 2 + 10)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut source = std::io::Cursor::new(&content);
+        let manifest = Manifest::create(&mut source).unwrap();
+        assert_eq!(manifest.size, content.len() as u64);
+        assert_eq!(manifest.chunks.len(), 3);
+
+        let mut reassembled = Vec::new();
+        for index in 0..manifest.chunks.len() {
+            let chunk = manifest.chunk(index, &mut source).unwrap();
+            reassembled.extend(manifest.decrypt_chunk(index, &chunk).unwrap());
+        }
+        assert_eq!(reassembled, content);
+        assert_eq!(crypto::sha256(&reassembled), manifest.content_hash);
+
+        let chunk = manifest.chunk(0, &mut source).unwrap();
+        assert!(manifest.decrypt_chunk(1, &chunk).is_none());
+    }
+}
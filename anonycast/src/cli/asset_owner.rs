@@ -4,7 +4,7 @@ use anyhow::{Context, Result};
 use clap::Parser;
 use crypto::{PrivateKey, PublicKey, RingPublicKey};
 
-use super::make_deaddrop_addrs;
+use super::{make_deaddrop_addrs, make_deaddrop_keys, DrandArgs};
 
 #[derive(Debug, Parser)]
 pub struct Args {
@@ -23,22 +23,32 @@ pub struct Args {
     #[clap(long)]
     deaddrop_tor: Vec<String>,
 
+    /// Key the deaddrop must identify with, given for every TCP then onion deaddrop in order.
+    #[clap(long)]
+    deaddrop_key: Vec<PublicKey>,
+
     #[clap(long)]
     allowed_sender_key: Vec<RingPublicKey>,
 
     #[clap(long)]
     allowed_receiver_key: Vec<PublicKey>,
+
+    #[clap(flatten)]
+    drand: DrandArgs,
 }
 
 pub async fn main(args: Args) -> Result<()> {
     let deaddrop_addresses =
         make_deaddrop_addrs(args.deaddrop_tcp, args.deaddrop_tor, args.tor_proxy);
+    let deaddrop_keys = make_deaddrop_keys(&deaddrop_addresses, args.deaddrop_key)?;
     let config = anonycast::asset_owner::Config {
         mode: args.mode,
         private_key: args.private_key,
         deaddrop_addresses,
+        deaddrop_keys,
         allowed_sender_keys: args.allowed_sender_key,
         allowed_receiver_keys: args.allowed_receiver_key,
+        drand_client: Some(args.drand.caching_client()),
     };
     anonycast::asset_owner::run(config)
         .await
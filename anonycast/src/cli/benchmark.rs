@@ -10,10 +10,15 @@ use clap::Parser;
 use serde::{Deserialize, Serialize};
 use tokio::sync::Barrier;
 
+use super::DrandArgs;
+
 #[derive(Debug, Parser)]
 pub struct Args {
     #[clap(subcommand)]
     bench: Benchmark,
+
+    #[clap(flatten)]
+    drand: DrandArgs,
 }
 
 #[derive(Debug, Parser)]
@@ -97,10 +102,13 @@ struct LatencyArgs {
 }
 
 pub async fn main(args: Args) -> Result<()> {
+    let drand_client = args.drand.caching_client();
     match args.bench {
-        Benchmark::PublishTroughput(args) => benchmark_publish_troughput(args).await,
-        Benchmark::RetreiveTroughput(args) => benchmark_retrieve_troughput(args).await,
-        Benchmark::Latency(args) => benchmark_latency(args).await,
+        Benchmark::PublishTroughput(args) => benchmark_publish_troughput(args, drand_client).await,
+        Benchmark::RetreiveTroughput(args) => {
+            benchmark_retrieve_troughput(args, drand_client).await
+        }
+        Benchmark::Latency(args) => benchmark_latency(args, drand_client).await,
     }
 }
 
@@ -139,9 +147,13 @@ struct PublishTroughputResults {
     messages: Vec<PublishTroughputResultsMessage>,
 }
 
-async fn benchmark_publish_troughput(args: PublishTroughputArgs) -> Result<()> {
+async fn benchmark_publish_troughput(
+    args: PublishTroughputArgs,
+    drand_client: drand::CachingClient,
+) -> Result<()> {
     let deaddrop_addr = deaddrop_sockaddr(&args.deaddrop_address)?;
     let (client_priv_key, prepared_messages) = prepare_open_mode_messages_cached(
+        &drand_client,
         args.prepared_messages,
         args.message_size,
         args.difficulty,
@@ -158,12 +170,16 @@ async fn benchmark_publish_troughput(args: PublishTroughputArgs) -> Result<()> {
             ring_private_key: None,
             ring: None,
             receivers_keys: Default::default(),
+            encryption_algorithm: Default::default(),
             deaddrop_addresses: vec![anonycast::DeaddropAddr::Tcp(deaddrop_addr)],
+            deaddrop_keys: Default::default(),
             difficulty: args.difficulty as u8,
+            puzzle_algorithm: Default::default(),
             acceptance_window: args.acceptance_window as u64,
             asset_owner_public_key: None,
             drand_chain: Default::default(),
-            drand_client: Default::default(),
+            drand_client: Some(drand_client.clone()),
+            max_frame_size: None,
         };
 
         let message_queue = message_queue.clone();
@@ -191,7 +207,9 @@ async fn benchmark_publish_troughput(args: PublishTroughputArgs) -> Result<()> {
                 }
 
                 let timestamp = get_timestamp();
-                client.send_prepared_message(message).await;
+                if let Err(err) = client.send_prepared_message(message).await {
+                    tracing::warn!("failed to publish message: {err}");
+                }
                 let latency = get_timestamp() - timestamp;
                 let result_message = PublishTroughputResultsMessage {
                     client: client_id,
@@ -264,7 +282,10 @@ struct RetreiveTroughputResults {
     message_fetches: Vec<RetreiveTroughputResultsFetch>,
 }
 
-async fn benchmark_retrieve_troughput(args: RetreiveTroughputArgs) -> Result<()> {
+async fn benchmark_retrieve_troughput(
+    args: RetreiveTroughputArgs,
+    drand_client: drand::CachingClient,
+) -> Result<()> {
     const TOPIC: &'static str = "topic";
 
     let deaddrop_addr = deaddrop_sockaddr(&args.deaddrop_address)?;
@@ -277,28 +298,34 @@ async fn benchmark_retrieve_troughput(args: RetreiveTroughputArgs) -> Result<()>
             ring_private_key: Default::default(),
             ring: Default::default(),
             receivers_keys: Default::default(),
+            encryption_algorithm: Default::default(),
             deaddrop_addresses: vec![anonycast::DeaddropAddr::Tcp(deaddrop_addr)],
+            deaddrop_keys: Default::default(),
             difficulty: args.difficulty as u8,
+            puzzle_algorithm: Default::default(),
             acceptance_window: args.acceptance_window as u64,
             asset_owner_public_key: Default::default(),
             drand_chain: Default::default(),
-            drand_client: Default::default(),
+            drand_client: Some(drand_client.clone()),
+            max_frame_size: None,
         };
         let mut client = anonycast::client::Client::new(config).await?;
 
         let mut data = vec![0u8; args.message_size];
         for message_id in 0..args.message_count {
             data[0..4].copy_from_slice(&(message_id as u32).to_be_bytes());
-            client.send_message(TOPIC, &data).await;
+            client
+                .send_message(TOPIC, &data)
+                .await
+                .context("publishing message")?;
         }
     }
 
     let (_client_kpub, client_kpriv) = crypto::generate();
-    let drand_client = drand::CachingClient::new(drand::DEFAULT_API_URL);
     let mut handles = Vec::with_capacity(args.clients);
     let barrier = Arc::new(Barrier::new(args.clients + 1));
     let stop_flag = Arc::new(AtomicBool::new(false));
-    let drand_chain = drand::chain_list().await.unwrap()[0].clone();
+    let drand_chain = drand_client.chain_list().await.unwrap()[0].clone();
     for client_id in 0..args.clients {
         let config = anonycast::client::Config {
             mode: anonycast::ModeOfOperation::Open,
@@ -306,12 +333,16 @@ async fn benchmark_retrieve_troughput(args: RetreiveTroughputArgs) -> Result<()>
             ring_private_key: Default::default(),
             ring: Default::default(),
             receivers_keys: Default::default(),
+            encryption_algorithm: Default::default(),
             deaddrop_addresses: vec![anonycast::DeaddropAddr::Tcp(deaddrop_addr)],
+            deaddrop_keys: Default::default(),
             difficulty: args.difficulty as u8,
+            puzzle_algorithm: Default::default(),
             acceptance_window: args.acceptance_window as u64,
             asset_owner_public_key: Default::default(),
             drand_chain: Some(drand_chain.clone()),
             drand_client: Some(drand_client.clone()),
+            max_frame_size: None,
         };
 
         let barrier = barrier.clone();
@@ -333,7 +364,9 @@ async fn benchmark_retrieve_troughput(args: RetreiveTroughputArgs) -> Result<()>
             let mut fetch_id = 0;
             while !stop_flag.load(std::sync::atomic::Ordering::Relaxed) {
                 let timestamp = get_timestamp();
-                client.fetch_messages_bench(TOPIC).await;
+                if let Err(err) = client.fetch_messages_bench(TOPIC).await {
+                    tracing::warn!("failed to fetch messages: {err}");
+                }
                 let latency = get_timestamp() - timestamp;
                 fetches.push(RetreiveTroughputResultsFetch {
                     client: client_id,
@@ -395,6 +428,7 @@ struct PreparedMessageCache {
 }
 
 pub async fn prepare_open_mode_messages_cached(
+    drand_client: &drand::CachingClient,
     message_count: usize,
     message_size: usize,
     crypto_difficulty: usize,
@@ -420,8 +454,14 @@ pub async fn prepare_open_mode_messages_cached(
     }
 
     let (_pubkey, privkey) = crypto::generate();
-    let messages =
-        prepare_open_mode_messages(message_count, message_size, crypto_difficulty, &privkey).await;
+    let messages = prepare_open_mode_messages(
+        drand_client,
+        message_count,
+        message_size,
+        crypto_difficulty,
+        &privkey,
+    )
+    .await;
     let cached = PreparedMessageCache {
         message_size,
         private_key: privkey,
@@ -437,6 +477,7 @@ pub async fn prepare_open_mode_messages_cached(
 }
 
 pub async fn prepare_open_mode_messages(
+    drand_client: &drand::CachingClient,
     message_count: usize,
     message_size: usize,
     crypto_difficulty: usize,
@@ -448,12 +489,16 @@ pub async fn prepare_open_mode_messages(
         ring_private_key: Default::default(),
         ring: Default::default(),
         receivers_keys: Default::default(),
+        encryption_algorithm: Default::default(),
         deaddrop_addresses: Default::default(),
+        deaddrop_keys: Default::default(),
         difficulty: crypto_difficulty as u8,
+        puzzle_algorithm: Default::default(),
         acceptance_window: Default::default(),
         asset_owner_public_key: Default::default(),
         drand_chain: Default::default(),
-        drand_client: Default::default(),
+        drand_client: Some(drand_client.clone()),
+        max_frame_size: None,
     };
     let client = anonycast::client::Client::new(config).await.unwrap();
     let mut requests = Vec::with_capacity(message_count);
@@ -468,7 +513,7 @@ pub async fn prepare_open_mode_messages(
             content,
         });
     }
-    client.prepare_messages(requests).await
+    client.prepare_messages(requests).await.unwrap()
 }
 
 #[derive(Debug, Serialize)]
@@ -483,7 +528,7 @@ struct LatencyResults {
     retreive_latency: f64,
 }
 
-async fn benchmark_latency(args: LatencyArgs) -> Result<()> {
+async fn benchmark_latency(args: LatencyArgs, drand_client: drand::CachingClient) -> Result<()> {
     const TOPIC: &'static str = "topic";
     const TOPIC_WARMUP: &'static str = "warmup";
     const MESSAGE_DATA: &'static [u8] = &[0u8; 128];
@@ -548,6 +593,7 @@ async fn benchmark_latency(args: LatencyArgs) -> Result<()> {
     let asset_owner_update = if args.mode != anonycast::ModeOfOperation::Open {
         Some(
             anonycast::asset_owner::create_update_message(
+                &drand_client,
                 &asset_owner_private_key,
                 allowed_sender_keys.clone(),
                 receivers_keys.clone(),
@@ -565,9 +611,25 @@ async fn benchmark_latency(args: LatencyArgs) -> Result<()> {
             private_key: kpriv,
             address: args.deaddrop_listen_address[i],
             difficulty: args.difficulty as u8,
+            difficulty_adjustment: None,
+            puzzle_algorithms: vec![anonycast::PuzzleRequirement {
+                algorithm: Default::default(),
+                difficulty: args.difficulty as u8,
+            }],
             acceptance_window: args.acceptance_window as u64,
             asset_owner_key: Some(asset_owner_public_key.clone()),
             asset_owner_update: asset_owner_update.clone(),
+            data_dir: None,
+            retention: Default::default(),
+            peers: Vec::new(),
+            peer_keys: Default::default(),
+            sync_interval: Duration::from_secs(60),
+            max_frame_size: anonycast::DEFAULT_MAX_FRAME_SIZE,
+            max_connections: anonycast::deaddrop::DEFAULT_MAX_CONNECTIONS,
+            request_timeout: anonycast::deaddrop::DEFAULT_REQUEST_TIMEOUT,
+            rate_limit: None,
+            drand_client: Some(drand_client.clone()),
+            prefetch_chains: Vec::new(),
         };
         tokio::spawn(async move {
             if let Err(err) = anonycast::deaddrop::run(config).await {
@@ -596,27 +658,43 @@ async fn benchmark_latency(args: LatencyArgs) -> Result<()> {
             ring_private_key,
             ring,
             receivers_keys,
+            encryption_algorithm: Default::default(),
             deaddrop_addresses,
+            deaddrop_keys: Default::default(),
             difficulty: args.difficulty as u8,
+            puzzle_algorithm: Default::default(),
             acceptance_window: args.acceptance_window as u64,
             asset_owner_public_key: Some(asset_owner_public_key),
             drand_chain: Default::default(),
-            drand_client: Default::default(),
+            drand_client: Some(drand_client.clone()),
+            max_frame_size: None,
         };
 
         let mut client = anonycast::client::Client::new(config)
             .await
             .context("creating client")?;
 
-        client.send_message(TOPIC_WARMUP, &MESSAGE_DATA).await;
-        client.fetch_messages(TOPIC_WARMUP, 0).await;
+        client
+            .send_message(TOPIC_WARMUP, &MESSAGE_DATA)
+            .await
+            .context("publishing warmup message")?;
+        client
+            .fetch_messages(TOPIC_WARMUP, 0)
+            .await
+            .context("fetching warmup messages")?;
 
         let p_start = Instant::now();
-        client.send_message(TOPIC, &MESSAGE_DATA).await;
+        client
+            .send_message(TOPIC, &MESSAGE_DATA)
+            .await
+            .context("publishing message")?;
         let publish_latency = p_start.elapsed();
 
         let r_start = Instant::now();
-        client.fetch_messages(TOPIC, 0).await;
+        client
+            .fetch_messages(TOPIC, 0)
+            .await
+            .context("fetching messages")?;
         let retreive_latency = r_start.elapsed();
 
         (publish_latency, retreive_latency)
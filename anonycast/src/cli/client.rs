@@ -1,17 +1,23 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time::Duration;
 use std::time::Instant;
 
+use anonycast::chunk::Manifest;
 use anonycast::stats;
-use anonycast::ModeOfOperation;
-use anyhow::Result;
+use anonycast::{DocumentContent, ModeOfOperation};
+use anyhow::{Context, Result};
 use clap::Parser;
 use crypto::{PrivateKey, PublicKey, RingPrivateKey, RingPublicKey};
+use futures::StreamExt;
 
-use super::make_deaddrop_addrs;
+use super::{make_deaddrop_addrs, make_deaddrop_keys, DrandArgs};
 
 #[derive(Debug, Parser)]
 pub struct Args {
+    #[clap(subcommand)]
+    cmd: Option<Command>,
+
     #[clap(long)]
     mode: ModeOfOperation,
 
@@ -27,9 +33,17 @@ pub struct Args {
     #[clap(long)]
     receiver_key: Vec<PublicKey>,
 
+    /// Algorithm used to wrap the keys of encrypted documents.
+    #[clap(long, default_value_t)]
+    encryption_algorithm: crypto::Algorithm,
+
     #[clap(long, default_value = "2")]
     difficulty: u8,
 
+    /// Puzzle algorithm of published documents and document id requests.
+    #[clap(long, default_value_t)]
+    puzzle_algorithm: anonycast::PuzzleAlgorithm,
+
     #[clap(long, default_value = "100")]
     acceptance_window: u64,
 
@@ -42,6 +56,10 @@ pub struct Args {
     #[clap(long)]
     deaddrop_tor: Vec<String>,
 
+    /// Key the deaddrop must identify with, given for every TCP then onion deaddrop in order.
+    #[clap(long)]
+    deaddrop_key: Vec<PublicKey>,
+
     #[clap(long, default_value = "1")]
     number_of_requests: u64,
 
@@ -50,11 +68,46 @@ pub struct Args {
 
     #[clap(long, default_value = "0")]
     initial_delay: u64,
+
+    /// Only accept documents advertised by at least this many deaddrops.
+    #[clap(long)]
+    quorum: Option<usize>,
+
+    #[clap(flatten)]
+    drand: DrandArgs,
+}
+
+#[derive(Debug, Parser)]
+enum Command {
+    /// Print the documents published to a topic as they arrive.
+    Subscribe {
+        #[clap(long, default_value = "test-topic")]
+        topic: String,
+    },
+    /// Publish a file as a chunked document, resuming a previous upload of the same file.
+    Upload {
+        #[clap(long, default_value = "test-topic")]
+        topic: String,
+
+        file: PathBuf,
+
+        /// Where the manifest of the file is kept between attempts.
+        #[clap(long)]
+        manifest: Option<PathBuf>,
+    },
+    /// Download the chunked documents of a topic into a directory.
+    Download {
+        #[clap(long, default_value = "test-topic")]
+        topic: String,
+
+        dir: PathBuf,
+    },
 }
 
 pub async fn main(args: Args) -> Result<()> {
     let deaddrop_addresses =
         make_deaddrop_addrs(args.deaddrop_tcp, args.deaddrop_tor, args.tor_proxy);
+    let deaddrop_keys = make_deaddrop_keys(&deaddrop_addresses, args.deaddrop_key)?;
     let config = anonycast::client::Config {
         mode: args.mode,
         private_key: args.private_key,
@@ -65,18 +118,91 @@ pub async fn main(args: Args) -> Result<()> {
             Some(crypto::Ring::from(args.ring_key))
         },
         receivers_keys: args.receiver_key,
+        encryption_algorithm: args.encryption_algorithm,
         deaddrop_addresses,
+        deaddrop_keys,
         difficulty: args.difficulty,
+        puzzle_algorithm: args.puzzle_algorithm,
         acceptance_window: args.acceptance_window,
         asset_owner_public_key: args.asset_owner_public_key,
         drand_chain: Default::default(),
-        drand_client: Default::default(),
+        drand_client: Some(args.drand.caching_client()),
+        max_frame_size: None,
     };
 
     tracing::info!("creating client");
     let mut client = anonycast::client::Client::new(config).await.unwrap();
     tracing::info!("client created");
 
+    match args.cmd {
+        Some(Command::Subscribe { topic }) => {
+            let documents = client.subscribe(&topic).await.context("subscribing")?;
+            futures::pin_mut!(documents);
+            while let Some(document) = documents.next().await {
+                match document.content.content {
+                    DocumentContent::Plaintext(data) => {
+                        println!("{}", String::from_utf8_lossy(&data))
+                    }
+                    DocumentContent::Encrypted { .. } => println!("<encrypted>"),
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Upload {
+            topic,
+            file,
+            manifest,
+        }) => {
+            let manifest_path = manifest.unwrap_or_else(|| file.with_extension("manifest"));
+            let manifest = match std::fs::read(&manifest_path) {
+                Ok(data) => bincode::deserialize(&data).context("reading manifest")?,
+                Err(_) => {
+                    let source = std::fs::File::open(&file).context("opening file")?;
+                    let manifest = Manifest::create(std::io::BufReader::new(source))
+                        .context("creating manifest")?;
+                    std::fs::write(&manifest_path, manifest.to_bytes())
+                        .context("writing manifest")?;
+                    manifest
+                }
+            };
+            let source = std::fs::File::open(&file).context("opening file")?;
+            client
+                .upload_file(&topic, &manifest, source)
+                .await
+                .context("uploading file")?;
+            std::fs::remove_file(&manifest_path).context("removing manifest")?;
+            return Ok(());
+        }
+        Some(Command::Download { topic, dir }) => {
+            let documents = client
+                .fetch_messages(&topic, 0)
+                .await
+                .context("fetching messages")?;
+            std::fs::create_dir_all(&dir).context("creating directory")?;
+            let cache_dir = dir.join(".chunks");
+            for document in documents {
+                let Some(manifest) = Manifest::from_document(&document.content) else {
+                    continue;
+                };
+                let path = dir.join(manifest.content_hash.to_string());
+                // the file only appears under its name once completely downloaded
+                let partial_path = path.with_extension("partial");
+                let output = std::fs::File::create(&partial_path).context("creating file")?;
+                let downloaded = client
+                    .download_file(&manifest, &cache_dir, std::io::BufWriter::new(output))
+                    .await;
+                if let Err(err) = downloaded {
+                    let _ = std::fs::remove_file(&partial_path);
+                    return Err(err).context("downloading file");
+                }
+                std::fs::rename(&partial_path, &path).context("renaming file")?;
+                println!("{}", path.display());
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+
     std::thread::sleep(Duration::from_secs(args.initial_delay));
     for i in 0..args.number_of_requests {
         let topic = "test-topic";
@@ -84,12 +210,30 @@ pub async fn main(args: Args) -> Result<()> {
         tracing::info!("sending message {} -> {}", topic, content);
 
         let t_start = Instant::now();
-        client.send_message(topic, content.as_bytes()).await;
+        client
+            .send_message(topic, content.as_bytes())
+            .await
+            .context("sending message")?;
         stats::log(stats::Operation::Send, t_start.elapsed());
     }
 
     let t_start = Instant::now();
-    let messages = client.fetch_messages("test-topic", 0).await;
+    let messages = match args.quorum {
+        Some(quorum) => {
+            let retrieval = client
+                .fetch_messages_quorum("test-topic", 0, quorum)
+                .await
+                .context("fetching messages")?;
+            if !retrieval.report.is_consistent() {
+                tracing::warn!("deaddrops are inconsistent: {:#?}", retrieval.report);
+            }
+            retrieval.documents
+        }
+        None => client
+            .fetch_messages("test-topic", 0)
+            .await
+            .context("fetching messages")?,
+    };
     stats::log(stats::Operation::Retrieve, t_start.elapsed());
     tracing::debug!("{messages:#?}");
 
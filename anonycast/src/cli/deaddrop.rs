@@ -1,10 +1,12 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 use anonycast::ModeOfOperation;
 use anyhow::{Context, Result};
 use clap::Parser;
 use crypto::{PrivateKey, PublicKey};
 
+use super::{make_deaddrop_addrs, make_deaddrop_keys, DrandArgs};
+
 #[derive(Debug, Parser)]
 pub struct Args {
     #[clap(long)]
@@ -22,21 +24,167 @@ pub struct Args {
     #[clap(long, default_value = "2")]
     difficulty: u8,
 
+    /// Raise the difficulty of documents up to this value when the publication load exceeds the
+    /// target. The difficulty is fixed if not set.
+    #[clap(long)]
+    max_difficulty: Option<u8>,
+
+    /// Documents stored per round above which the difficulty of documents is raised.
+    #[clap(long, default_value = "10")]
+    target_documents_per_round: u32,
+
+    /// Rounds between two adjustments of the difficulty of documents.
+    #[clap(long, default_value = "10")]
+    difficulty_interval: u64,
+
+    /// Accept this puzzle algorithm for documents and document id requests, as
+    /// `<algorithm>[:<difficulty>]`. The difficulty defaults to the request difficulty.
+    #[clap(long = "puzzle-algorithm", default_value = "sha256", value_parser = parse_puzzle_algorithm)]
+    puzzle_algorithms: Vec<(anonycast::PuzzleAlgorithm, Option<u8>)>,
+
     #[clap(long, default_value = "100")]
     acceptance_window: u64,
+
+    /// Persist published documents in this directory instead of keeping them in memory.
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
+
+    /// Evict documents, and chunks uploaded, older than this many drand rounds.
+    #[clap(long)]
+    retention_max_age: Option<u64>,
+
+    /// Evict the oldest documents when the stored documents exceed this many bytes.
+    #[clap(long)]
+    retention_max_bytes: Option<u64>,
+
+    /// Evict the oldest documents of a topic when it has more than this many documents.
+    #[clap(long)]
+    retention_max_documents_per_topic: Option<usize>,
+
+    /// Reject chunk uploads when the stored chunks exceed this many bytes.
+    #[clap(long)]
+    retention_max_chunk_bytes: Option<u64>,
+
+    /// Interval, in seconds, between enforcements of the retention policy.
+    #[clap(long, default_value = "60")]
+    retention_interval: u64,
+
+    #[clap(long)]
+    tor_proxy: Option<SocketAddr>,
+
+    /// Replicate the documents of the deaddrop at this address.
+    #[clap(long)]
+    peer_tcp: Vec<SocketAddr>,
+
+    /// Replicate the documents of the deaddrop at this onion address.
+    #[clap(long)]
+    peer_tor: Vec<String>,
+
+    /// Key the peer must identify with, given for every TCP then onion peer in order.
+    #[clap(long)]
+    peer_key: Vec<PublicKey>,
+
+    /// Interval, in seconds, between synchronizations with the peer deaddrops.
+    #[clap(long, default_value = "60")]
+    sync_interval: u64,
+
+    /// Largest frame, in bytes, accepted from clients and peers.
+    #[clap(long, default_value_t = anonycast::DEFAULT_MAX_FRAME_SIZE)]
+    max_frame_size: u32,
+
+    /// Limit on the connections handled at once, subscriptions included, each buffering at most
+    /// one frame.
+    #[clap(long, default_value_t = anonycast::deaddrop::DEFAULT_MAX_CONNECTIONS)]
+    max_connections: usize,
+
+    /// Seconds allowed for a client to send its hello or the body of a request.
+    #[clap(long, default_value_t = anonycast::deaddrop::DEFAULT_REQUEST_TIMEOUT.as_secs())]
+    request_timeout: u64,
+
+    /// Documents each allowed sender may publish per epoch, unlimited if not set. Only enforced
+    /// in the sender restricted modes.
+    #[clap(long)]
+    documents_per_epoch: Option<u32>,
+
+    /// Length, in rounds, of the epochs documents are rate limited over.
+    #[clap(long, default_value = "10")]
+    epoch_rounds: u64,
+
+    #[clap(flatten)]
+    drand: DrandArgs,
+
+    /// Fetch the latest beacon of this drand chain as each round starts.
+    #[clap(long)]
+    prefetch_chain: Vec<String>,
 }
 
 pub async fn main(args: Args) -> Result<()> {
+    let peers = make_deaddrop_addrs(args.peer_tcp, args.peer_tor, args.tor_proxy);
+    let peer_keys = make_deaddrop_keys(&peers, args.peer_key)?;
     let config = anonycast::deaddrop::Config {
         mode: args.mode,
         private_key: args.private_key.unwrap_or_else(|| crypto::generate().1),
         address: args.address,
         difficulty: args.difficulty,
+        difficulty_adjustment: args.max_difficulty.map(|max_difficulty| {
+            anonycast::deaddrop::DifficultyAdjustment {
+                min_difficulty: args.difficulty,
+                max_difficulty,
+                target_documents_per_round: args.target_documents_per_round,
+                interval_rounds: args.difficulty_interval,
+            }
+        }),
+        puzzle_algorithms: args
+            .puzzle_algorithms
+            .into_iter()
+            .map(|(algorithm, difficulty)| anonycast::PuzzleRequirement {
+                algorithm,
+                difficulty: difficulty.unwrap_or(args.difficulty),
+            })
+            .collect(),
         acceptance_window: args.acceptance_window,
         asset_owner_key: args.asset_owner_key,
         asset_owner_update: None,
+        data_dir: args.data_dir,
+        retention: anonycast::deaddrop::RetentionPolicy {
+            max_age_rounds: args.retention_max_age,
+            max_total_bytes: args.retention_max_bytes,
+            max_documents_per_topic: args.retention_max_documents_per_topic,
+            max_chunk_bytes: args.retention_max_chunk_bytes,
+            interval: Duration::from_secs(args.retention_interval),
+        },
+        peers,
+        peer_keys,
+        sync_interval: Duration::from_secs(args.sync_interval),
+        max_frame_size: args.max_frame_size,
+        max_connections: args.max_connections,
+        request_timeout: Duration::from_secs(args.request_timeout),
+        rate_limit: args.documents_per_epoch.map(|documents_per_epoch| {
+            anonycast::protocol::RateLimit {
+                documents_per_epoch,
+                epoch_rounds: args.epoch_rounds,
+            }
+        }),
+        drand_client: Some(args.drand.caching_client()),
+        prefetch_chains: args.prefetch_chain,
     };
     anonycast::deaddrop::run(config)
         .await
         .context("while running deaddrop")
 }
+
+fn parse_puzzle_algorithm(s: &str) -> Result<(anonycast::PuzzleAlgorithm, Option<u8>), String> {
+    let (algorithm, difficulty) = match s.split_once(':') {
+        Some((algorithm, difficulty)) => (algorithm, Some(difficulty)),
+        None => (s, None),
+    };
+    let algorithm = algorithm.parse().map_err(|err| format!("{err}"))?;
+    let difficulty = difficulty
+        .map(|difficulty| {
+            difficulty
+                .parse()
+                .map_err(|_| "invalid difficulty".to_string())
+        })
+        .transpose()?;
+    Ok((algorithm, difficulty))
+}
@@ -5,6 +5,10 @@ use clap::Parser;
 pub struct Args {
     #[clap(long)]
     ring: bool,
+
+    /// Algorithm of the generated key, ignored for ring keys.
+    #[clap(long, default_value_t)]
+    algorithm: crypto::Algorithm,
 }
 
 pub async fn main(args: Args) -> Result<()> {
@@ -12,7 +16,7 @@ pub async fn main(args: Args) -> Result<()> {
         let (_, key) = crypto::ring_generate();
         println!("{key}");
     } else {
-        let (_, key) = crypto::generate();
+        let (_, key) = crypto::generate_with(args.algorithm);
         println!("{key}");
     }
     Ok(())
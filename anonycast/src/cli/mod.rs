@@ -1,8 +1,9 @@
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
 
 use anonycast::DeaddropAddr;
 use anyhow::Result;
 use clap::Parser;
+use crypto::PublicKey;
 use tracing_chrome::ChromeLayerBuilder;
 use tracing_subscriber::{
     fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
@@ -53,6 +54,38 @@ pub async fn main() -> Result<()> {
     }
 }
 
+#[derive(Debug, Parser)]
+struct DrandArgs {
+    /// Comma separated URLs of drand relays, or `local[:<period>]` for a local chain only meant
+    /// for testing.
+    #[clap(long, global = true, default_value_t)]
+    drand: drand::BeaconSourceConfig,
+
+    /// Seconds after which a drand relay that has not answered is skipped.
+    #[clap(long, global = true, default_value_t = drand::DEFAULT_RELAY_TIMEOUT.as_secs())]
+    drand_timeout: u64,
+
+    /// Number of drand relays that must return the same chain info and beacons.
+    #[clap(long, global = true, default_value = "1")]
+    drand_agreement: usize,
+
+    /// Trust the drand chain `<chain hash>:<public key>` besides the well-known ones.
+    #[clap(long, global = true)]
+    drand_pin: Vec<drand::PinnedChain>,
+}
+
+impl DrandArgs {
+    fn caching_client(&self) -> drand::CachingClient {
+        let client = self.drand.caching_client(drand::RelayOptions {
+            timeout: Duration::from_secs(self.drand_timeout),
+            agreement: self.drand_agreement,
+        });
+        self.drand_pin
+            .iter()
+            .fold(client, |client, pinned| client.pin(pinned.clone()))
+    }
+}
+
 fn make_deaddrop_addrs(
     tcp: Vec<SocketAddr>,
     tor: Vec<String>,
@@ -68,3 +101,15 @@ fn make_deaddrop_addrs(
     }
     addrs
 }
+
+/// Pairs the keys given on the command line with the deaddrops, in the order of
+/// [`make_deaddrop_addrs`]. Either every deaddrop or none has a key.
+fn make_deaddrop_keys(
+    addrs: &[DeaddropAddr],
+    keys: Vec<PublicKey>,
+) -> Result<HashMap<DeaddropAddr, PublicKey>> {
+    if !keys.is_empty() && keys.len() != addrs.len() {
+        anyhow::bail!("deaddrop key count must match the number of deaddrops");
+    }
+    Ok(addrs.iter().cloned().zip(keys).collect())
+}
@@ -1,20 +1,32 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Seek, Write},
+    path::Path,
+};
 
-use crypto::{PrivateKey, PublicKey, Ring, RingPrivateKey, Sha256};
+use crypto::{PrivateKey, PublicKey, Ring, RingPrivateKey, Sha256, Sha256Hasher};
+use futures::Stream;
 use rayon::iter::{IntoParallelIterator, ParallelIterator as _};
 use serde::{Deserialize, Serialize};
-use tokio::task::JoinSet;
+use tokio::{sync::mpsc, task::JoinSet};
 use tracing::Instrument;
 
 use crate::{
+    chunk::Manifest,
     crypto_puzzle_solve,
-    document::{Document, DocumentDrand, DocumentId, SignedDocument},
+    document::{Document, DocumentDrand, DocumentId, DocumentKind, SignedDocument},
     protocol::{
-        Message, PublishDocument, RetrieveDocumentIds, RetrieveDocuments, Signed, UpdateAllowedKeys,
+        exchange, unexpected_response, DocumentIdList, ExchangeError, Message, PublishDocument,
+        RateLimit, Request, RequestContext, Response, RetrieveChunk, RetrieveDocumentIds,
+        RetrieveDocuments, RetrieveMissingChunks, Signed, Subscribe, UpdateAllowedKeys,
+        UploadChunk, FEATURE_ADAPTIVE_DIFFICULTY, FEATURE_CHUNKS, FEATURE_SUBSCRIBE,
     },
-    DeaddropAddr, DeaddropConn, ModeOfOperation,
+    puzzle_solve, DeaddropAddr, DeaddropConn, ModeOfOperation, PuzzleAlgorithm,
+    DEFAULT_MAX_FRAME_SIZE,
 };
 
+pub use crate::protocol::DeaddropError;
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub mode: ModeOfOperation,
@@ -22,16 +34,233 @@ pub struct Config {
     pub ring_private_key: Option<RingPrivateKey>,
     pub ring: Option<Ring>,
     pub receivers_keys: Vec<PublicKey>,
+    /// Algorithm the keys of encrypted documents are wrapped with, documents are only readable
+    /// by receivers whose keys use it.
+    pub encryption_algorithm: crypto::Algorithm,
     pub deaddrop_addresses: Vec<DeaddropAddr>,
+    /// Keys the deaddrops must sign their handshake with. A deaddrop missing from it is trusted
+    /// with whatever key it presents.
+    pub deaddrop_keys: HashMap<DeaddropAddr, PublicKey>,
+    /// Puzzle difficulty of requests, replaced by the one the deaddrops advertise.
     pub difficulty: u8,
+    /// Puzzle algorithm of published documents and document id requests, which every deaddrop
+    /// must accept.
+    pub puzzle_algorithm: PuzzleAlgorithm,
     pub acceptance_window: u64,
     pub asset_owner_public_key: Option<PublicKey>,
     pub drand_chain: Option<String>,
+    /// Source of the drand beacons, defaults to the relay at [`drand::DEFAULT_API_URL`].
     pub drand_client: Option<drand::CachingClient>,
+    /// Largest frame accepted from deaddrops, defaults to [`DEFAULT_MAX_FRAME_SIZE`].
+    pub max_frame_size: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// Failed to communicate with a deaddrop.
+    Transport(std::io::Error),
+    /// Failed to obtain chain information or randomness from drand.
+    Drand(drand::ClientError),
+    /// A deaddrop response or key update carried an invalid signature.
+    Signature(String),
+    /// A deaddrop sent a response that does not match the request.
+    Protocol(String),
+    /// A deaddrop rejected the request.
+    Rejected(DeaddropError),
+    /// The client configuration lacks something required by the mode of operation.
+    Configuration(String),
+    /// Failed to read or write local files.
+    Storage(std::io::Error),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Transport(err) => write!(f, "deaddrop transport error: {err}"),
+            ClientError::Drand(err) => write!(f, "drand error: {err}"),
+            ClientError::Signature(reason) => write!(f, "invalid signature: {reason}"),
+            ClientError::Protocol(reason) => write!(f, "protocol error: {reason}"),
+            ClientError::Rejected(err) => err.fmt(f),
+            ClientError::Configuration(reason) => write!(f, "invalid configuration: {reason}"),
+            ClientError::Storage(err) => write!(f, "local storage error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Transport(err) => Some(err),
+            ClientError::Drand(err) => Some(err),
+            ClientError::Rejected(err) => Some(err),
+            ClientError::Storage(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(value: std::io::Error) -> Self {
+        ClientError::Transport(value)
+    }
+}
+
+impl From<drand::ClientError> for ClientError {
+    fn from(value: drand::ClientError) -> Self {
+        ClientError::Drand(value)
+    }
+}
+
+impl From<DeaddropError> for ClientError {
+    fn from(value: DeaddropError) -> Self {
+        ClientError::Rejected(value)
+    }
+}
+
+impl From<ExchangeError> for ClientError {
+    fn from(value: ExchangeError) -> Self {
+        match value {
+            ExchangeError::Transport(err) => ClientError::Transport(err),
+            ExchangeError::Signature(reason) => ClientError::Signature(reason),
+            ExchangeError::Protocol(reason) => ClientError::Protocol(reason),
+            ExchangeError::Rejected(err) => ClientError::Rejected(err),
+        }
+    }
+}
+
+/// Parameters agreed on with the deaddrops during the handshake.
+struct Negotiated {
+    /// Features supported by all deaddrops.
+    features: Vec<String>,
+    /// Rate limit documents must be signed for.
+    rate_limit: Option<RateLimit>,
+    /// Difficulty of every accepted puzzle algorithm.
+    puzzle_difficulties: HashMap<PuzzleAlgorithm, u8>,
+}
+
+/// Checks the handshake of every deaddrop against the configuration and adopts the request
+/// parameters they advertise.
+fn negotiate(config: &mut Config, deaddrops: &[DeaddropConn]) -> Result<Negotiated, ClientError> {
+    let mut difficulty = None;
+    let mut puzzle_difficulties = HashMap::<PuzzleAlgorithm, u8>::default();
+    let mut acceptance_window = None;
+    let mut rate_limit: Option<RateLimit> = None;
+    let mut features: Option<Vec<String>> = None;
+    for deaddrop in deaddrops {
+        // the handshake signature, nonce and version were checked when connecting
+        let hello = &deaddrop.hello().content;
+        if hello.mode != config.mode {
+            return Err(ClientError::Configuration(format!(
+                "deaddrop operates in {} mode but the client is configured for {} mode",
+                hello.mode, config.mode
+            )));
+        }
+        if !hello
+            .puzzle_algorithms
+            .iter()
+            .any(|requirement| requirement.algorithm == config.puzzle_algorithm)
+        {
+            return Err(ClientError::Configuration(format!(
+                "deaddrop does not accept {} puzzles",
+                config.puzzle_algorithm
+            )));
+        }
+        for requirement in &hello.puzzle_algorithms {
+            let difficulty = puzzle_difficulties
+                .entry(requirement.algorithm)
+                .or_insert(requirement.difficulty);
+            if *difficulty != requirement.difficulty {
+                return Err(ClientError::Configuration(format!(
+                    "deaddrops require different {} puzzle difficulties",
+                    requirement.algorithm
+                )));
+            }
+        }
+        match difficulty {
+            Some(difficulty) if difficulty != hello.difficulty => {
+                return Err(ClientError::Configuration(
+                    "deaddrops require different puzzle difficulties".to_string(),
+                ))
+            }
+            _ => difficulty = Some(hello.difficulty),
+        }
+        match acceptance_window {
+            Some(window) if window != hello.acceptance_window => {
+                tracing::warn!("deaddrops advertise different acceptance windows");
+            }
+            _ => acceptance_window = Some(hello.acceptance_window),
+        }
+        // deaddrops without a rate limit accept documents signed for any epoch
+        match (rate_limit, hello.rate_limit) {
+            (Some(limit), Some(other)) if limit.epoch_rounds != other.epoch_rounds => {
+                return Err(ClientError::Configuration(
+                    "deaddrops rate limit documents over different epochs".to_string(),
+                ))
+            }
+            (None, other) => rate_limit = other,
+            _ => {}
+        }
+        features = Some(match features {
+            Some(mut features) => {
+                features.retain(|f| hello.features.contains(f));
+                features
+            }
+            None => hello.features.clone(),
+        });
+    }
+
+    if let Some(difficulty) = difficulty {
+        if difficulty != config.difficulty {
+            tracing::info!("using deaddrop puzzle difficulty {difficulty}");
+            config.difficulty = difficulty;
+        }
+    }
+    if let Some(acceptance_window) = acceptance_window {
+        config.acceptance_window = acceptance_window;
+    }
+    Ok(Negotiated {
+        features: features.unwrap_or_default(),
+        rate_limit,
+        puzzle_difficulties,
+    })
+}
+
+/// Forwards the documents pushed by a deaddrop for a subscription until either side goes away.
+async fn forward_subscription(
+    deaddrop: DeaddropConn,
+    nonce: [u8; 16],
+    sender: mpsc::Sender<SignedDocument>,
+) -> Result<(), ClientError> {
+    loop {
+        let response: Signed<Response> = tokio::select! {
+            _ = sender.closed() => return Ok(()),
+            response = deaddrop.read() => response?,
+        };
+        if response.content.nonce != nonce {
+            return Err(ClientError::Protocol(
+                "deaddrop push does not match the subscription".to_string(),
+            ));
+        }
+        if !response.verify_with(deaddrop.public_key()) {
+            return Err(ClientError::Signature(
+                "invalid deaddrop response signature".to_string(),
+            ));
+        }
+        match response.content.message {
+            Message::DocumentList(list) => {
+                for document in list.documents {
+                    if sender.send(document).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            other => return Err(unexpected_response("subscription", other).into()),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PreparedMessage(Signed<Message>);
+pub struct PreparedMessage(SignedDocument);
 
 #[derive(Debug, Clone)]
 pub struct PrepareMessageRequest {
@@ -39,154 +268,419 @@ pub struct PrepareMessageRequest {
     pub content: String,
 }
 
+/// Differences between deaddrops observed during a quorum retrieval.
+#[derive(Debug, Default)]
+pub struct ConsistencyReport {
+    /// Valid documents that a deaddrop did not advertise although it answered.
+    pub withheld: HashMap<DeaddropAddr, Vec<DocumentId>>,
+    /// Documents advertised by fewer deaddrops than the quorum, which were not fetched.
+    pub below_quorum: Vec<DocumentId>,
+    /// Accepted documents that no advertising deaddrop delivered.
+    pub unavailable: Vec<DocumentId>,
+    /// Documents a deaddrop delivered that failed verification or belong to another topic.
+    pub invalid: HashMap<DeaddropAddr, Vec<DocumentId>>,
+    /// Requests that failed, by deaddrop.
+    pub failures: Vec<(DeaddropAddr, ClientError)>,
+}
+
+impl ConsistencyReport {
+    /// Whether every deaddrop answered and advertised the same documents.
+    pub fn is_consistent(&self) -> bool {
+        self.withheld.is_empty()
+            && self.below_quorum.is_empty()
+            && self.unavailable.is_empty()
+            && self.invalid.is_empty()
+            && self.failures.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct QuorumRetrieval {
+    pub documents: Vec<SignedDocument>,
+    pub report: ConsistencyReport,
+}
+
 pub struct Client {
     config: Config,
     drand_client: drand::CachingClient,
     drand_chain: String,
     deaddrops: Vec<DeaddropConn>,
+    /// Address of each connection in `deaddrops`.
+    deaddrop_addresses: Vec<DeaddropAddr>,
     sender_ring: Ring,
     receiver_keys: Vec<PublicKey>,
+    features: Vec<String>,
+    rate_limit: Option<RateLimit>,
+    /// Least difficulty the deaddrops require from documents of each puzzle algorithm.
+    puzzle_difficulties: HashMap<PuzzleAlgorithm, u8>,
 }
 
 impl Client {
-    pub async fn new(config: Config) -> std::io::Result<Self> {
+    pub async fn new(mut config: Config) -> Result<Self, ClientError> {
         let mut set = JoinSet::new();
-        #[allow(clippy::unnecessary_to_owned)]
-        for addr in config.deaddrop_addresses.iter().cloned() {
-            set.spawn(async move { DeaddropConn::connect(&addr).await });
+        let max_frame_size = config.max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+        for addr in &config.deaddrop_addresses {
+            let addr = addr.clone();
+            let key = config.deaddrop_keys.get(&addr).cloned();
+            if key.is_none() {
+                tracing::warn!("key of deaddrop {addr:?} is not pinned");
+            }
+            set.spawn(async move {
+                let result = DeaddropConn::connect(&addr, key.as_ref(), max_frame_size).await;
+                (addr, result)
+            });
         }
 
         let mut conns = Vec::new();
-        while let Some(Ok(result)) = set.join_next().await {
-            let conn = result?;
-            conns.push(conn);
+        let mut deaddrop_addresses = Vec::new();
+        while let Some(Ok((addr, result))) = set.join_next().await {
+            conns.push(result?);
+            deaddrop_addresses.push(addr);
         }
+        let Negotiated {
+            features,
+            rate_limit,
+            puzzle_difficulties,
+        } = negotiate(&mut config, &conns)?;
 
+        let drand_client = match config.drand_client {
+            Some(ref client) => client.clone(),
+            None => drand::CachingClient::new(drand::DEFAULT_API_URL),
+        };
         let drand_chain = match config.drand_chain {
             Some(ref chain) => chain.clone(),
-            None => drand::chain_list()
-                .await
-                .map_err(std::io::Error::other)?
+            None => drand_client
+                .chain_list()
+                .await?
                 .into_iter()
                 .next()
-                .ok_or_else(|| std::io::Error::other("no chains found"))?,
+                .ok_or_else(|| ClientError::Configuration("no drand chains found".to_string()))?,
         };
+        if !drand_client.is_pinned(&drand_chain) {
+            return Err(ClientError::Configuration(format!(
+                "drand chain {drand_chain} is not pinned"
+            )));
+        }
         tracing::info!("drand chain: {drand_chain}");
 
-        let drand_client = match config.drand_client {
-            Some(ref client) => client.clone(),
-            None => drand::CachingClient::new(drand::DEFAULT_API_URL),
-        };
         Ok(Self {
             config,
             drand_client,
             drand_chain,
             deaddrops: conns,
+            deaddrop_addresses,
             sender_ring: Default::default(),
             receiver_keys: Default::default(),
+            features,
+            rate_limit,
+            puzzle_difficulties,
         })
     }
 
-    pub async fn prepare_message(&self, topic: &str, data: &[u8]) -> PreparedMessage {
-        let document_drand = self.create_document_drand().await;
-        let msg = self.create_message(topic, data, document_drand);
-        PreparedMessage(msg)
+    /// Least difficulty of the puzzles of documents and document id requests solved with the
+    /// configured puzzle algorithm, which every deaddrop accepts.
+    fn puzzle_difficulty(&self) -> u8 {
+        self.puzzle_difficulties[&self.config.puzzle_algorithm]
+    }
+
+    /// Whether every deaddrop advertised support for `feature` during the handshake.
+    pub fn supports_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    pub async fn prepare_message(
+        &self,
+        topic: &str,
+        data: &[u8],
+    ) -> Result<PreparedMessage, ClientError> {
+        let document_drand = self.create_document_drand().await?;
+        let difficulty = self.document_difficulty(&document_drand).await?;
+        let msg = self.create_message(
+            topic,
+            DocumentKind::Inline,
+            data,
+            difficulty,
+            document_drand,
+        )?;
+        Ok(PreparedMessage(msg))
     }
 
     pub async fn prepare_messages(
         &self,
         requests: Vec<PrepareMessageRequest>,
-    ) -> Vec<PreparedMessage> {
-        let document_drand = self.create_document_drand().await;
+    ) -> Result<Vec<PreparedMessage>, ClientError> {
+        let document_drand = self.create_document_drand().await?;
+        let difficulty = self.document_difficulty(&document_drand).await?;
         tokio::task::block_in_place(move || {
             requests
                 .into_par_iter()
                 .map(|req| {
-                    self.create_message(&req.topic, req.content.as_bytes(), document_drand.clone())
+                    self.create_message(
+                        &req.topic,
+                        DocumentKind::Inline,
+                        req.content.as_bytes(),
+                        difficulty,
+                        document_drand.clone(),
+                    )
+                    .map(PreparedMessage)
                 })
-                .map(PreparedMessage)
-                .collect::<Vec<_>>()
+                .collect::<Result<Vec<_>, _>>()
         })
     }
 
-    pub async fn send_message(&mut self, topic: &str, data: &[u8]) {
-        self.update_keys().await;
-        let document_drand = self.create_document_drand().await;
-        let msg = self.create_message(topic, data, document_drand);
-        self.deaddrop_broadcast_publish(&msg).await;
+    pub async fn send_message(&mut self, topic: &str, data: &[u8]) -> Result<(), ClientError> {
+        self.update_keys().await?;
+        let document_drand = self.create_document_drand().await?;
+        let round = document_drand.beacon.round_number;
+        let difficulty = self.document_difficulty(&document_drand).await?;
+        let document = self.create_message(
+            topic,
+            DocumentKind::Inline,
+            data,
+            difficulty,
+            document_drand,
+        )?;
+        self.deaddrop_broadcast_publish(document, round).await
     }
 
-    pub async fn send_prepared_message(&mut self, PreparedMessage(msg): PreparedMessage) {
-        self.deaddrop_broadcast_publish(&msg).await;
+    pub async fn send_prepared_message(
+        &mut self,
+        PreparedMessage(document): PreparedMessage,
+    ) -> Result<(), ClientError> {
+        let round = self.current_round().await?;
+        self.deaddrop_broadcast_publish(document, round).await
     }
 
-    pub async fn fetch_messages_bench(&mut self, topic: &str) {
+    /// Uploads the chunks of `manifest` missing from each deaddrop, reading them from `source`,
+    /// then publishes the manifest to `topic`. An interrupted upload is resumed by calling this
+    /// again with the same manifest.
+    pub async fn upload_file<R: Read + Seek>(
+        &mut self,
+        topic: &str,
+        manifest: &Manifest,
+        mut source: R,
+    ) -> Result<(), ClientError> {
+        if !self.supports_feature(FEATURE_CHUNKS) {
+            return Err(ClientError::Configuration(
+                "not every deaddrop supports chunked documents".to_string(),
+            ));
+        }
+        self.update_keys().await?;
+
+        for stream in self.deaddrops.iter() {
+            let beacon = self
+                .drand_client
+                .chain_latest_randomness(&self.drand_chain)
+                .await?;
+            let request = self.sign_request(
+                stream,
+                beacon.round_number,
+                Message::RetrieveMissingChunks(RetrieveMissingChunks {
+                    chunks: manifest.chunks.clone(),
+                    beacon: beacon.clone(),
+                    chain: self.drand_chain.clone(),
+                    nonce_solution: crypto_puzzle_solve(&[], &beacon, self.config.difficulty),
+                }),
+            )?;
+            let missing = match exchange(stream.clone(), request, true).await? {
+                Message::ChunkHashes(list) => list.chunks,
+                other => return Err(unexpected_response("missing chunks request", other).into()),
+            };
+            tracing::info!(
+                "uploading {} of {} chunks",
+                missing.len(),
+                manifest.chunks.len()
+            );
+
+            for (index, hash) in manifest.chunks.iter().enumerate() {
+                if !missing.contains(hash) {
+                    continue;
+                }
+                let data = manifest
+                    .chunk(index, &mut source)
+                    .map_err(ClientError::Storage)?;
+                let beacon = self
+                    .drand_client
+                    .chain_latest_randomness(&self.drand_chain)
+                    .await?;
+                let nonce_solution = crypto_puzzle_solve(&data, &beacon, self.config.difficulty);
+                let request = self.sign_request(
+                    stream,
+                    beacon.round_number,
+                    Message::UploadChunk(UploadChunk {
+                        data,
+                        beacon,
+                        chain: self.drand_chain.clone(),
+                        nonce_solution,
+                    }),
+                )?;
+                match exchange(stream.clone(), request, true).await? {
+                    Message::Success => {}
+                    other => return Err(unexpected_response("chunk upload", other).into()),
+                }
+            }
+        }
+
+        let document_drand = self.create_document_drand().await?;
+        let round = document_drand.beacon.round_number;
+        let difficulty = self.document_difficulty(&document_drand).await?;
+        let document = self.create_message(
+            topic,
+            DocumentKind::Manifest,
+            &manifest.to_bytes(),
+            difficulty,
+            document_drand,
+        )?;
+        self.deaddrop_broadcast_publish(document, round).await
+    }
+
+    /// Downloads the chunks of `manifest` into `cache_dir` and writes the reassembled content to
+    /// `output`. Chunks already in `cache_dir` are not downloaded again, so an interrupted
+    /// download is resumed by calling this again with the same directory.
+    pub async fn download_file<W: Write>(
+        &self,
+        manifest: &Manifest,
+        cache_dir: &Path,
+        mut output: W,
+    ) -> Result<(), ClientError> {
+        std::fs::create_dir_all(cache_dir).map_err(ClientError::Storage)?;
+        for hash in manifest.chunks.iter() {
+            let path = cache_dir.join(hash.to_string());
+            if let Ok(data) = std::fs::read(&path) {
+                if crypto::sha256(&data) == *hash {
+                    continue;
+                }
+            }
+            let data = self.retrieve_chunk(hash).await?;
+            std::fs::write(&path, data).map_err(ClientError::Storage)?;
+        }
+
+        let mut hasher = Sha256Hasher::default();
+        for (index, hash) in manifest.chunks.iter().enumerate() {
+            let path = cache_dir.join(hash.to_string());
+            let data = std::fs::read(&path).map_err(ClientError::Storage)?;
+            let data = manifest.decrypt_chunk(index, &data).ok_or_else(|| {
+                ClientError::Protocol(format!("chunk {hash} does not match the manifest"))
+            })?;
+            hasher.update(&data);
+            output.write_all(&data).map_err(ClientError::Storage)?;
+        }
+        output.flush().map_err(ClientError::Storage)?;
+        if hasher.finalize() != manifest.content_hash {
+            return Err(ClientError::Protocol(
+                "reassembled content does not match the manifest".to_string(),
+            ));
+        }
+
+        for hash in manifest.chunks.iter() {
+            let _ = std::fs::remove_file(cache_dir.join(hash.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Retrieves a chunk from the first deaddrop that returns it intact.
+    async fn retrieve_chunk(&self, hash: &Sha256) -> Result<Vec<u8>, ClientError> {
+        let beacon = self
+            .drand_client
+            .chain_latest_randomness(&self.drand_chain)
+            .await?;
+        let solution = crypto_puzzle_solve(&[], &beacon, self.config.difficulty);
+        let mut error = ClientError::Configuration("no deaddrops configured".to_string());
+        for stream in self.deaddrops.iter() {
+            let request = self.sign_request(
+                stream,
+                beacon.round_number,
+                Message::RetrieveChunk(RetrieveChunk {
+                    hash: *hash,
+                    beacon: beacon.clone(),
+                    chain: self.drand_chain.clone(),
+                    nonce_solution: solution,
+                }),
+            )?;
+            error = match exchange(stream.clone(), request, true).await {
+                Ok(Message::Chunk(chunk)) if crypto::sha256(&chunk.data) == *hash => {
+                    return Ok(chunk.data)
+                }
+                Ok(Message::Chunk(_)) => {
+                    ClientError::Protocol(format!("deaddrop returned a corrupt chunk {hash}"))
+                }
+                Ok(other) => unexpected_response("chunk request", other).into(),
+                Err(err) => err.into(),
+            };
+            tracing::warn!("failed to retrieve chunk {hash}: {error}");
+        }
+        Err(error)
+    }
+
+    pub async fn fetch_messages_bench(&mut self, topic: &str) -> Result<(), ClientError> {
         let since = 0;
         let beacon = self
             .drand_client
             .chain_latest_randomness(&self.drand_chain)
-            .await
-            .unwrap();
+            .await?;
         let solution = crypto_puzzle_solve(&[], &beacon, self.config.difficulty);
-        let request = self.sign_message(Message::RetrieveDocumentIds(RetrieveDocumentIds {
+        let request = Message::RetrieveDocumentIds(RetrieveDocumentIds {
             topic: topic.to_string(),
             since_round: since,
             beacon: beacon.clone(),
             chain: self.drand_chain.clone(),
-            nonce_solution: solution,
-        }));
+            puzzle_algorithm: self.config.puzzle_algorithm,
+            nonce_solution: self.document_ids_solution(&beacon),
+        });
 
         let message_ids = async {
             let mut response_set = JoinSet::new();
             let mut message_ids = HashMap::<DocumentId, usize>::default();
-            for (stream_idx, stream) in self.deaddrops.iter_mut().enumerate() {
+            for (stream_idx, stream) in self.deaddrops.iter().enumerate() {
                 let stream = stream.clone();
-                let request = request.clone();
+                let request = self.sign_request(&stream, beacon.round_number, request.clone())?;
                 response_set.spawn(async move {
-                    let response: Signed<Message> = stream.send_and_read(&request).await;
+                    let response = exchange(stream, request, false).await;
                     (response, stream_idx)
                 });
             }
             while let Some(v) = response_set.join_next().await {
                 let (response, stream_idx) = v.unwrap();
-                match response.content {
+                match response? {
                     Message::DocumentIdList(list) => {
                         message_ids.extend(list.message_ids.into_iter().map(|id| (id, stream_idx)));
                     }
-                    _ => panic!("unexpected deaddrop response to message id request"),
+                    other => return Err(unexpected_response("message id request", other).into()),
                 }
             }
-            message_ids
+            Ok::<_, ClientError>(message_ids)
         }
         .instrument(tracing::info_span!("fetch_messages_fetch_ids"))
-        .await;
+        .await?;
 
         async {
             let message_ids = message_ids.keys().cloned().collect::<Vec<_>>();
             let mut retreive_set = JoinSet::new();
             for stream_idx in 0..self.deaddrops.len() {
                 let stream = self.deaddrops[stream_idx].clone();
-                let request = self.sign_message(Message::RetrieveDocuments(RetrieveDocuments {
-                    message_ids: message_ids.clone(),
-                    beacon: beacon.clone(),
-                    chain: self.drand_chain.clone(),
-                    nonce_solution: solution,
-                }));
-                retreive_set.spawn(async move {
-                    let response: Signed<Message> = stream.send_and_read(&request).await;
-                    response
-                });
+                let request = self.sign_request(
+                    &stream,
+                    beacon.round_number,
+                    Message::RetrieveDocuments(RetrieveDocuments {
+                        message_ids: message_ids.clone(),
+                        beacon: beacon.clone(),
+                        chain: self.drand_chain.clone(),
+                        nonce_solution: solution,
+                    }),
+                )?;
+                retreive_set.spawn(exchange(stream, request, false));
             }
 
             while let Some(response) = retreive_set.join_next().await {
-                match response.unwrap().content {
+                match response.unwrap()? {
                     Message::DocumentList(_) => {}
-                    _ => panic!("unexpected deaddrop response"),
+                    other => return Err(unexpected_response("document request", other).into()),
                 }
             }
+            Ok::<_, ClientError>(())
         }
         .instrument(tracing::info_span!("fetch_messages_fetch_documents"))
-        .await;
+        .await
     }
 
     async fn fetch_messages_ext(
@@ -194,171 +688,478 @@ impl Client {
         topic: &str,
         since: u64,
         check: bool,
-    ) -> Vec<SignedDocument> {
-        self.update_keys().await;
+    ) -> Result<Vec<SignedDocument>, ClientError> {
+        self.update_keys().await?;
 
-        let current_round = self
+        let beacon = self
             .drand_client
             .chain_latest_randomness(&self.drand_chain)
-            .await
-            .unwrap()
-            .round_number;
-        let since = 0.max(
-            current_round
-                .checked_sub(self.config.acceptance_window)
-                .and_then(|res1| {
-                    since
-                        .checked_sub(self.config.acceptance_window)
-                        .map(|res2| res1.min(res2))
-                })
-                .unwrap_or(0),
-        );
+            .await?;
+        let since = self.retrieval_since(beacon.round_number, since);
+        let solution = crypto_puzzle_solve(&[], &beacon, self.config.difficulty);
+
+        let mut message_ids = HashMap::<DocumentId, usize>::default();
+        let mut key_updates = Vec::new();
+        let responses = self
+            .retrieve_document_ids(topic, since, &beacon, check)
+            .await?;
+        for (stream_idx, response) in responses.into_iter().enumerate() {
+            let list = response?;
+            if let Some(key_update) = list.allowed_sender_keys {
+                key_updates.push(key_update);
+            }
+            message_ids.extend(list.message_ids.into_iter().map(|id| (id, stream_idx)));
+        }
+
+        for key_update in key_updates {
+            self.handle_key_update(key_update)?;
+        }
+
+        // ids left to retrieve from each deaddrop, which answers with as many as fit in a frame
+        let mut remaining = HashMap::<usize, Vec<DocumentId>>::default();
+        for (message_id, stream_idx) in message_ids {
+            remaining.entry(stream_idx).or_default().push(message_id);
+        }
+
+        let mut documents = Vec::new();
+        while !remaining.is_empty() {
+            let mut retreive_set = JoinSet::new();
+            for (stream_idx, stream_ids) in remaining.iter() {
+                let stream = self.deaddrops[*stream_idx].clone();
+                let request = self.sign_request(
+                    &stream,
+                    beacon.round_number,
+                    Message::RetrieveDocuments(RetrieveDocuments {
+                        message_ids: stream_ids.clone(),
+                        beacon: beacon.clone(),
+                        chain: self.drand_chain.clone(),
+                        nonce_solution: solution,
+                    }),
+                )?;
+                let stream_idx = *stream_idx;
+                retreive_set.spawn(async move {
+                    let response = exchange(stream, request, check).await;
+                    (response, stream_idx)
+                });
+            }
+
+            while let Some(response) = retreive_set.join_next().await {
+                let (response, stream_idx) = response.unwrap();
+                let stream_ids = remaining.get_mut(&stream_idx).unwrap();
+                match response? {
+                    Message::DocumentList(mut list) => {
+                        if check
+                            && !list
+                                .documents
+                                .iter()
+                                .all(|m| stream_ids.contains(&m.content.id))
+                        {
+                            return Err(ClientError::Protocol(
+                                "deaddrop returned documents that were not requested".to_string(),
+                            ));
+                        }
+                        let requested = stream_ids.len();
+                        stream_ids.retain(|id| !list.documents.iter().any(|m| &m.content.id == id));
+                        if stream_ids.len() == requested {
+                            return Err(ClientError::Protocol(
+                                "deaddrop returned none of the requested documents".to_string(),
+                            ));
+                        }
+                        if stream_ids.is_empty() {
+                            remaining.remove(&stream_idx);
+                        }
+                        if check {
+                            // check_document warns about every invalid document it drops
+                            let mut valid = Vec::with_capacity(list.documents.len());
+                            for mut signed_document in list.documents {
+                                if self.check_document(&mut signed_document).await? {
+                                    valid.push(signed_document);
+                                }
+                            }
+                            list.documents = valid;
+
+                            let received = list.documents.len();
+                            list.documents.retain(|d| d.content.topic == topic);
+                            if list.documents.len() != received {
+                                tracing::warn!(
+                                    "deaddrop returned {} documents from other topics",
+                                    received - list.documents.len()
+                                );
+                            }
+                        }
+                        documents.extend(list.documents);
+                    }
+                    other => return Err(unexpected_response("document request", other).into()),
+                }
+            }
+        }
+
+        Ok(documents)
+    }
+
+    /// Retrieves the documents of a topic, only accepting documents advertised by at least
+    /// `quorum` deaddrops. A document is fetched from another advertising deaddrop if one fails to
+    /// deliver it, and the returned report lists the deaddrops whose answers differ.
+    pub async fn fetch_messages_quorum(
+        &mut self,
+        topic: &str,
+        since: u64,
+        quorum: usize,
+    ) -> Result<QuorumRetrieval, ClientError> {
+        if quorum == 0 || quorum > self.deaddrops.len() {
+            return Err(ClientError::Configuration(format!(
+                "quorum of {quorum} with {} deaddrops",
+                self.deaddrops.len()
+            )));
+        }
+        self.update_keys().await?;
+
         let beacon = self
             .drand_client
             .chain_latest_randomness(&self.drand_chain)
-            .await
-            .unwrap();
+            .await?;
+        let since = self.retrieval_since(beacon.round_number, since);
         let solution = crypto_puzzle_solve(&[], &beacon, self.config.difficulty);
-        let request = self.sign_message(Message::RetrieveDocumentIds(RetrieveDocumentIds {
+
+        let mut report = ConsistencyReport::default();
+        let mut advertisers = HashMap::<DocumentId, Vec<usize>>::default();
+        let mut responded = Vec::new();
+        let mut key_updates = Vec::new();
+        let responses = self
+            .retrieve_document_ids(topic, since, &beacon, true)
+            .await?;
+        for (stream_idx, response) in responses.into_iter().enumerate() {
+            match response {
+                Ok(list) => {
+                    if let Some(key_update) = list.allowed_sender_keys {
+                        key_updates.push(key_update);
+                    }
+                    for id in list.message_ids {
+                        advertisers.entry(id).or_default().push(stream_idx);
+                    }
+                    responded.push(stream_idx);
+                }
+                Err(err) => {
+                    let addr = self.deaddrop_addresses[stream_idx].clone();
+                    report.failures.push((addr, err));
+                }
+            }
+        }
+
+        for key_update in key_updates {
+            self.handle_key_update(key_update)?;
+        }
+
+        // deaddrops left to fetch each accepted document from
+        advertisers.retain(|id, stream_idxs| {
+            if stream_idxs.len() < quorum {
+                report.below_quorum.push(id.clone());
+                return false;
+            }
+            true
+        });
+        let mut pending = advertisers.clone();
+
+        let mut documents = Vec::new();
+        let mut invalid = HashSet::<DocumentId>::new();
+        while !pending.is_empty() {
+            let mut assignments = HashMap::<usize, Vec<DocumentId>>::default();
+            pending.retain(|id, stream_idxs| {
+                if stream_idxs.is_empty() {
+                    if !invalid.contains(id) {
+                        report.unavailable.push(id.clone());
+                    }
+                    return false;
+                }
+                let stream_idx = stream_idxs.remove(0);
+                assignments.entry(stream_idx).or_default().push(id.clone());
+                true
+            });
+
+            let mut retreive_set = JoinSet::new();
+            for (stream_idx, stream_ids) in assignments {
+                let stream = self.deaddrops[stream_idx].clone();
+                let request = self.sign_request(
+                    &stream,
+                    beacon.round_number,
+                    Message::RetrieveDocuments(RetrieveDocuments {
+                        message_ids: stream_ids.clone(),
+                        beacon: beacon.clone(),
+                        chain: self.drand_chain.clone(),
+                        nonce_solution: solution,
+                    }),
+                )?;
+                retreive_set.spawn(async move {
+                    let response = exchange(stream, request, true).await;
+                    (response, stream_idx, stream_ids)
+                });
+            }
+
+            while let Some(response) = retreive_set.join_next().await {
+                let (response, stream_idx, mut stream_ids) = response.unwrap();
+                let addr = self.deaddrop_addresses[stream_idx].clone();
+                let list = match response {
+                    Ok(Message::DocumentList(list)) => list,
+                    Ok(other) => {
+                        let err = unexpected_response("document request", other);
+                        report.failures.push((addr, err.into()));
+                        continue;
+                    }
+                    Err(err) => {
+                        report.failures.push((addr, err.into()));
+                        continue;
+                    }
+                };
+
+                let requested = stream_ids.len();
+                for mut document in list.documents {
+                    let id = document.content.id.clone();
+                    if !stream_ids.contains(&id) {
+                        continue;
+                    }
+                    stream_ids.retain(|i| i != &id);
+                    if document.content.topic != topic
+                        || !self.check_document(&mut document).await?
+                    {
+                        report
+                            .invalid
+                            .entry(addr.clone())
+                            .or_default()
+                            .push(id.clone());
+                        invalid.insert(id);
+                        continue;
+                    }
+                    // only deaddrops missing a valid document withhold it
+                    for other_idx in responded.iter().filter(|i| !advertisers[&id].contains(i)) {
+                        let other = self.deaddrop_addresses[*other_idx].clone();
+                        report.withheld.entry(other).or_default().push(id.clone());
+                    }
+                    pending.remove(&id);
+                    documents.push(document);
+                }
+                if stream_ids.len() == requested {
+                    let err = ClientError::Protocol(format!(
+                        "deaddrop did not deliver {} advertised documents",
+                        stream_ids.len()
+                    ));
+                    report.failures.push((addr, err));
+                } else {
+                    // documents left out of a full frame are requested from the deaddrop again
+                    for id in stream_ids {
+                        if let Some(stream_idxs) = pending.get_mut(&id) {
+                            stream_idxs.insert(0, stream_idx);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(QuorumRetrieval { documents, report })
+    }
+
+    /// Solves the puzzle of a document id request, which uses the configured puzzle algorithm.
+    fn document_ids_solution(&self, beacon: &drand::Beacon) -> u32 {
+        let algorithm = self.config.puzzle_algorithm;
+        let difficulty = self.puzzle_difficulty();
+        tokio::task::block_in_place(|| puzzle_solve(algorithm, &[], beacon, difficulty))
+    }
+
+    /// Requests the ids of the documents of a topic from every deaddrop, returning the answers in
+    /// deaddrop order.
+    async fn retrieve_document_ids(
+        &self,
+        topic: &str,
+        since: u64,
+        beacon: &drand::Beacon,
+        check: bool,
+    ) -> Result<Vec<Result<DocumentIdList, ClientError>>, ClientError> {
+        let request = Message::RetrieveDocumentIds(RetrieveDocumentIds {
             topic: topic.to_string(),
             since_round: since,
             beacon: beacon.clone(),
             chain: self.drand_chain.clone(),
-            nonce_solution: solution,
-        }));
+            puzzle_algorithm: self.config.puzzle_algorithm,
+            nonce_solution: self.document_ids_solution(beacon),
+        });
 
         let mut response_set = JoinSet::new();
-        let mut message_ids = HashMap::<DocumentId, usize>::default();
-        let mut key_updates = Vec::new();
-        for (stream_idx, stream) in self.deaddrops.iter_mut().enumerate() {
+        for (stream_idx, stream) in self.deaddrops.iter().enumerate() {
             let stream = stream.clone();
-            let request = request.clone();
+            let request = self.sign_request(&stream, beacon.round_number, request.clone())?;
             response_set.spawn(async move {
-                let response: Signed<Message> = stream.send_and_read(&request).await;
-                if check && !response.verify() {
-                    panic!("invalid deaddrop response signature");
-                }
+                let response = exchange(stream, request, check).await;
                 (response, stream_idx)
             });
         }
+
+        let mut responses = (0..self.deaddrops.len()).map(|_| None).collect::<Vec<_>>();
         while let Some(v) = response_set.join_next().await {
             let (response, stream_idx) = v.unwrap();
-            match response.content {
-                Message::DocumentIdList(list) => {
-                    if let Some(key_update) = list.allowed_sender_keys {
-                        key_updates.push(key_update);
-                    }
-                    message_ids.extend(list.message_ids.into_iter().map(|id| (id, stream_idx)));
-                }
-                _ => panic!("unexpected deaddrop response to message id request"),
-            }
+            responses[stream_idx] = Some(match response {
+                Ok(Message::DocumentIdList(list)) => Ok(list),
+                Ok(other) => Err(unexpected_response("message id request", other).into()),
+                Err(err) => Err(err.into()),
+            });
         }
+        Ok(responses.into_iter().flatten().collect())
+    }
 
-        for key_update in key_updates {
-            self.handle_key_update(key_update);
+    /// Verifies a retrieved document, decrypting it in the restricted receiver modes. The
+    /// acceptance window only applies to publication, documents of any age are valid here.
+    async fn check_document(
+        &self,
+        signed_document: &mut SignedDocument,
+    ) -> Result<bool, ClientError> {
+        let document = &signed_document.content;
+        if !self.drand_client.is_pinned(&document.drand.chain) {
+            tracing::warn!(
+                "document references unpinned chain {}",
+                document.drand.chain
+            );
+            return Ok(false);
         }
+        let chain = self.drand_client.chain_info(&document.drand.chain).await?;
+        let beacon = self
+            .drand_client
+            .chain_latest_randomness(&document.drand.chain)
+            .await?;
+        let algorithm = signed_document.content.puzzle_algorithm;
+        let Some(&difficulty) = self.puzzle_difficulties.get(&algorithm) else {
+            tracing::warn!("document uses {algorithm} puzzles, which no deaddrop accepts");
+            return Ok(false);
+        };
+        let valid = signed_document
+            .content
+            .is_valid(difficulty, 0, &chain, &beacon);
 
-        let mut retreive_set = JoinSet::new();
-        let mut documents = Vec::new();
-        for stream_idx in 0..self.deaddrops.len() {
-            let mut stream_ids = Vec::new();
-            for (message_id, message_id_stream_idx) in message_ids.iter() {
-                if *message_id_stream_idx != stream_idx {
-                    continue;
-                }
-                stream_ids.push(message_id.clone());
+        let verified = match self.config.mode {
+            ModeOfOperation::Open => signed_document.verify(),
+            ModeOfOperation::SenderRestricted => signed_document.ring_verify(self.ring()?),
+            ModeOfOperation::ReceiverRestricted => {
+                signed_document.verify() && signed_document.content.decrypt(self.private_key()?)
             }
-
-            for message_id in stream_ids.iter() {
-                message_ids.remove(message_id);
+            ModeOfOperation::FullyRestricted => {
+                signed_document.ring_verify(self.ring()?)
+                    && signed_document.content.decrypt(self.private_key()?)
             }
+        };
+
+        if !verified || !valid {
+            tracing::warn!("received invalid document. verified = {verified} valid = {valid}");
+        }
+        Ok(verified && valid)
+    }
 
-            if stream_ids.is_empty() {
+    /// First round to retrieve documents from, widened by the acceptance window.
+    fn retrieval_since(&self, current_round: u64, since: u64) -> u64 {
+        0.max(
+            current_round
+                .checked_sub(self.config.acceptance_window)
+                .and_then(|res1| {
+                    since
+                        .checked_sub(self.config.acceptance_window)
+                        .map(|res2| res1.min(res2))
+                })
+                .unwrap_or(0),
+        )
+    }
+
+    /// Subscribes to a topic on every deaddrop supporting subscriptions. The stream yields each
+    /// valid document published from the current round on once, and ends when all deaddrops
+    /// have closed their subscription.
+    pub async fn subscribe(
+        &mut self,
+        topic: &str,
+    ) -> Result<impl Stream<Item = SignedDocument> + '_, ClientError> {
+        self.update_keys().await?;
+        let beacon = self
+            .drand_client
+            .chain_latest_randomness(&self.drand_chain)
+            .await?;
+        let since_round = beacon.round_number;
+        let solution = crypto_puzzle_solve(&[], &beacon, self.config.difficulty);
+
+        let (sender, receiver) = mpsc::channel(64);
+        let mut subscriptions = 0;
+        for (stream_idx, addr) in self.deaddrop_addresses.iter().enumerate() {
+            let hello = self.deaddrops[stream_idx].hello();
+            if !hello
+                .content
+                .features
+                .iter()
+                .any(|f| f == FEATURE_SUBSCRIBE)
+            {
+                tracing::warn!("deaddrop {addr:?} does not support subscriptions");
                 continue;
             }
 
-            let stream = self.deaddrops[stream_idx].clone();
-            let request = self.sign_message(Message::RetrieveDocuments(RetrieveDocuments {
-                message_ids: stream_ids.clone(),
-                beacon: beacon.clone(),
-                chain: self.drand_chain.clone(),
-                nonce_solution: solution,
-            }));
-            retreive_set.spawn(async move {
-                let response: Signed<Message> = stream.send_and_read(&request).await;
-                if check && !response.verify() {
-                    panic!("invalid deaddrop signature");
+            // pushed documents would interleave with other responses on the shared connection
+            let max_frame_size = self.config.max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+            let key = self.deaddrops[stream_idx].public_key();
+            let stream = DeaddropConn::connect(addr, Some(key), max_frame_size).await?;
+            let request = self.sign_request(
+                &stream,
+                since_round,
+                Message::Subscribe(Subscribe {
+                    topic: topic.to_string(),
+                    since_round,
+                    beacon: beacon.clone(),
+                    chain: self.drand_chain.clone(),
+                    nonce_solution: solution,
+                }),
+            )?;
+            stream.send(&request).await?;
+
+            let nonce = request.content.context.nonce;
+            let sender = sender.clone();
+            let addr = addr.clone();
+            tokio::spawn(async move {
+                if let Err(err) = forward_subscription(stream, nonce, sender).await {
+                    tracing::warn!("subscription to deaddrop {addr:?} ended: {err}");
                 }
-                (response, stream_ids)
             });
+            subscriptions += 1;
+        }
+        if subscriptions == 0 {
+            return Err(ClientError::Configuration(
+                "no deaddrop supports subscriptions".to_string(),
+            ));
         }
 
-        while let Some(response) = retreive_set.join_next().await {
-            let (response, stream_ids) = response.unwrap();
-            match response.content {
-                Message::DocumentList(mut list) => {
-                    if check {
-                        assert_eq!(list.documents.len(), stream_ids.len());
-                        assert!(list
-                            .documents
-                            .iter()
-                            .all(|m| stream_ids.contains(&m.content.id)));
-
-                        for signed_document in list.documents.iter_mut() {
-                            let document = &signed_document.content;
-                            let chain = self
-                                .drand_client
-                                .chain_info(&document.drand.chain)
-                                .await
-                                .unwrap();
-                            let beacon = self
-                                .drand_client
-                                .chain_latest_randomness(&document.drand.chain)
-                                .await
-                                .unwrap();
-                            let valid = signed_document.content.is_valid(
-                                self.config.difficulty,
-                                self.config.acceptance_window,
-                                &chain,
-                                &beacon,
-                            );
-
-                            let verified = match self.config.mode {
-                                ModeOfOperation::Open => signed_document.verify(),
-                                ModeOfOperation::SenderRestricted => {
-                                    signed_document.ring_verify(self.config.ring.as_ref().unwrap())
-                                }
-                                ModeOfOperation::ReceiverRestricted => {
-                                    signed_document.verify()
-                                        && signed_document
-                                            .content
-                                            .decrypt(self.config.private_key.as_ref().unwrap())
-                                }
-                                ModeOfOperation::FullyRestricted => {
-                                    signed_document.ring_verify(self.config.ring.as_ref().unwrap())
-                                        && signed_document
-                                            .content
-                                            .decrypt(self.config.private_key.as_ref().unwrap())
-                                }
-                            };
-
-                            if !verified || !valid {
-                                tracing::warn!(
-                                "received invalid document. verified = {verified} valid = {valid}"
-                            );
+        let client = &*self;
+        let topic = topic.to_string();
+        let seen = HashSet::<DocumentId>::new();
+        Ok(futures::stream::unfold(
+            (receiver, seen),
+            move |(mut receiver, mut seen)| {
+                let topic = topic.clone();
+                async move {
+                    while let Some(mut document) = receiver.recv().await {
+                        if document.content.topic != topic || seen.contains(&document.content.id) {
+                            continue;
+                        }
+                        match client.check_document(&mut document).await {
+                            Ok(true) => {}
+                            Ok(false) => continue,
+                            Err(err) => {
+                                tracing::warn!("failed to check pushed document: {err}");
+                                continue;
                             }
                         }
+                        seen.insert(document.content.id.clone());
+                        return Some((document, (receiver, seen)));
                     }
-                    documents.extend(list.documents);
+                    None
                 }
-                _ => panic!("unexpected deaddrop response"),
-            }
-        }
-
-        documents
+            },
+        ))
     }
 
-    pub async fn fetch_messages(&mut self, topic: &str, since: u64) -> Vec<SignedDocument> {
+    pub async fn fetch_messages(
+        &mut self,
+        topic: &str,
+        since: u64,
+    ) -> Result<Vec<SignedDocument>, ClientError> {
         self.fetch_messages_ext(topic, since, true).await
     }
 
@@ -366,48 +1167,71 @@ impl Client {
         &mut self,
         topic: &str,
         since: u64,
-    ) -> Vec<SignedDocument> {
+    ) -> Result<Vec<SignedDocument>, ClientError> {
         self.fetch_messages_ext(topic, since, false).await
     }
 
-    pub async fn update_keys(&mut self) {
+    pub async fn update_keys(&mut self) -> Result<(), ClientError> {
         tracing::info!("updating keys...");
         if std::matches!(self.config.mode, ModeOfOperation::Open) {
             tracing::info!("skipping key update, using open mop");
-            return;
+            return Ok(());
         }
 
-        let request = self.sign_message(Message::RetrieveKeys);
-        let stream = &mut self.deaddrops[0];
-        let response: Signed<UpdateAllowedKeys> = stream.send_and_read(&request).await;
-        self.handle_key_update(response);
+        let stream = self
+            .deaddrops
+            .first()
+            .cloned()
+            .ok_or_else(|| ClientError::Configuration("no deaddrops configured".to_string()))?;
+        let round = self.current_round().await?;
+        let request = self.sign_request(&stream, round, Message::RetrieveKeys)?;
+        match exchange(stream, request, false).await? {
+            Message::AllowedKeys(keys) => self.handle_key_update(keys.allowed_keys)?,
+            other => return Err(unexpected_response("key request", other).into()),
+        }
         tracing::info!("keys updated");
+        Ok(())
     }
 
-    fn handle_key_update(&mut self, update: Signed<UpdateAllowedKeys>) {
-        if !update.verify_with(self.config.asset_owner_public_key.as_ref().unwrap()) {
-            panic!("deaddrop sent key update with invalid asset owner signature");
+    fn handle_key_update(&mut self, update: Signed<UpdateAllowedKeys>) -> Result<(), ClientError> {
+        let asset_owner_public_key =
+            self.config.asset_owner_public_key.as_ref().ok_or_else(|| {
+                ClientError::Configuration("missing asset owner public key".to_string())
+            })?;
+        if !update.verify_with(asset_owner_public_key) {
+            return Err(ClientError::Signature(
+                "deaddrop sent key update with invalid asset owner signature".to_string(),
+            ));
         }
         self.sender_ring = Ring::from(update.content.allowed_sender_keys);
         self.receiver_keys = update.content.allowed_receiver_keys;
+        Ok(())
     }
 
     fn create_message(
         &self,
         topic: &str,
+        kind: DocumentKind,
         data: &[u8],
+        difficulty: u8,
         document_drand: DocumentDrand,
-    ) -> Signed<Message> {
+    ) -> Result<SignedDocument, ClientError> {
         match self.config.mode {
-            ModeOfOperation::Open => self.create_message_open(topic, data, document_drand),
-            ModeOfOperation::SenderRestricted => {
-                self.create_message_sender_restricted(topic, data, document_drand)
+            ModeOfOperation::Open => {
+                self.create_message_open(topic, kind, data, difficulty, document_drand)
             }
-            ModeOfOperation::ReceiverRestricted => {
-                self.create_message_receiver_restricted(topic, data, document_drand)
+            ModeOfOperation::SenderRestricted => {
+                self.create_message_sender_restricted(topic, kind, data, difficulty, document_drand)
             }
+            ModeOfOperation::ReceiverRestricted => self.create_message_receiver_restricted(
+                topic,
+                kind,
+                data,
+                difficulty,
+                document_drand,
+            ),
             ModeOfOperation::FullyRestricted => {
-                self.create_message_restricted(topic, data, document_drand)
+                self.create_message_restricted(topic, kind, data, difficulty, document_drand)
             }
         }
     }
@@ -415,173 +1239,478 @@ impl Client {
     fn create_message_open(
         &self,
         topic: &str,
+        kind: DocumentKind,
         data: &[u8],
+        difficulty: u8,
         document_drand: DocumentDrand,
-    ) -> Signed<Message> {
-        let document = self.create_signed_document(self.create_document_plaintext(
+    ) -> Result<SignedDocument, ClientError> {
+        self.create_signed_document(self.create_document_plaintext(
             topic,
+            kind,
             data,
+            difficulty,
             document_drand,
-        ));
-        self.sign_message(Message::PublishDocument(PublishDocument { document }))
+        )?)
     }
 
     fn create_message_sender_restricted(
         &self,
         topic: &str,
+        kind: DocumentKind,
         data: &[u8],
+        difficulty: u8,
         document_drand: DocumentDrand,
-    ) -> Signed<Message> {
-        let document = self.create_signed_document(self.create_document_plaintext(
+    ) -> Result<SignedDocument, ClientError> {
+        self.create_signed_document(self.create_document_plaintext(
             topic,
+            kind,
             data,
+            difficulty,
             document_drand,
-        ));
-        self.sign_message(Message::PublishDocument(PublishDocument { document }))
+        )?)
     }
 
     fn create_message_receiver_restricted(
         &self,
         topic: &str,
+        kind: DocumentKind,
         data: &[u8],
+        difficulty: u8,
         document_drand: DocumentDrand,
-    ) -> Signed<Message> {
-        let document = self.create_signed_document(self.create_document_encrypted(
+    ) -> Result<SignedDocument, ClientError> {
+        self.create_signed_document(self.create_document_encrypted(
             topic,
+            kind,
             data,
+            difficulty,
             document_drand,
-        ));
-        self.sign_message(Message::PublishDocument(PublishDocument { document }))
+        )?)
     }
 
     fn create_message_restricted(
         &self,
         topic: &str,
+        kind: DocumentKind,
         data: &[u8],
+        difficulty: u8,
         document_drand: DocumentDrand,
-    ) -> Signed<Message> {
-        let document = self.create_signed_document(self.create_document_encrypted(
+    ) -> Result<SignedDocument, ClientError> {
+        self.create_signed_document(self.create_document_encrypted(
             topic,
+            kind,
             data,
+            difficulty,
             document_drand,
-        ));
-        self.sign_message(Message::PublishDocument(PublishDocument { document }))
+        )?)
     }
 
-    async fn deaddrop_broadcast_publish(&mut self, message: &Signed<Message>) {
+    async fn deaddrop_broadcast_publish(
+        &mut self,
+        document: SignedDocument,
+        round: u64,
+    ) -> Result<(), ClientError> {
         tracing::info!("broadingcasting message to deaddrops");
 
+        let message = Message::PublishDocument(PublishDocument { document });
         let mut handles = Vec::with_capacity(self.deaddrops.len());
-        for (i, stream) in &mut self.deaddrops.iter_mut().enumerate() {
+        for (i, stream) in self.deaddrops.iter().enumerate() {
             let stream = stream.clone();
-            let message = message.clone();
+            let request = self.sign_request(&stream, round, message.clone())?;
             let handle = tokio::spawn(async move {
                 tracing::debug!("sending message to stream {i}");
-                let response = stream.send_and_read::<Signed<Message>, _>(&message).await;
-                match response.content {
-                    Message::Success => {}
-                    _ => panic!("expected success when publishing message"),
+                match exchange(stream, request, false).await? {
+                    Message::Success => Ok(()),
+                    Message::AlreadyStored(id) => {
+                        tracing::debug!("stream {i} already stores the document as {id:?}");
+                        Ok(())
+                    }
+                    other => Err(unexpected_response("publish request", other)),
                 }
             });
             handles.push(handle);
         }
 
+        let mut result = Ok(());
         for handle in handles {
-            let _ = handle.await;
+            if let Ok(Err(err)) = handle.await {
+                tracing::warn!("failed to publish message: {err}");
+                result = Err(err.into());
+            }
         }
 
         tracing::info!("broadcasting message to deaddrops complete");
+        result
     }
 
     fn create_document_plaintext(
         &self,
         topic: &str,
+        kind: DocumentKind,
         data: &[u8],
+        difficulty: u8,
         document_drand: DocumentDrand,
-    ) -> Document {
-        Document::plaintext(
+    ) -> Result<Document, ClientError> {
+        Ok(Document::plaintext(
             topic,
+            kind,
             data,
-            self.config.difficulty,
-            self.public_key_hash(),
+            self.config.puzzle_algorithm,
+            difficulty,
+            self.sender_tag()?,
             document_drand,
-        )
+        ))
     }
 
     fn create_document_encrypted(
         &self,
         topic: &str,
+        kind: DocumentKind,
         data: &[u8],
+        difficulty: u8,
         document_drand: DocumentDrand,
-    ) -> Document {
+    ) -> Result<Document, ClientError> {
         Document::encrypted(
             topic,
+            kind,
             data,
-            self.config.difficulty,
-            self.public_key_hash(),
+            self.config.puzzle_algorithm,
+            difficulty,
+            self.sender_tag()?,
+            self.config.encryption_algorithm,
             &self.receiver_keys,
             document_drand,
         )
+        .map_err(|err| ClientError::Configuration(err.to_string()))
     }
 
-    async fn create_document_drand(&self) -> DocumentDrand {
+    async fn create_document_drand(&self) -> Result<DocumentDrand, ClientError> {
         let chain = self.drand_chain.clone();
-        let info = self.drand_client.chain_info(&chain).await.unwrap();
-        let beacon = self
-            .drand_client
-            .chain_latest_randomness(&chain)
-            .await
-            .unwrap();
-        DocumentDrand {
+        let info = self.drand_client.chain_info(&chain).await?;
+        let beacon = self.drand_client.chain_latest_randomness(&chain).await?;
+        Ok(DocumentDrand {
             chain,
             beacon,
             scheme: info.scheme_id,
+        })
+    }
+
+    /// Puzzle difficulty of a new document, the highest required by any deaddrop.
+    async fn document_difficulty(&self, document_drand: &DocumentDrand) -> Result<u8, ClientError> {
+        if !self.supports_feature(FEATURE_ADAPTIVE_DIFFICULTY) {
+            return Ok(self.puzzle_difficulty());
+        }
+        let round = document_drand.beacon.round_number;
+        let mut difficulty = self.puzzle_difficulty();
+        for deaddrop in &self.deaddrops {
+            let message = Message::RetrieveDifficulty {
+                chain: document_drand.chain.clone(),
+            };
+            let request = self.sign_request(deaddrop, round, message)?;
+            let update = match exchange(deaddrop.clone(), request, false).await? {
+                Message::Difficulty(update) => update,
+                other => return Err(unexpected_response("difficulty request", other).into()),
+            };
+            if !update.verify_with(deaddrop.public_key()) {
+                return Err(ClientError::Signature(
+                    "deaddrop sent difficulty with invalid signature".to_string(),
+                ));
+            }
+            if update.content.chain != document_drand.chain {
+                return Err(ClientError::Protocol(
+                    "deaddrop sent difficulty of another chain".to_string(),
+                ));
+            }
+            if update.content.round != round {
+                tracing::debug!(
+                    "deaddrop difficulty applies to round {}, document is from round {round}",
+                    update.content.round
+                );
+            }
+            let required = update
+                .content
+                .difficulties
+                .iter()
+                .find(|requirement| requirement.algorithm == self.config.puzzle_algorithm)
+                .ok_or_else(|| {
+                    ClientError::Protocol(format!(
+                        "deaddrop sent no difficulty of {} puzzles",
+                        self.config.puzzle_algorithm
+                    ))
+                })?;
+            difficulty = difficulty.max(required.difficulty);
         }
+        Ok(difficulty)
     }
 
-    fn create_signed_document(&self, document: Document) -> SignedDocument {
-        match self.config.mode {
+    fn create_signed_document(&self, document: Document) -> Result<SignedDocument, ClientError> {
+        Ok(match self.config.mode {
             ModeOfOperation::Open | ModeOfOperation::ReceiverRestricted => {
-                Signed::sign(self.config.private_key.as_ref().unwrap(), document)
+                Signed::sign(self.private_key()?, document)
             }
             ModeOfOperation::SenderRestricted | ModeOfOperation::FullyRestricted => {
-                Signed::ring_sign(
-                    self.config.ring_private_key.as_ref().unwrap(),
-                    &self.sender_ring,
-                    document,
-                )
+                let key = self.ring_private_key()?;
+                match self.rate_limit {
+                    Some(rate_limit) => {
+                        let context = rate_limit.context(&document.drand.chain, document.id.round);
+                        Signed::ring_sign_linkable(key, &self.sender_ring, &context, document)
+                    }
+                    None => Signed::ring_sign(key, &self.sender_ring, document),
+                }
             }
-        }
+        })
     }
 
-    fn public_key_hash(&self) -> Sha256 {
-        match self.config.mode {
-            ModeOfOperation::Open | ModeOfOperation::ReceiverRestricted => crypto::sha256(
-                &self
-                    .config
-                    .private_key
-                    .as_ref()
-                    .unwrap()
-                    .public_key()
-                    .to_bytes(),
-            ),
+    /// The [`DocumentId::sender_tag`] of a new document.
+    fn sender_tag(&self) -> Result<Sha256, ClientError> {
+        Ok(match self.config.mode {
+            ModeOfOperation::Open | ModeOfOperation::ReceiverRestricted => {
+                crypto::sha256(&self.private_key()?.public_key().to_bytes())
+            }
             ModeOfOperation::SenderRestricted | ModeOfOperation::FullyRestricted => {
-                crypto::sha256(self.config.ring_private_key.as_ref().unwrap().as_bytes())
+                crypto::sha256(&crypto::random_bytes::<32>())
             }
-        }
+        })
     }
 
-    fn sign_message(&self, message: Message) -> Signed<Message> {
-        tokio::task::block_in_place(|| match self.config.mode {
+    async fn current_round(&self) -> Result<u64, ClientError> {
+        let beacon = self
+            .drand_client
+            .chain_latest_randomness(&self.drand_chain)
+            .await?;
+        Ok(beacon.round_number)
+    }
+
+    /// Signs a request addressed to `deaddrop` alone, made in drand round `round`.
+    fn sign_request(
+        &self,
+        deaddrop: &DeaddropConn,
+        round: u64,
+        message: Message,
+    ) -> Result<Signed<Request>, ClientError> {
+        let request = Request {
+            context: RequestContext {
+                nonce: crypto::random_bytes(),
+                deaddrop: deaddrop.public_key().clone(),
+                chain: self.drand_chain.clone(),
+                round,
+            },
+            message,
+        };
+        Ok(match self.config.mode {
             ModeOfOperation::Open | ModeOfOperation::ReceiverRestricted => {
-                let key = self.config.private_key.as_ref().unwrap();
-                Signed::sign(key, message)
+                let key = self.private_key()?;
+                tokio::task::block_in_place(|| Signed::sign(key, request))
             }
             ModeOfOperation::SenderRestricted | ModeOfOperation::FullyRestricted => {
-                let key = self.config.ring_private_key.as_ref().unwrap();
-                let ring = self.config.ring.as_ref().unwrap();
-                Signed::ring_sign(key, ring, message)
+                let key = self.ring_private_key()?;
+                let ring = self.ring()?;
+                tokio::task::block_in_place(|| Signed::ring_sign(key, ring, request))
             }
         })
     }
+
+    fn private_key(&self) -> Result<&PrivateKey, ClientError> {
+        self.config
+            .private_key
+            .as_ref()
+            .ok_or_else(|| ClientError::Configuration("missing private key".to_string()))
+    }
+
+    fn ring_private_key(&self) -> Result<&RingPrivateKey, ClientError> {
+        self.config
+            .ring_private_key
+            .as_ref()
+            .ok_or_else(|| ClientError::Configuration("missing ring private key".to_string()))
+    }
+
+    fn ring(&self) -> Result<&Ring, ClientError> {
+        self.config
+            .ring
+            .as_ref()
+            .ok_or_else(|| ClientError::Configuration("missing ring".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{deaddrop, protocol::ErrorCode};
+
+    /// Runs a deaddrop on a local port, returning its address and key.
+    async fn start_deaddrop(config: deaddrop::Config) -> (DeaddropAddr, PublicKey) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = DeaddropAddr::Tcp(listener.local_addr().unwrap());
+        let key = config.private_key.public_key();
+        tokio::spawn(deaddrop::serve(config, listener));
+        (addr, key)
+    }
+
+    fn create_config(mode: ModeOfOperation, deaddrops: &[(DeaddropAddr, PublicKey)]) -> Config {
+        Config {
+            mode,
+            private_key: Some(crypto::generate().1),
+            ring_private_key: None,
+            ring: None,
+            receivers_keys: Vec::new(),
+            encryption_algorithm: Default::default(),
+            deaddrop_addresses: deaddrops.iter().map(|(addr, _)| addr.clone()).collect(),
+            deaddrop_keys: deaddrops.iter().cloned().collect(),
+            difficulty: 0,
+            puzzle_algorithm: PuzzleAlgorithm::Sha256,
+            acceptance_window: 10,
+            asset_owner_public_key: None,
+            drand_chain: None,
+            drand_client: Some(
+                drand::BeaconSourceConfig::Local { period_seconds: 1 }
+                    .caching_client(Default::default()),
+            ),
+            max_frame_size: None,
+        }
+    }
+
+    /// Two valid documents of `topic`, and the second one with a signature of the first.
+    async fn create_documents(topic: &str) -> (SignedDocument, SignedDocument, SignedDocument) {
+        let deaddrop = start_deaddrop(deaddrop::test::create_config(0)).await;
+        let client = Client::new(create_config(ModeOfOperation::Open, &[deaddrop]))
+            .await
+            .unwrap();
+        let PreparedMessage(first) = client.prepare_message(topic, b"first").await.unwrap();
+        let PreparedMessage(second) = client.prepare_message(topic, b"second").await.unwrap();
+        let mut tampered = second.clone();
+        tampered.signature = first.signature.clone();
+        (first, second, tampered)
+    }
+
+    fn ids(documents: &[SignedDocument]) -> HashSet<DocumentId> {
+        documents.iter().map(|d| d.content.id.clone()).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sender_tag_identifies_sender() {
+        let deaddrop = start_deaddrop(deaddrop::test::create_config(0)).await;
+        let client = Client::new(create_config(
+            ModeOfOperation::Open,
+            std::slice::from_ref(&deaddrop),
+        ))
+        .await
+        .unwrap();
+        let other = Client::new(create_config(ModeOfOperation::Open, &[deaddrop]))
+            .await
+            .unwrap();
+        let sender_tag = |message: PreparedMessage| message.0.content.id.sender_tag;
+
+        let first = sender_tag(client.prepare_message("topic", b"first").await.unwrap());
+        let second = sender_tag(
+            client
+                .prepare_message("other-topic", b"second")
+                .await
+                .unwrap(),
+        );
+        let other = sender_tag(other.prepare_message("topic", b"first").await.unwrap());
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn invalid_documents_are_not_returned() {
+        let (first, _, tampered) = create_documents("topic").await;
+        let deaddrop = deaddrop::test::serve_documents(
+            deaddrop::test::create_config(0),
+            vec![first.clone(), tampered],
+        )
+        .await;
+        let mut client = Client::new(create_config(ModeOfOperation::Open, &[deaddrop]))
+            .await
+            .unwrap();
+
+        let documents = client.fetch_messages("topic", 0).await.unwrap();
+        assert_eq!(ids(&documents), ids(&[first]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn quorum_reports_inconsistent_deaddrops() {
+        let (first, second, tampered) = create_documents("topic").await;
+        let config = deaddrop::test::create_config;
+        let deaddrops = vec![
+            deaddrop::test::serve_documents(config(0), vec![first.clone(), tampered]).await,
+            deaddrop::test::serve_documents(config(0), vec![first.clone(), second.clone()]).await,
+            deaddrop::test::serve_documents(config(0), vec![first.clone()]).await,
+        ];
+        let addrs = deaddrops
+            .iter()
+            .map(|(addr, _)| addr.clone())
+            .collect::<Vec<_>>();
+        let mut client = Client::new(create_config(ModeOfOperation::Open, &deaddrops))
+            .await
+            .unwrap();
+
+        // the tampered copy is replaced by the one of the second deaddrop
+        let QuorumRetrieval { documents, report } =
+            client.fetch_messages_quorum("topic", 0, 2).await.unwrap();
+        assert_eq!(ids(&documents), ids(&[first.clone(), second.clone()]));
+        let second_id = second.content.id.clone();
+        assert_eq!(
+            report.invalid,
+            HashMap::from([(addrs[0].clone(), vec![second_id.clone()])])
+        );
+        assert_eq!(
+            report.withheld,
+            HashMap::from([(addrs[2].clone(), vec![second_id.clone()])])
+        );
+        assert!(report.below_quorum.is_empty());
+        assert!(report.unavailable.is_empty());
+        assert!(report.failures.is_empty());
+        assert!(!report.is_consistent());
+
+        // a document only one deaddrop advertises is not accepted
+        let QuorumRetrieval { documents, report } =
+            client.fetch_messages_quorum("topic", 0, 3).await.unwrap();
+        assert_eq!(ids(&documents), ids(&[first]));
+        assert_eq!(report.below_quorum, vec![second_id]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejections_map_to_client_errors() {
+        let deaddrop = start_deaddrop(deaddrop::test::create_config(0)).await;
+        let mut client = Client::new(create_config(ModeOfOperation::Open, &[deaddrop]))
+            .await
+            .unwrap();
+
+        let PreparedMessage(document) = client.prepare_message("topic", b"data").await.unwrap();
+        client
+            .send_prepared_message(PreparedMessage(document.clone()))
+            .await
+            .unwrap();
+        // the topic is not part of the id, so this is another document under the same id
+        let mut other = document.content;
+        other.topic = "other-topic".to_string();
+        let other = Signed::sign(client.private_key().unwrap(), other);
+        match client.send_prepared_message(PreparedMessage(other)).await {
+            Err(ClientError::Rejected(DeaddropError {
+                code: ErrorCode::Conflict,
+                ..
+            })) => {}
+            other => panic!("unexpected publication result {other:?}"),
+        }
+
+        let mut config = deaddrop::test::create_config(0);
+        config.mode = ModeOfOperation::ReceiverRestricted;
+        let deaddrop = start_deaddrop(config).await;
+        let mut client = Client::new(create_config(
+            ModeOfOperation::ReceiverRestricted,
+            &[deaddrop],
+        ))
+        .await
+        .unwrap();
+        // the asset owner never sent the keys
+        match client.update_keys().await {
+            Err(ClientError::Rejected(DeaddropError {
+                code: ErrorCode::KeysUnavailable,
+                ..
+            })) => {}
+            other => panic!("unexpected key update result {other:?}"),
+        }
+    }
 }
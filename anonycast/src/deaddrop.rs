@@ -1,38 +1,111 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     net::SocketAddr,
-    sync::{Arc, RwLock},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
 
 use crossbeam::channel::{Receiver, Sender};
-use crypto::{PrivateKey, PublicKey, Ring};
+use crypto::{PrivateKey, PublicKey, Ring, Sha256Hasher};
 use tokio::{
-    io::BufStream,
+    io::{AsyncReadExt, BufStream},
     net::{TcpListener, TcpStream},
-    sync::oneshot,
+    sync::{broadcast, oneshot, OwnedSemaphorePermit, Semaphore},
 };
 
 use crate::{
-    crypto_puzzle_verify,
+    chunk::MAX_CHUNK_SIZE,
+    crypto_puzzle_solve,
+    difficulty::DifficultyController,
     document::{Document, DocumentId, SignedDocument},
     protocol::{
-        DocumentIdList, DocumentList, Message, PublishDocument, RetrieveDocumentIds,
-        RetrieveDocuments, Signed, UpdateAllowedKeys,
+        exchange, unexpected_response, AllowedKeys, Chunk, ChunkHashes, ClientHello, DeaddropHello,
+        DigestList, DocumentIdList, DocumentList, ErrorCode, ExchangeError, Message,
+        PublishDocument, PuzzleDifficulty, RateLimit, Request, RequestContext, Response,
+        RetrieveChunk, RetrieveDigests, RetrieveDocumentIds, RetrieveDocuments,
+        RetrieveMissingChunks, RetrieveRoundIds, RoundDigest, Signable, Signed, Subscribe,
+        UpdateAllowedKeys, UploadChunk, FEATURE_ADAPTIVE_DIFFICULTY, FEATURE_CHUNKS,
+        FEATURE_SUBSCRIBE, PROTOCOL_VERSION,
     },
-    rle, ModeOfOperation,
+    puzzle_verify,
+    rate_limit::KeyImageLimiter,
+    replay::ReplayCache,
+    rle,
+    store::{ChunkStore, DirChunkStore, DocumentStore, LogStore, MemoryChunkStore, MemoryStore},
+    DeaddropAddr, DeaddropConn, FrameTooLarge, ModeOfOperation, PuzzleAlgorithm, PuzzleRequirement,
 };
 
+pub use crate::{difficulty::DifficultyAdjustment, retention::RetentionPolicy};
+
 type ClientStream = BufStream<TcpStream>;
 
+/// Request window used when the acceptance window is unlimited, since the replay cache must
+/// eventually forget requests.
+const DEFAULT_REQUEST_WINDOW: u64 = 100;
+
+/// Default limit on the connections handled at once.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+
+/// Default time allowed for the hello and the body of each request to arrive.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes of a document list response left for its signature and framing.
+const RESPONSE_OVERHEAD: u64 = 4096;
+
+/// Number of documents requested at once from a peer deaddrop.
+const SYNC_BATCH_SIZE: usize = 64;
+
+/// Number of published documents buffered for subscribers before slow ones are disconnected.
+const SUBSCRIPTION_BUFFER: usize = 1024;
+
+type DocumentIdsResult = Result<(Vec<DocumentId>, Option<Signed<UpdateAllowedKeys>>), Rejection>;
+
 #[derive(Debug)]
 pub struct Config {
     pub mode: ModeOfOperation,
     pub private_key: PrivateKey,
     pub address: SocketAddr,
+    /// Puzzle difficulty of the requests other than document id requests.
     pub difficulty: u8,
+    /// Adjusts the difficulty of documents to the publication load, starting from `difficulty`.
+    /// The minimum difficulty must be at least `difficulty`, and every puzzle algorithm is raised
+    /// by as many bits as `difficulty` is.
+    pub difficulty_adjustment: Option<DifficultyAdjustment>,
+    /// Puzzle algorithms accepted for documents and document id requests, with the difficulty
+    /// required from each.
+    pub puzzle_algorithms: Vec<PuzzleRequirement>,
     pub acceptance_window: u64,
     pub asset_owner_key: Option<PublicKey>,
     pub asset_owner_update: Option<Signed<UpdateAllowedKeys>>,
+    /// Directory where published documents are persisted. Documents are only kept in memory if
+    /// this is not set.
+    pub data_dir: Option<PathBuf>,
+    pub retention: RetentionPolicy,
+    /// Deaddrops whose documents are periodically replicated into this one.
+    pub peers: Vec<DeaddropAddr>,
+    /// Keys the peers must sign their handshake with. A peer missing from it is trusted with
+    /// whatever key it presents.
+    pub peer_keys: HashMap<DeaddropAddr, PublicKey>,
+    pub sync_interval: Duration,
+    /// Largest frame accepted from clients and peers.
+    pub max_frame_size: u32,
+    /// Limit on the connections handled at once, further connections wait to be accepted. Each
+    /// connection buffers at most one frame, so this also bounds the buffered requests.
+    /// Subscriptions count against the limit for as long as they last.
+    pub max_connections: usize,
+    /// Time allowed for the hello and for the body of each request, once its size is read, to
+    /// arrive before the connection is closed.
+    pub request_timeout: Duration,
+    /// Limit on the documents each allowed sender publishes, only enforced in the sender
+    /// restricted modes. Published documents are only accepted in the current epoch, documents
+    /// replicated from peers in any epoch the retention policy keeps, and they share the limit.
+    /// The counts are kept in memory, so a restart lets senders publish the limit again.
+    pub rate_limit: Option<RateLimit>,
+    /// Source of the drand beacons, defaults to the relay at [`drand::DEFAULT_API_URL`].
+    pub drand_client: Option<drand::CachingClient>,
+    /// Chains whose latest beacon is fetched as each round starts rather than on first use.
+    pub prefetch_chains: Vec<String>,
 }
 
 type SharedState = Arc<State>;
@@ -40,44 +113,137 @@ type SharedState = Arc<State>;
 struct State {
     mode: ModeOfOperation,
     private_key: PrivateKey,
+    public_key: PublicKey,
     asset_owner_key: Option<PublicKey>,
     difficulty: u8,
+    document_difficulty: Mutex<DifficultyController>,
+    puzzle_algorithms: Vec<PuzzleRequirement>,
     acceptance_window: u64,
     drand_client: drand::CachingClient,
     state_mut: RwLock<StateMut>,
-    success_response: Signed<Message>,
+    replay_cache: Mutex<ReplayCache>,
+    rate_limit: Option<RateLimit>,
+    key_images: Mutex<KeyImageLimiter>,
+    /// Age of the oldest documents kept by the retention policy.
+    max_age_rounds: Option<u64>,
+    /// Hello sent to every client, signed with the nonce of the client's hello.
+    hello: DeaddropHello,
+    /// Documents stored since startup, pushed to subscribed connections.
+    published: broadcast::Sender<SignedDocument>,
+    /// Ids of the documents evicted by the retention policy, which are not replicated from peers
+    /// again. Only kept in memory and forgotten once older than the synchronized rounds.
+    evicted: Mutex<HashSet<DocumentId>>,
+    max_frame_size: u32,
+    max_chunk_bytes: Option<u64>,
+    request_timeout: Duration,
+}
+
+impl State {
+    /// Number of rounds during which a request is accepted and remembered by the replay cache.
+    fn request_window(&self) -> u64 {
+        if self.acceptance_window == 0 {
+            DEFAULT_REQUEST_WINDOW
+        } else {
+            self.acceptance_window
+        }
+    }
 }
 
 struct StateMut {
-    published_documents: HashMap<DocumentId, SignedDocument>,
+    published_documents: Box<dyn DocumentStore>,
+    chunks: Box<dyn ChunkStore>,
     allowed_sender_ring: Ring,
     allowed_receiver_keys: Vec<PublicKey>,
     keys_update_asset_owner: Option<Signed<UpdateAllowedKeys>>,
 }
 
+/// Reason for rejecting a client request, sent back to the client as a [`Message::Error`].
+#[derive(Debug)]
+struct Rejection {
+    code: ErrorCode,
+    reason: String,
+}
+
+impl Rejection {
+    fn new(code: ErrorCode, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl From<Rejection> for Message {
+    fn from(value: Rejection) -> Self {
+        Message::Error {
+            code: value.code,
+            reason: value.reason,
+        }
+    }
+}
+
+/// Outcome of a successful publication.
+enum Publication {
+    Stored,
+    /// The document, or another one with the same content in the same topic, was already stored
+    /// under this id.
+    AlreadyStored(DocumentId),
+}
+
+#[allow(clippy::large_enum_variant)]
 enum WorkerJob {
     Sign {
-        message: Message,
-        resp: oneshot::Sender<Signed<Message>>,
+        response: Response,
+        resp: oneshot::Sender<Signed<Response>>,
+    },
+    SignHello {
+        hello: DeaddropHello,
+        resp: oneshot::Sender<Signed<DeaddropHello>>,
     },
     PublishDocument {
         request: PublishDocument,
         document_chain: drand::ChainInfo,
         document_beacon: drand::Beacon,
-        resp: oneshot::Sender<bool>,
+        resp: oneshot::Sender<Result<Publication, Rejection>>,
     },
     RetrieveDocuments {
         request: RetrieveDocuments,
-        resp: oneshot::Sender<Vec<Signed<Document>>>,
+        resp: oneshot::Sender<Result<Vec<Signed<Document>>, Rejection>>,
         beacon: drand::Beacon,
     },
     RetrieveDocumentIds {
         request: RetrieveDocumentIds,
-        resp: oneshot::Sender<Option<(Vec<DocumentId>, Option<Signed<UpdateAllowedKeys>>)>>,
+        resp: oneshot::Sender<DocumentIdsResult>,
+        beacon: drand::Beacon,
+    },
+    RetrieveDigests {
+        request: RetrieveDigests,
+        resp: oneshot::Sender<Result<Vec<RoundDigest>, Rejection>>,
+        beacon: drand::Beacon,
+    },
+    RetrieveRoundIds {
+        request: RetrieveRoundIds,
+        resp: oneshot::Sender<Result<Vec<DocumentId>, Rejection>>,
+        beacon: drand::Beacon,
+    },
+    ReplicateDocument {
+        document: SignedDocument,
+        document_chain: drand::ChainInfo,
+        document_beacon: drand::Beacon,
+        resp: oneshot::Sender<Result<Publication, Rejection>>,
+    },
+    UploadChunk {
+        request: UploadChunk,
+        resp: oneshot::Sender<Result<(), Rejection>>,
+        beacon: drand::Beacon,
+    },
+    RetrieveChunk {
+        request: RetrieveChunk,
+        resp: oneshot::Sender<Result<Vec<u8>, Rejection>>,
         beacon: drand::Beacon,
     },
     VerifySignature {
-        signed_message: Signed<Message>,
+        signed_request: Signed<Request>,
         resp: oneshot::Sender<bool>,
     },
 }
@@ -105,10 +271,19 @@ impl Workers {
     }
 
     #[tracing::instrument(skip_all)]
-    pub async fn sign(&self, message: Message) -> Signed<Message> {
+    pub async fn sign(&self, response: Response) -> Signed<Response> {
         let (sender, receiver) = oneshot::channel();
         self.send_job(WorkerJob::Sign {
-            message,
+            response,
+            resp: sender,
+        });
+        receiver.await.unwrap()
+    }
+
+    pub async fn sign_hello(&self, hello: DeaddropHello) -> Signed<DeaddropHello> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_job(WorkerJob::SignHello {
+            hello,
             resp: sender,
         });
         receiver.await.unwrap()
@@ -119,7 +294,7 @@ impl Workers {
         request: PublishDocument,
         document_chain: drand::ChainInfo,
         document_beacon: drand::Beacon,
-    ) -> bool {
+    ) -> Result<Publication, Rejection> {
         let (sender, receiver) = oneshot::channel();
         self.send_job(WorkerJob::PublishDocument {
             request,
@@ -135,7 +310,7 @@ impl Workers {
         &self,
         request: RetrieveDocuments,
         beacon: drand::Beacon,
-    ) -> Vec<Signed<Document>> {
+    ) -> Result<Vec<Signed<Document>>, Rejection> {
         let (sender, receiver) = oneshot::channel();
         self.send_job(WorkerJob::RetrieveDocuments {
             request,
@@ -149,7 +324,7 @@ impl Workers {
         &self,
         request: RetrieveDocumentIds,
         beacon: drand::Beacon,
-    ) -> Option<(Vec<DocumentId>, Option<Signed<UpdateAllowedKeys>>)> {
+    ) -> DocumentIdsResult {
         let (sender, receiver) = oneshot::channel();
         self.send_job(WorkerJob::RetrieveDocumentIds {
             request,
@@ -159,10 +334,82 @@ impl Workers {
         receiver.await.unwrap()
     }
 
-    pub async fn verify_signature(&self, signed_message: Signed<Message>) -> bool {
+    pub async fn retrieve_digests(
+        &self,
+        request: RetrieveDigests,
+        beacon: drand::Beacon,
+    ) -> Result<Vec<RoundDigest>, Rejection> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_job(WorkerJob::RetrieveDigests {
+            request,
+            resp: sender,
+            beacon,
+        });
+        receiver.await.unwrap()
+    }
+
+    pub async fn retrieve_round_ids(
+        &self,
+        request: RetrieveRoundIds,
+        beacon: drand::Beacon,
+    ) -> Result<Vec<DocumentId>, Rejection> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_job(WorkerJob::RetrieveRoundIds {
+            request,
+            resp: sender,
+            beacon,
+        });
+        receiver.await.unwrap()
+    }
+
+    pub async fn replicate_document(
+        &self,
+        document: SignedDocument,
+        document_chain: drand::ChainInfo,
+        document_beacon: drand::Beacon,
+    ) -> Result<Publication, Rejection> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_job(WorkerJob::ReplicateDocument {
+            document,
+            document_chain,
+            document_beacon,
+            resp: sender,
+        });
+        receiver.await.unwrap()
+    }
+
+    pub async fn upload_chunk(
+        &self,
+        request: UploadChunk,
+        beacon: drand::Beacon,
+    ) -> Result<(), Rejection> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_job(WorkerJob::UploadChunk {
+            request,
+            resp: sender,
+            beacon,
+        });
+        receiver.await.unwrap()
+    }
+
+    pub async fn retrieve_chunk(
+        &self,
+        request: RetrieveChunk,
+        beacon: drand::Beacon,
+    ) -> Result<Vec<u8>, Rejection> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_job(WorkerJob::RetrieveChunk {
+            request,
+            resp: sender,
+            beacon,
+        });
+        receiver.await.unwrap()
+    }
+
+    pub async fn verify_signature(&self, signed_request: Signed<Request>) -> bool {
         let (sender, receiver) = oneshot::channel();
         self.send_job(WorkerJob::VerifySignature {
-            signed_message,
+            signed_request,
             resp: sender,
         });
         receiver.await.unwrap()
@@ -177,8 +424,11 @@ impl Workers {
     fn worker_entrypoint(state: SharedState, receiver: Receiver<WorkerJob>) {
         while let Ok(job) = receiver.recv() {
             match job {
-                WorkerJob::Sign { message, resp } => {
-                    let _ = resp.send(sign(&state, message));
+                WorkerJob::Sign { response, resp } => {
+                    let _ = resp.send(sign(&state, response));
+                }
+                WorkerJob::SignHello { hello, resp } => {
+                    let _ = resp.send(Signed::sign(&state.private_key, hello));
                 }
                 WorkerJob::PublishDocument {
                     request,
@@ -207,11 +457,52 @@ impl Workers {
                 } => {
                     let _ = resp.send(retreive_document_ids(&state, request, beacon));
                 }
+                WorkerJob::RetrieveDigests {
+                    request,
+                    resp,
+                    beacon,
+                } => {
+                    let _ = resp.send(retrieve_digests(&state, request, beacon));
+                }
+                WorkerJob::RetrieveRoundIds {
+                    request,
+                    resp,
+                    beacon,
+                } => {
+                    let _ = resp.send(retrieve_round_ids(&state, request, beacon));
+                }
+                WorkerJob::ReplicateDocument {
+                    document,
+                    document_chain,
+                    document_beacon,
+                    resp,
+                } => {
+                    let _ = resp.send(replicate_document(
+                        &state,
+                        document,
+                        document_chain,
+                        document_beacon,
+                    ));
+                }
+                WorkerJob::UploadChunk {
+                    request,
+                    resp,
+                    beacon,
+                } => {
+                    let _ = resp.send(upload_chunk(&state, request, beacon));
+                }
+                WorkerJob::RetrieveChunk {
+                    request,
+                    resp,
+                    beacon,
+                } => {
+                    let _ = resp.send(retrieve_chunk(&state, request, beacon));
+                }
                 WorkerJob::VerifySignature {
-                    signed_message,
+                    signed_request,
                     resp,
                 } => {
-                    let _ = resp.send(verify_signature(&state, signed_message));
+                    let _ = resp.send(verify_signature(&state, signed_request));
                 }
             }
         }
@@ -219,34 +510,67 @@ impl Workers {
 }
 
 pub async fn run(config: Config) -> std::io::Result<()> {
-    let success_response = Signed::sign(&config.private_key, Message::Success);
-    let state = Arc::new(State {
-        mode: config.mode,
-        private_key: config.private_key,
-        asset_owner_key: config.asset_owner_key.clone(),
-        difficulty: config.difficulty,
-        acceptance_window: config.acceptance_window,
-        drand_client: drand::CachingClient::new(drand::DEFAULT_API_URL),
-        success_response,
-        state_mut: RwLock::new(StateMut {
-            published_documents: Default::default(),
-            allowed_sender_ring: Default::default(),
-            allowed_receiver_keys: Default::default(),
-            keys_update_asset_owner: None,
-        }),
-    });
+    let listener = TcpListener::bind(config.address).await?;
+    serve(config, listener).await
+}
+
+/// Runs the deaddrop on an already bound `listener`, `config.address` is ignored.
+pub(crate) async fn serve(config: Config, listener: TcpListener) -> std::io::Result<()> {
+    let state = Arc::new(open_state(&config)?);
 
     let workers = Workers::new(
         state.clone(),
         usize::from(std::thread::available_parallelism().unwrap()),
     );
 
+    for chain in config.prefetch_chains {
+        if !state.drand_client.is_pinned(&chain) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("prefetched chain {chain} is not pinned"),
+            ));
+        }
+        let drand_client = state.drand_client.clone();
+        tokio::spawn(async move { drand_client.prefetch(&chain).await });
+    }
+
+    let max_age_rounds = config.retention.max_age_rounds;
+    if !config.retention.is_unlimited() {
+        tokio::spawn(enforce_retention(state.clone(), config.retention));
+    }
+
+    if !config.peers.is_empty() {
+        tokio::spawn(synchronize_peers(
+            state.clone(),
+            workers.clone(),
+            config.peers,
+            config.peer_keys,
+            config.sync_interval,
+            max_age_rounds,
+        ));
+    }
+
     if let Some(update) = config.asset_owner_update {
         handle_update_allowed_keys(&state, &workers, update).await;
     }
 
-    let listener = TcpListener::bind(config.address).await?;
+    accept_connections(state, workers, listener, config.max_connections).await
+}
+
+/// Handles the connections made to `listener`, at most `max_connections` at once.
+async fn accept_connections(
+    state: SharedState,
+    workers: Workers,
+    listener: TcpListener,
+    max_connections: usize,
+) -> std::io::Result<()> {
+    let connections = Arc::new(Semaphore::new(max_connections));
     loop {
+        let permit = connections
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
         let (stream, _client_address) = match listener.accept().await {
             Ok((stream, client_address)) => (stream, client_address),
             Err(e) => {
@@ -258,153 +582,930 @@ pub async fn run(config: Config) -> std::io::Result<()> {
         let workers = workers.clone();
         let state = state.clone();
         tokio::spawn(async move {
-            if let Err(err) = handle_connection(state, workers, stream).await {
+            if let Err(err) = handle_connection(state, workers, stream, permit).await {
                 tracing::error!("failed to handle connection: {err}");
             }
         });
     }
 }
 
-async fn handle_connection(
-    state: SharedState,
-    workers: Workers,
-    stream: TcpStream,
-) -> std::io::Result<()> {
-    tracing::info!("handling connection");
+/// Checks the configuration and creates the state of the deaddrop, opening its stores.
+fn open_state(config: &Config) -> std::io::Result<State> {
+    if config.max_connections == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "at least one connection must be allowed",
+        ));
+    }
+    if config.puzzle_algorithms.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "at least one puzzle algorithm must be accepted",
+        ));
+    }
+    if let Some(adjustment) = config.difficulty_adjustment {
+        if adjustment.min_difficulty < config.difficulty
+            || adjustment.max_difficulty < adjustment.min_difficulty
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "difficulty adjustment must stay between the difficulty and the max difficulty",
+            ));
+        }
+    }
+    let rate_limit = match config.mode {
+        ModeOfOperation::SenderRestricted | ModeOfOperation::FullyRestricted => config.rate_limit,
+        ModeOfOperation::Open | ModeOfOperation::ReceiverRestricted => {
+            if config.rate_limit.is_some() {
+                tracing::warn!("rate limit ignored, senders are not restricted");
+            }
+            None
+        }
+    };
+    let published_documents = open_document_store(config.data_dir.as_deref())?;
+    let chunks = open_chunk_store(config.data_dir.as_deref())?;
+    let mut features = vec![FEATURE_SUBSCRIBE.to_string(), FEATURE_CHUNKS.to_string()];
+    if config.difficulty_adjustment.is_some() {
+        features.push(FEATURE_ADAPTIVE_DIFFICULTY.to_string());
+    }
+    let hello = DeaddropHello {
+        version: PROTOCOL_VERSION,
+        mode: config.mode,
+        difficulty: config.difficulty,
+        puzzle_algorithms: config.puzzle_algorithms.clone(),
+        acceptance_window: config.acceptance_window,
+        features,
+        max_frame_size: config.max_frame_size,
+        rate_limit,
+        nonce: [0; 16],
+    };
+    Ok(State {
+        mode: config.mode,
+        public_key: config.private_key.public_key(),
+        private_key: config.private_key.clone(),
+        asset_owner_key: config.asset_owner_key.clone(),
+        difficulty: config.difficulty,
+        document_difficulty: Mutex::new(DifficultyController::new(
+            config.difficulty,
+            config.difficulty_adjustment,
+        )),
+        puzzle_algorithms: config.puzzle_algorithms.clone(),
+        acceptance_window: config.acceptance_window,
+        drand_client: config
+            .drand_client
+            .clone()
+            .unwrap_or_else(|| drand::CachingClient::new(drand::DEFAULT_API_URL)),
+        replay_cache: Default::default(),
+        rate_limit,
+        key_images: Default::default(),
+        max_age_rounds: config.retention.max_age_rounds,
+        hello,
+        published: broadcast::channel(SUBSCRIPTION_BUFFER).0,
+        evicted: Default::default(),
+        max_frame_size: config.max_frame_size,
+        max_chunk_bytes: config.retention.max_chunk_bytes,
+        request_timeout: config.request_timeout,
+        state_mut: RwLock::new(StateMut {
+            published_documents,
+            chunks,
+            allowed_sender_ring: Default::default(),
+            allowed_receiver_keys: Default::default(),
+            keys_update_asset_owner: None,
+        }),
+    })
+}
 
-    let mut stream = BufStream::new(stream);
+fn open_chunk_store(data_dir: Option<&Path>) -> std::io::Result<Box<dyn ChunkStore>> {
+    match data_dir {
+        Some(data_dir) => Ok(Box::new(DirChunkStore::open(data_dir.join("chunks"))?)),
+        None => Ok(Box::new(MemoryChunkStore::default())),
+    }
+}
+
+fn open_document_store(data_dir: Option<&Path>) -> std::io::Result<Box<dyn DocumentStore>> {
+    let data_dir = match data_dir {
+        Some(data_dir) => data_dir,
+        None => return Ok(Box::new(MemoryStore::default())),
+    };
+    std::fs::create_dir_all(data_dir)?;
+    let path = data_dir.join("documents.log");
+    let store = LogStore::open(&path)?;
+    tracing::info!("loaded {} documents from {}", store.len(), path.display());
+    Ok(Box::new(store))
+}
 
+async fn enforce_retention(state: SharedState, policy: RetentionPolicy) {
+    let mut interval = tokio::time::interval(policy.interval);
     loop {
-        let signed = match rle::async_deserialize_and_read::<Signed<Message>, _>(&mut stream).await
-        {
-            Ok(signed) => signed,
-            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
-            Err(err) => return Err(err),
+        interval.tick().await;
+
+        let (documents, chunks) = {
+            let state_mut = state.state_mut.read().unwrap();
+            (
+                state_mut.published_documents.metadata(),
+                state_mut.chunks.metadata(),
+            )
         };
+        let chains = documents
+            .iter()
+            .map(|document| &document.chain)
+            .chain(chunks.iter().map(|chunk| &chunk.chain))
+            .collect::<HashSet<_>>();
+        let mut current_rounds = HashMap::new();
+        for chain in chains {
+            match state.drand_client.chain_latest_randomness(chain).await {
+                Ok(beacon) => {
+                    current_rounds.insert(chain.clone(), beacon.round_number);
+                }
+                Err(err) => {
+                    tracing::warn!("failed to get latest round of chain {chain}: {err}")
+                }
+            }
+        }
 
-        if !workers.verify_signature(signed.clone()).await {
-            tracing::warn!(
-                "signature verification failed for message {:#?}",
-                signed.content
-            );
+        let evicted_chunks = policy.select_chunk_evictions(&chunks, &current_rounds);
+        if !evicted_chunks.is_empty() {
+            tracing::info!("evicting {} chunks", evicted_chunks.len());
+        }
+        for hash in evicted_chunks.iter() {
+            let mut state_mut = state.state_mut.write().unwrap();
+            if let Err(err) = state_mut.chunks.remove(hash) {
+                tracing::error!("failed to evict chunk {hash}: {err}");
+            }
+        }
+
+        let evicted = policy.select_evictions(documents, &current_rounds);
+        if evicted.is_empty() {
             continue;
         }
 
-        match signed.content {
-            Message::RetrieveDocumentIds(request) => {
-                handle_retrieve_document_ids(&state, &workers, &mut stream, request).await
-            }
-            Message::RetrieveDocuments(request) => {
-                handle_retrieve_documents(&state, &workers, &mut stream, request).await
+        tracing::info!("evicting {} documents", evicted.len());
+        for id in evicted.iter() {
+            // the lock is taken per document so requests are served in between
+            let mut state_mut = state.state_mut.write().unwrap();
+            if let Err(err) = state_mut.published_documents.remove(id) {
+                tracing::error!("failed to evict document {id:?}: {err}");
             }
-            Message::PublishDocument(request) => {
-                handle_publish_documents(&state, &workers, &mut stream, request).await;
-                // let response = sign(state.clone(), Message::Success);
-                // rle::async_serialize_and_write(&mut stream, &response)
-                //     .await
-                //     .unwrap();
-            }
-            Message::UpdateAllowedKeys(update) => {
-                handle_update_allowed_keys(&state, &workers, Signed::new(update, signed.signature))
-                    .await
-            }
-            Message::RetrieveKeys => handle_retreive_keys(&state, &workers, &mut stream).await,
-            _ => return Err(std::io::Error::other("invalid message type received")),
+        }
+        state.evicted.lock().unwrap().extend(evicted);
+        if let Err(err) = compact_document_store(&state).await {
+            tracing::error!("failed to compact document store: {err}");
         }
     }
-
-    tracing::info!("connection handling terminated");
-    Ok(())
 }
 
-async fn handle_retreive_keys(state: &SharedState, _workers: &Workers, stream: &mut ClientStream) {
-    let update_message = {
-        let state_mut = state.state_mut.write().unwrap();
-        state_mut
-            .keys_update_asset_owner
-            .clone()
-            .expect("asset owner hasnt sent key update")
+/// Compacts the document store, copying the documents on a blocking thread while the store keeps
+/// serving requests. The store is only locked for writing to apply the compaction.
+async fn compact_document_store(state: &SharedState) -> std::io::Result<()> {
+    let compaction = state
+        .state_mut
+        .read()
+        .unwrap()
+        .published_documents
+        .start_compaction();
+    let Some(mut compaction) = compaction else {
+        return Ok(());
     };
-    rle::async_serialize_and_write(stream, &update_message)
+    let compaction = tokio::task::spawn_blocking(move || compaction.run().map(|()| compaction))
         .await
-        .unwrap();
+        .map_err(std::io::Error::other)??;
+    state
+        .state_mut
+        .write()
+        .unwrap()
+        .published_documents
+        .finish_compaction(compaction)
 }
 
-#[tracing::instrument(skip_all)]
-async fn handle_retrieve_document_ids(
-    _state: &SharedState,
-    workers: &Workers,
-    stream: &mut ClientStream,
-    request: RetrieveDocumentIds,
+async fn synchronize_peers(
+    state: SharedState,
+    workers: Workers,
+    peers: Vec<DeaddropAddr>,
+    peer_keys: HashMap<DeaddropAddr, PublicKey>,
+    interval: Duration,
+    max_age_rounds: Option<u64>,
 ) {
-    let beacon = _state
-        .drand_client
-        .chain_latest_randomness(&request.chain)
-        .await
-        .unwrap();
+    for peer in peers.iter().filter(|peer| !peer_keys.contains_key(peer)) {
+        tracing::warn!("key of peer {peer:?} is not pinned");
+    }
+    let mut conns = HashMap::<DeaddropAddr, DeaddropConn>::new();
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
 
-    let response = match workers.retreive_document_ids(request, beacon).await {
-        Some((document_ids, allowed_sender_keys)) => {
-            let message = Message::DocumentIdList(DocumentIdList {
-                message_ids: document_ids,
-                allowed_sender_keys,
-            });
-            let response = workers.sign(message).await;
-            response
+        for peer in peers.iter() {
+            let conn = match conns.get(peer) {
+                Some(conn) => conn.clone(),
+                None => {
+                    let key = peer_keys.get(peer);
+                    match DeaddropConn::connect(peer, key, state.max_frame_size).await {
+                        Ok(conn) => {
+                            conns.insert(peer.clone(), conn.clone());
+                            conn
+                        }
+                        Err(err) => {
+                            tracing::warn!("failed to connect to peer {peer:?}: {err}");
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            match synchronize_peer(&state, &workers, &conn, max_age_rounds).await {
+                Ok(0) => {}
+                Ok(replicated) => {
+                    tracing::info!("replicated {replicated} documents from peer {peer:?}")
+                }
+                Err(err) => {
+                    tracing::warn!("failed to synchronize with peer {peer:?}: {err}");
+                    conns.remove(peer);
+                }
+            }
         }
-        None => {
-            panic!("client sent bad retreive document ids message");
+    }
+}
+
+/// Failure to synchronize with a peer deaddrop.
+#[derive(Debug)]
+enum SyncError {
+    Exchange(ExchangeError),
+    Drand(drand::ClientError),
+    /// The peer is configured differently or sent something it should not have.
+    Peer(String),
+}
+
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::Exchange(err) => err.fmt(f),
+            SyncError::Drand(err) => write!(f, "drand error: {err}"),
+            SyncError::Peer(reason) => f.write_str(reason),
         }
-    };
-    rle::async_serialize_and_write(stream, &response)
-        .await
-        .unwrap();
+    }
 }
 
-#[tracing::instrument(skip_all)]
-async fn handle_retrieve_documents(
-    _state: &SharedState,
-    workers: &Workers,
-    stream: &mut ClientStream,
-    request: RetrieveDocuments,
-) {
-    let beacon = _state
-        .drand_client
-        .chain_latest_randomness(&request.chain)
-        .await
-        .unwrap();
-    let documents = workers.retreive_documents(request, beacon).await;
-    let message = Message::DocumentList(DocumentList { documents });
-    let response = workers.sign(message).await;
-    rle::async_serialize_and_write(stream, &response)
-        .await
-        .unwrap();
+impl From<ExchangeError> for SyncError {
+    fn from(value: ExchangeError) -> Self {
+        SyncError::Exchange(value)
+    }
 }
 
-async fn handle_publish_documents(
+impl From<drand::ClientError> for SyncError {
+    fn from(value: drand::ClientError) -> Self {
+        SyncError::Drand(value)
+    }
+}
+
+/// Pulls the documents stored by `peer` that are missing from this deaddrop. Rounds are compared
+/// by digest first so only the ids of rounds that differ are exchanged. Documents evicted by the
+/// retention policy count as stored so they are not pulled again. Returns the number of documents
+/// stored.
+async fn synchronize_peer(
     state: &SharedState,
     workers: &Workers,
-    stream: &mut ClientStream,
-    request: PublishDocument,
-) {
-    let document = &request.document.content;
+    peer: &DeaddropConn,
+    max_age_rounds: Option<u64>,
+) -> Result<usize, SyncError> {
+    let hello = &peer.hello().content;
+    if hello.mode != state.mode {
+        return Err(SyncError::Peer(format!(
+            "peer operates in {} mode",
+            hello.mode
+        )));
+    }
+
     let chain = state
         .drand_client
-        .chain_info(&document.drand.chain)
+        .chain_list()
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| SyncError::Peer("no drand chains found".to_string()))?;
+    let beacon = state.drand_client.chain_latest_randomness(&chain).await?;
+    let nonce_solution = crypto_puzzle_solve(&[], &beacon, hello.difficulty);
+    let since_round = match max_age_rounds {
+        Some(max_age) => beacon.round_number.saturating_sub(max_age),
+        None => 0,
+    };
+    let request = |message| {
+        let request = Request {
+            context: RequestContext {
+                nonce: crypto::random_bytes(),
+                deaddrop: peer.public_key().clone(),
+                chain: chain.clone(),
+                round: beacon.round_number,
+            },
+            message,
+        };
+        Signed::sign(&state.private_key, request)
+    };
+
+    let message = Message::RetrieveDigests(RetrieveDigests {
+        since_round,
+        beacon: beacon.clone(),
+        chain: chain.clone(),
+        nonce_solution,
+    });
+    let digests = match exchange(peer.clone(), request(message), true).await? {
+        Message::DigestList(list) => list.digests,
+        other => return Err(unexpected_response("digest request", other).into()),
+    };
+
+    let (local_digests, evicted) = {
+        let mut evicted = state.evicted.lock().unwrap();
+        evicted.retain(|id| id.round >= since_round);
+        let evicted = evicted.clone();
+        let mut ids = state.state_mut.read().unwrap().published_documents.ids();
+        ids.extend(evicted.iter().cloned());
+        let digests = round_digests(ids, since_round)
+            .into_iter()
+            .map(|digest| (digest.round, digest))
+            .collect::<HashMap<_, _>>();
+        (digests, evicted)
+    };
+    let rounds = digests
+        .into_iter()
+        .filter(|digest| local_digests.get(&digest.round) != Some(digest))
+        .map(|digest| digest.round)
+        .collect::<Vec<_>>();
+    if rounds.is_empty() {
+        return Ok(0);
+    }
+
+    let message = Message::RetrieveRoundIds(RetrieveRoundIds {
+        rounds,
+        beacon: beacon.clone(),
+        chain: chain.clone(),
+        nonce_solution,
+    });
+    let ids = match exchange(peer.clone(), request(message), true).await? {
+        Message::DocumentIdList(list) => list.message_ids,
+        other => return Err(unexpected_response("round id request", other).into()),
+    };
+    let missing = {
+        let state_mut = state.state_mut.read().unwrap();
+        ids.into_iter()
+            .filter(|id| !evicted.contains(id) && !state_mut.published_documents.contains(id))
+            .collect::<Vec<_>>()
+    };
+
+    let mut replicated = 0;
+    for batch in missing.chunks(SYNC_BATCH_SIZE) {
+        let message = Message::RetrieveDocuments(RetrieveDocuments {
+            message_ids: batch.to_vec(),
+            beacon: beacon.clone(),
+            chain: chain.clone(),
+            nonce_solution,
+        });
+        let documents = match exchange(peer.clone(), request(message), true).await? {
+            Message::DocumentList(list) => list.documents,
+            other => return Err(unexpected_response("document request", other).into()),
+        };
+
+        for document in documents {
+            if !batch.contains(&document.content.id) {
+                return Err(SyncError::Peer(
+                    "peer returned a document that was not requested".to_string(),
+                ));
+            }
+            let document_chain = &document.content.drand.chain;
+            if !state.drand_client.is_pinned(document_chain) {
+                tracing::warn!("rejected document from peer: chain {document_chain} is not pinned");
+                continue;
+            }
+            let chain_info = state.drand_client.chain_info(document_chain).await?;
+            let document_beacon = state
+                .drand_client
+                .chain_latest_randomness(document_chain)
+                .await?;
+            match workers
+                .replicate_document(document, chain_info, document_beacon)
+                .await
+            {
+                Ok(Publication::Stored) => replicated += 1,
+                Ok(Publication::AlreadyStored(_)) => {}
+                Err(rejection) => tracing::warn!(
+                    "rejected document from peer ({}): {}",
+                    rejection.code,
+                    rejection.reason
+                ),
+            }
+        }
+    }
+
+    Ok(replicated)
+}
+
+/// Digests of the documents with the given ids, per round starting at `since_round`.
+fn round_digests(ids: Vec<DocumentId>, since_round: u64) -> Vec<RoundDigest> {
+    let mut rounds = BTreeMap::<u64, Vec<Vec<u8>>>::new();
+    for id in ids.iter().filter(|id| id.round >= since_round) {
+        rounds
+            .entry(id.round)
+            .or_default()
+            .push(bincode::serialize(id).unwrap());
+    }
+    rounds
+        .into_iter()
+        .map(|(round, mut ids)| {
+            ids.sort();
+            let mut hasher = Sha256Hasher::default();
+            for id in ids.iter() {
+                hasher.update(id);
+            }
+            RoundDigest {
+                round,
+                count: ids.len() as u64,
+                digest: hasher.finalize(),
+            }
+        })
+        .collect()
+}
+
+async fn handle_connection(
+    state: SharedState,
+    workers: Workers,
+    stream: TcpStream,
+    // held until the connection ends, subscriptions included
+    _permit: OwnedSemaphorePermit,
+) -> std::io::Result<()> {
+    tracing::info!("handling connection");
+
+    let mut stream = BufStream::new(stream);
+
+    // the version is read alone since hellos of other versions may not deserialize
+    let data = read_timeout(&state, rle::async_read(&mut stream, state.max_frame_size)).await?;
+    let version = rle::deserialize::<u32>(&data)?;
+    let nonce = if version == PROTOCOL_VERSION {
+        rle::deserialize::<ClientHello>(&data)?.nonce
+    } else {
+        [0; 16]
+    };
+    let hello = workers
+        .sign_hello(DeaddropHello {
+            nonce,
+            ..state.hello.clone()
+        })
+        .await;
+    rle::async_serialize_and_write(&mut stream, &hello).await?;
+    if version != PROTOCOL_VERSION {
+        tracing::warn!(
+            "closing connection from client using protocol version {version}, expected {}",
+            PROTOCOL_VERSION
+        );
+        return Ok(());
+    }
+
+    loop {
+        let size = match rle::async_read_size(&mut stream, state.max_frame_size).await {
+            Ok(size) => size,
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => {
+                let Some(too_large) = FrameTooLarge::from_io(&err) else {
+                    return Err(err);
+                };
+                // the frame is left unread, so nothing else can be read from the connection
+                tracing::warn!("closing connection: {too_large}");
+                let rejection = Rejection::new(ErrorCode::FrameTooLarge, too_large.to_string());
+                write_response(&workers, &mut stream, [0; 16], Err(rejection)).await?;
+                break;
+            }
+        };
+        let data = read_timeout(&state, rle::async_read_data(&mut stream, size)).await?;
+        let signed = match rle::deserialize::<Signed<Request>>(&data) {
+            Ok(signed) => signed,
+            Err(err) => {
+                tracing::warn!("closing connection after malformed request: {err}");
+                let rejection = Rejection::new(ErrorCode::MalformedRequest, "malformed request");
+                write_response(&workers, &mut stream, [0; 16], Err(rejection)).await?;
+                break;
+            }
+        };
+        drop(data);
+        let nonce = signed.content.context.nonce;
+
+        if !signed.content.message.is_request() {
+            let rejection = Rejection::new(
+                ErrorCode::UnexpectedMessage,
+                "deaddrop only accepts request messages",
+            );
+            write_response(&workers, &mut stream, nonce, Err(rejection)).await?;
+            continue;
+        }
+
+        if let Err(rejection) = check_request(&state, &workers, &signed).await {
+            // key updates from the asset owner are not answered
+            if std::matches!(signed.content.message, Message::UpdateAllowedKeys(_)) {
+                tracing::warn!(
+                    "ignoring key update ({}): {}",
+                    rejection.code,
+                    rejection.reason
+                );
+            } else {
+                write_response(&workers, &mut stream, nonce, Err(rejection)).await?;
+            }
+            continue;
+        }
+
+        let response = match signed.content.message {
+            Message::RetrieveDocumentIds(request) => {
+                handle_retrieve_document_ids(&state, &workers, request).await
+            }
+            Message::RetrieveDocuments(request) => {
+                handle_retrieve_documents(&state, &workers, request).await
+            }
+            Message::PublishDocument(request) => {
+                handle_publish_documents(&state, &workers, request).await
+            }
+            Message::UpdateAllowedKeys(update) => {
+                handle_update_allowed_keys(&state, &workers, update).await;
+                continue;
+            }
+            Message::RetrieveDigests(request) => {
+                handle_retrieve_digests(&state, &workers, request).await
+            }
+            Message::RetrieveRoundIds(request) => {
+                handle_retrieve_round_ids(&state, &workers, request).await
+            }
+            Message::RetrieveKeys => handle_retreive_keys(&state),
+            Message::RetrieveDifficulty { chain } => {
+                handle_retrieve_difficulty(&state, chain).await
+            }
+            Message::UploadChunk(request) => handle_upload_chunk(&state, &workers, request).await,
+            Message::RetrieveMissingChunks(request) => {
+                handle_retrieve_missing_chunks(&state, request).await
+            }
+            Message::RetrieveChunk(request) => {
+                handle_retrieve_chunk(&state, &workers, request).await
+            }
+            Message::Subscribe(request) => match verify_subscription(&state, &request).await {
+                Ok(()) => {
+                    // the connection only carries pushed documents from now on
+                    handle_subscribe(&state, &workers, &mut stream, nonce, request).await?;
+                    break;
+                }
+                Err(rejection) => Err(rejection),
+            },
+            _ => unreachable!("non request messages are rejected before this point"),
+        };
+        write_response(&workers, &mut stream, nonce, response).await?;
+    }
+
+    tracing::info!("connection handling terminated");
+    Ok(())
+}
+
+/// Reads from a client, failing if the read does not complete within the request timeout.
+async fn read_timeout<T>(
+    state: &State,
+    read: impl std::future::Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    tokio::time::timeout(state.request_timeout, read)
         .await
-        .unwrap();
-    let beacon = state
+        .map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "client took too long to send")
+        })?
+}
+
+async fn write_response(
+    workers: &Workers,
+    stream: &mut ClientStream,
+    nonce: [u8; 16],
+    response: Result<Message, Rejection>,
+) -> std::io::Result<()> {
+    let message = match response {
+        Ok(message) => message,
+        Err(rejection) => {
+            tracing::warn!(
+                "rejecting request ({}): {}",
+                rejection.code,
+                rejection.reason
+            );
+            rejection.into()
+        }
+    };
+    let response = workers.sign(Response { nonce, message }).await;
+    rle::async_serialize_and_write(stream, &response).await
+}
+
+/// Checks that a request is addressed to this deaddrop, made in a recent round, correctly signed
+/// and not a replay of an earlier request.
+async fn check_request(
+    state: &SharedState,
+    workers: &Workers,
+    signed: &Signed<Request>,
+) -> Result<(), Rejection> {
+    let context = &signed.content.context;
+    if context.deaddrop != state.public_key {
+        return Err(Rejection::new(
+            ErrorCode::WrongDeaddrop,
+            "request is addressed to another deaddrop",
+        ));
+    }
+
+    let current_round = latest_beacon(state, &context.chain).await?.round_number;
+    let window = state.request_window();
+    if context.round > current_round + 1 || context.round + window <= current_round {
+        return Err(Rejection::new(
+            ErrorCode::ExpiredBeacon,
+            format!(
+                "request round {} is outside the acceptance window",
+                context.round
+            ),
+        ));
+    }
+
+    if !workers.verify_signature(signed.clone()).await {
+        tracing::warn!(
+            "signature verification failed for message {:#?}",
+            signed.content.message
+        );
+        return Err(Rejection::new(
+            ErrorCode::InvalidSignature,
+            "invalid request signature",
+        ));
+    }
+
+    if !state
+        .replay_cache
+        .lock()
+        .unwrap()
+        .insert(context, current_round, window)
+    {
+        return Err(Rejection::new(
+            ErrorCode::ReplayedRequest,
+            "request was already received",
+        ));
+    }
+
+    Ok(())
+}
+
+async fn latest_beacon(state: &SharedState, chain: &str) -> Result<drand::Beacon, Rejection> {
+    state
         .drand_client
-        .chain_latest_randomness(&document.drand.chain)
+        .chain_latest_randomness(chain)
         .await
-        .unwrap();
-    if workers.publish_document(request, chain, beacon).await {
-        rle::async_serialize_and_write(stream, &state.success_response)
-            .await
-            .unwrap();
-    } else {
-        tracing::warn!("message invalid, not publishing");
-        panic!("should not be happening during testing");
+        .map_err(|err| {
+            Rejection::new(
+                ErrorCode::BeaconUnavailable,
+                format!("failed to get latest beacon of chain {chain}: {err}"),
+            )
+        })
+}
+
+async fn handle_upload_chunk(
+    state: &SharedState,
+    workers: &Workers,
+    request: UploadChunk,
+) -> Result<Message, Rejection> {
+    let beacon = latest_beacon(state, &request.chain).await?;
+    workers.upload_chunk(request, beacon).await?;
+    Ok(Message::Success)
+}
+
+async fn handle_retrieve_missing_chunks(
+    state: &SharedState,
+    request: RetrieveMissingChunks,
+) -> Result<Message, Rejection> {
+    let beacon = latest_beacon(state, &request.chain).await?;
+    verify_request_puzzle(state, &request.beacon, request.nonce_solution, &beacon)?;
+    let state_mut = state.state_mut.read().unwrap();
+    let chunks = request
+        .chunks
+        .into_iter()
+        .filter(|hash| !state_mut.chunks.contains(hash))
+        .collect();
+    Ok(Message::ChunkHashes(ChunkHashes { chunks }))
+}
+
+async fn handle_retrieve_chunk(
+    state: &SharedState,
+    workers: &Workers,
+    request: RetrieveChunk,
+) -> Result<Message, Rejection> {
+    let beacon = latest_beacon(state, &request.chain).await?;
+    let data = workers.retrieve_chunk(request, beacon).await?;
+    Ok(Message::Chunk(Chunk { data }))
+}
+
+/// Checks the puzzle of a subscription, which is the same as the one of a document request.
+async fn verify_subscription(state: &SharedState, request: &Subscribe) -> Result<(), Rejection> {
+    let beacon = latest_beacon(state, &request.chain).await?;
+    verify_request_puzzle(state, &request.beacon, request.nonce_solution, &beacon)
+}
+
+/// Pushes the documents published to the topic of `request` until the client disconnects.
+async fn handle_subscribe(
+    state: &SharedState,
+    workers: &Workers,
+    stream: &mut ClientStream,
+    nonce: [u8; 16],
+    request: Subscribe,
+) -> std::io::Result<()> {
+    let topic = request.topic;
+    // subscribe before reading the store so no document is missed in between
+    let mut published = state.published.subscribe();
+    let documents = match topic_documents(state, &topic, request.since_round) {
+        Ok(documents) => documents,
+        Err(rejection) => return write_response(workers, stream, nonce, Err(rejection)).await,
+    };
+    let sent = documents
+        .iter()
+        .map(|document| document.content.id.clone())
+        .collect::<HashSet<_>>();
+    // the backlog is pushed over as many frames as it needs, the first answering the request
+    let mut documents = documents.into_iter().peekable();
+    let mut first = true;
+    while first || documents.peek().is_some() {
+        let mut size = 0;
+        let mut frame = Vec::new();
+        while let Some(document) = documents.next_if(|document| {
+            size += bincode::serialized_size(document).unwrap();
+            frame.is_empty() || size <= response_budget(state)
+        }) {
+            frame.push(document);
+        }
+        let message = Message::DocumentList(DocumentList { documents: frame });
+        write_response(workers, stream, nonce, Ok(message)).await?;
+        first = false;
+    }
+
+    loop {
+        let received = tokio::select! {
+            received = published.recv() => received,
+            // clients send nothing once subscribed, so the read only ends when they go away
+            _ = stream.read_u8() => return Ok(()),
+        };
+        let document = match received {
+            Ok(document) => document,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                let rejection = Rejection::new(
+                    ErrorCode::Internal,
+                    format!("subscription fell behind by {skipped} documents"),
+                );
+                return write_response(workers, stream, nonce, Err(rejection)).await;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        if document.content.topic != topic || sent.contains(&document.content.id) {
+            continue;
+        }
+        let message = Message::DocumentList(DocumentList {
+            documents: vec![document],
+        });
+        write_response(workers, stream, nonce, Ok(message)).await?;
+    }
+}
+
+/// Bytes of documents sent in a single document list.
+fn response_budget(state: &State) -> u64 {
+    u64::from(state.max_frame_size).saturating_sub(RESPONSE_OVERHEAD)
+}
+
+fn topic_documents(
+    state: &SharedState,
+    topic: &str,
+    since_round: u64,
+) -> Result<Vec<SignedDocument>, Rejection> {
+    let state_mut = state.state_mut.read().unwrap();
+    let store = &state_mut.published_documents;
+    let mut documents = Vec::new();
+    for id in store.topic_ids(topic, since_round) {
+        match store.get(&id) {
+            Ok(Some(document)) => documents.push(document),
+            Ok(None) => {}
+            Err(err) => {
+                tracing::error!("failed to read document {id:?}: {err}");
+                return Err(Rejection::new(
+                    ErrorCode::Internal,
+                    "failed to read document store",
+                ));
+            }
+        }
+    }
+    Ok(documents)
+}
+
+fn handle_retreive_keys(state: &SharedState) -> Result<Message, Rejection> {
+    let state_mut = state.state_mut.read().unwrap();
+    match state_mut.keys_update_asset_owner.clone() {
+        Some(allowed_keys) => Ok(Message::AllowedKeys(AllowedKeys { allowed_keys })),
+        None => Err(Rejection::new(
+            ErrorCode::KeysUnavailable,
+            "asset owner has not sent a key update",
+        )),
+    }
+}
+
+async fn handle_retrieve_difficulty(
+    state: &SharedState,
+    chain: String,
+) -> Result<Message, Rejection> {
+    let round = latest_beacon(state, &chain).await?.round_number;
+    let raise = difficulty_raise(state, &chain, round);
+    let difficulties = state
+        .puzzle_algorithms
+        .iter()
+        .map(|requirement| PuzzleRequirement {
+            algorithm: requirement.algorithm,
+            difficulty: requirement.difficulty.saturating_add(raise),
+        })
+        .collect();
+    Ok(Message::Difficulty(Signed::sign(
+        &state.private_key,
+        PuzzleDifficulty {
+            chain,
+            round,
+            difficulties,
+        },
+    )))
+}
+
+/// Bits the publication load currently adds to the difficulty of documents of `chain`.
+fn difficulty_raise(state: &State, chain: &str, current_round: u64) -> u8 {
+    state
+        .document_difficulty
+        .lock()
+        .unwrap()
+        .difficulty(chain, current_round)
+        .saturating_sub(state.difficulty)
+}
+
+#[tracing::instrument(skip_all)]
+async fn handle_retrieve_document_ids(
+    state: &SharedState,
+    workers: &Workers,
+    request: RetrieveDocumentIds,
+) -> Result<Message, Rejection> {
+    let beacon = latest_beacon(state, &request.chain).await?;
+    let (document_ids, allowed_sender_keys) =
+        workers.retreive_document_ids(request, beacon).await?;
+    Ok(Message::DocumentIdList(DocumentIdList {
+        message_ids: document_ids,
+        allowed_sender_keys,
+    }))
+}
+
+async fn handle_retrieve_digests(
+    state: &SharedState,
+    workers: &Workers,
+    request: RetrieveDigests,
+) -> Result<Message, Rejection> {
+    let beacon = latest_beacon(state, &request.chain).await?;
+    let digests = workers.retrieve_digests(request, beacon).await?;
+    Ok(Message::DigestList(DigestList { digests }))
+}
+
+async fn handle_retrieve_round_ids(
+    state: &SharedState,
+    workers: &Workers,
+    request: RetrieveRoundIds,
+) -> Result<Message, Rejection> {
+    let beacon = latest_beacon(state, &request.chain).await?;
+    let message_ids = workers.retrieve_round_ids(request, beacon).await?;
+    Ok(Message::DocumentIdList(DocumentIdList {
+        message_ids,
+        allowed_sender_keys: None,
+    }))
+}
+
+#[tracing::instrument(skip_all)]
+async fn handle_retrieve_documents(
+    state: &SharedState,
+    workers: &Workers,
+    request: RetrieveDocuments,
+) -> Result<Message, Rejection> {
+    let beacon = latest_beacon(state, &request.chain).await?;
+    let documents = workers.retreive_documents(request, beacon).await?;
+    Ok(Message::DocumentList(DocumentList { documents }))
+}
+
+async fn handle_publish_documents(
+    state: &SharedState,
+    workers: &Workers,
+    request: PublishDocument,
+) -> Result<Message, Rejection> {
+    let chain = request.document.content.drand.chain.clone();
+    if !state.drand_client.is_pinned(&chain) {
+        return Err(Rejection::new(
+            ErrorCode::InvalidDocument,
+            format!("chain {chain} is not pinned"),
+        ));
+    }
+    let chain_info = state.drand_client.chain_info(&chain).await.map_err(|err| {
+        Rejection::new(
+            ErrorCode::BeaconUnavailable,
+            format!("failed to get info of chain {chain}: {err}"),
+        )
+    })?;
+    let beacon = latest_beacon(state, &chain).await?;
+    match workers
+        .publish_document(request, chain_info, beacon)
+        .await?
+    {
+        Publication::Stored => Ok(Message::Success),
+        Publication::AlreadyStored(id) => Ok(Message::AlreadyStored(id)),
     }
 }
 
@@ -414,12 +1515,15 @@ async fn handle_update_allowed_keys(
     update: Signed<UpdateAllowedKeys>,
 ) {
     tracing::info!("updating allowed keys");
-    let current_round = drand::get_beacon_from_first_chain()
-        .await
-        .unwrap()
-        .round_number;
+    let current_round = match state.drand_client.get_beacon_from_first_chain().await {
+        Ok(beacon) => beacon.round_number,
+        Err(err) => {
+            tracing::error!("failed to get latest beacon, ignoring key update: {err}");
+            return;
+        }
+    };
     let generated_in_round = update.content.beacon.round_number;
-    if current_round - generated_in_round > state.acceptance_window {
+    if current_round.saturating_sub(generated_in_round) > state.acceptance_window {
         tracing::warn!(
             "update beacon too old, current round: {}, generated in round: {}",
             current_round,
@@ -434,11 +1538,11 @@ async fn handle_update_allowed_keys(
 }
 
 #[inline(never)]
-fn verify_signature(state: &SharedState, signed_message: Signed<Message>) -> bool {
-    match signed_message.content {
-        Message::UpdateAllowedKeys(_) => {
+fn verify_signature(state: &SharedState, signed_request: Signed<Request>) -> bool {
+    match signed_request.content.message {
+        Message::UpdateAllowedKeys(ref update) => {
             if let Some(ref asset_owner_key) = state.asset_owner_key {
-                signed_message.verify_with(asset_owner_key)
+                signed_request.verify_with(asset_owner_key) && update.verify_with(asset_owner_key)
             } else {
                 tracing::warn!(
                     "asset owner key not configured, ignoring UpdateAllowedKeys message"
@@ -446,53 +1550,128 @@ fn verify_signature(state: &SharedState, signed_message: Signed<Message>) -> boo
                 false
             }
         }
-        Message::PublishDocument(_) => match state.mode {
-            ModeOfOperation::Open | ModeOfOperation::ReceiverRestricted => signed_message.verify(),
+        Message::PublishDocument(_) | Message::UploadChunk(_) => match state.mode {
+            ModeOfOperation::Open | ModeOfOperation::ReceiverRestricted => signed_request.verify(),
             ModeOfOperation::SenderRestricted | ModeOfOperation::FullyRestricted => {
                 let state_mut = state.state_mut.read().unwrap();
                 let ring = &state_mut.allowed_sender_ring;
-                signed_message.ring_verify(ring)
+                signed_request.ring_verify(ring)
             }
         },
-        Message::DocumentIdList(_) | Message::DocumentList(_) | Message::Success => {
+        Message::DocumentIdList(_)
+        | Message::DocumentList(_)
+        | Message::Success
+        | Message::AlreadyStored(_)
+        | Message::Error { .. }
+        | Message::AllowedKeys(_)
+        | Message::DigestList(_)
+        | Message::ChunkHashes(_)
+        | Message::Chunk(_)
+        | Message::Difficulty(_) => {
             unreachable!("deaddrop should not received this message type")
         }
-        Message::RetrieveDocumentIds(_) | Message::RetrieveDocuments(_) | Message::RetrieveKeys => {
+        Message::RetrieveDocumentIds(_)
+        | Message::RetrieveDocuments(_)
+        | Message::RetrieveDigests(_)
+        | Message::RetrieveRoundIds(_)
+        | Message::RetrieveKeys
+        | Message::Subscribe { .. }
+        | Message::RetrieveMissingChunks(_)
+        | Message::RetrieveChunk(_)
+        | Message::RetrieveDifficulty { .. } => {
             let state_mut = state.state_mut.read().unwrap();
             let ring = &state_mut.allowed_sender_ring;
-            signed_message.verify() || signed_message.ring_verify(ring)
+            signed_request.verify() || signed_request.ring_verify(ring)
         }
     }
 }
 
+/// Checks that the beacon used by a request is recent enough and that the request's puzzle was
+/// solved with it.
+fn verify_request_puzzle(
+    state: &SharedState,
+    request_beacon: &drand::Beacon,
+    nonce_solution: u32,
+    beacon: &drand::Beacon,
+) -> Result<(), Rejection> {
+    verify_puzzle(
+        state,
+        PuzzleAlgorithm::Sha256,
+        state.difficulty,
+        &[],
+        request_beacon,
+        nonce_solution,
+        beacon,
+    )
+}
+
+/// Like [`verify_request_puzzle`] for a puzzle solved over `data` with `algorithm`.
+fn verify_puzzle(
+    state: &SharedState,
+    algorithm: PuzzleAlgorithm,
+    difficulty: u8,
+    data: &[u8],
+    request_beacon: &drand::Beacon,
+    nonce_solution: u32,
+    beacon: &drand::Beacon,
+) -> Result<(), Rejection> {
+    if state.acceptance_window != 0
+        && request_beacon.round_number + state.acceptance_window <= beacon.round_number
+    {
+        return Err(Rejection::new(
+            ErrorCode::ExpiredBeacon,
+            format!(
+                "request beacon round {} is outside the acceptance window",
+                request_beacon.round_number
+            ),
+        ));
+    }
+    if !puzzle_verify(algorithm, data, request_beacon, difficulty, nonce_solution) {
+        return Err(Rejection::new(
+            ErrorCode::InvalidPuzzle,
+            "invalid request puzzle solution",
+        ));
+    }
+    Ok(())
+}
+
+/// Least difficulty required from puzzles solved with `algorithm`, if it is accepted.
+fn puzzle_difficulty(state: &SharedState, algorithm: PuzzleAlgorithm) -> Result<u8, Rejection> {
+    state
+        .puzzle_algorithms
+        .iter()
+        .find(|requirement| requirement.algorithm == algorithm)
+        .map(|requirement| requirement.difficulty)
+        .ok_or_else(|| {
+            Rejection::new(
+                ErrorCode::InvalidPuzzle,
+                format!("puzzle algorithm {algorithm} is not accepted"),
+            )
+        })
+}
+
 #[inline(never)]
 #[tracing::instrument(skip_all)]
 fn retreive_document_ids(
     state: &SharedState,
     request: RetrieveDocumentIds,
     beacon: drand::Beacon,
-) -> Option<(Vec<DocumentId>, Option<Signed<UpdateAllowedKeys>>)> {
-    if state.acceptance_window != 0
-        && request.beacon.round_number + state.acceptance_window <= beacon.round_number
-    {
-        return None;
-    }
-    if !crypto_puzzle_verify(
+) -> DocumentIdsResult {
+    let difficulty = puzzle_difficulty(state, request.puzzle_algorithm)?;
+    verify_puzzle(
+        state,
+        request.puzzle_algorithm,
+        difficulty,
         &[],
         &request.beacon,
-        state.difficulty,
         request.nonce_solution,
-    ) {
-        return None;
-    }
+        &beacon,
+    )?;
 
-    let mut document_ids = Vec::new();
     let state_mut = state.state_mut.read().unwrap();
-    for signed_document in state_mut.published_documents.values() {
-        if signed_document.content.id.round >= request.since_round {
-            document_ids.push(signed_document.content.id.clone());
-        }
-    }
+    let document_ids = state_mut
+        .published_documents
+        .topic_ids(&request.topic, request.since_round);
 
     let allowed_sender_keys = match state.mode {
         ModeOfOperation::Open | ModeOfOperation::ReceiverRestricted => None,
@@ -501,7 +1680,7 @@ fn retreive_document_ids(
         }
     };
 
-    Some((document_ids, allowed_sender_keys))
+    Ok((document_ids, allowed_sender_keys))
 }
 
 #[inline(never)]
@@ -510,22 +1689,12 @@ fn retreive_documents(
     state: &SharedState,
     request: RetrieveDocuments,
     beacon: drand::Beacon,
-) -> Vec<Signed<Document>> {
-    if state.acceptance_window != 0
-        && request.beacon.round_number + state.acceptance_window <= beacon.round_number
-    {
-        panic!("client sent bad request");
-    }
-    if !crypto_puzzle_verify(
-        &[],
-        &request.beacon,
-        state.difficulty,
-        request.nonce_solution,
-    ) {
-        panic!("client sent bad request");
-    }
+) -> Result<Vec<Signed<Document>>, Rejection> {
+    verify_request_puzzle(state, &request.beacon, request.nonce_solution, &beacon)?;
 
     let mut documents = Vec::new();
+    // documents beyond a frame are left for the client to request again
+    let mut size = 0;
 
     let span = tracing::info_span!("acquire_state_lock");
     let _guard = span.enter();
@@ -533,15 +1702,101 @@ fn retreive_documents(
     drop(_guard);
 
     for id in request.message_ids {
-        documents.push(
-            state_mut
-                .published_documents
-                .get(&id)
-                .cloned()
-                .expect("request unknown message id"),
-        );
+        match state_mut.published_documents.get(&id) {
+            Ok(Some(document)) => {
+                size += bincode::serialized_size(&document).unwrap();
+                // a document was published in a frame, so it alone fits in the response
+                if size > response_budget(state) && !documents.is_empty() {
+                    break;
+                }
+                documents.push(document);
+            }
+            Ok(None) => {
+                return Err(Rejection::new(
+                    ErrorCode::UnknownDocument,
+                    format!("unknown document {id:?}"),
+                ))
+            }
+            Err(err) => {
+                tracing::error!("failed to read document {id:?}: {err}");
+                return Err(Rejection::new(
+                    ErrorCode::Internal,
+                    "failed to read document store",
+                ));
+            }
+        }
+    }
+    Ok(documents)
+}
+
+#[inline(never)]
+fn upload_chunk(
+    state: &SharedState,
+    request: UploadChunk,
+    beacon: drand::Beacon,
+) -> Result<(), Rejection> {
+    if request.data.len() > MAX_CHUNK_SIZE {
+        return Err(Rejection::new(
+            ErrorCode::InvalidDocument,
+            format!("chunk exceeds {MAX_CHUNK_SIZE} bytes"),
+        ));
+    }
+    verify_puzzle(
+        state,
+        PuzzleAlgorithm::Sha256,
+        state.difficulty,
+        &request.data,
+        &request.beacon,
+        request.nonce_solution,
+        &beacon,
+    )?;
+
+    let mut state_mut = state.state_mut.write().unwrap();
+    let chunks = &mut state_mut.chunks;
+    if let Some(max_chunk_bytes) = state.max_chunk_bytes {
+        if !chunks.contains(&crypto::sha256(&request.data))
+            && chunks.size() + request.data.len() as u64 > max_chunk_bytes
+        {
+            return Err(Rejection::new(
+                ErrorCode::StorageFull,
+                "no room left for chunks",
+            ));
+        }
+    }
+    match chunks.insert(&request.data, &request.chain, beacon.round_number) {
+        Ok(hash) => {
+            tracing::debug!("stored chunk {hash}");
+            Ok(())
+        }
+        Err(err) => {
+            tracing::error!("failed to store chunk: {err}");
+            Err(Rejection::new(ErrorCode::Internal, "failed to store chunk"))
+        }
+    }
+}
+
+#[inline(never)]
+fn retrieve_chunk(
+    state: &SharedState,
+    request: RetrieveChunk,
+    beacon: drand::Beacon,
+) -> Result<Vec<u8>, Rejection> {
+    verify_request_puzzle(state, &request.beacon, request.nonce_solution, &beacon)?;
+    let state_mut = state.state_mut.read().unwrap();
+    match state_mut.chunks.get(&request.hash) {
+        Ok(Some(data)) => Ok(data),
+        Ok(None) => Err(Rejection::new(
+            ErrorCode::UnknownDocument,
+            format!("unknown chunk {}", request.hash),
+        )),
+        Err(err) => {
+            tracing::error!("failed to read chunk {}: {err}", request.hash);
+            Err(Rejection::new(
+                ErrorCode::Internal,
+                "failed to read chunk store",
+            ))
+        }
     }
-    documents
 }
 
 fn publish_document(
@@ -549,26 +1804,966 @@ fn publish_document(
     request: PublishDocument,
     document_chain: drand::ChainInfo,
     document_beacon: drand::Beacon,
-) -> bool {
-    if !request.document.content.is_valid(
-        state.difficulty,
+) -> Result<Publication, Rejection> {
+    verify_document_signature(state, &request.document)?;
+
+    let document = &request.document.content;
+    let current_round = document_beacon.round_number;
+    // the difficulty of the current round applies, the round of the document is the sender's
+    // choice within the acceptance window
+    let difficulty = puzzle_difficulty(state, document.puzzle_algorithm)?.saturating_add(
+        difficulty_raise(state, &document.drand.chain, current_round),
+    );
+    if !document.is_valid(
+        difficulty,
         state.acceptance_window,
         &document_chain,
         &document_beacon,
     ) {
-        return false;
+        return Err(Rejection::new(
+            ErrorCode::InvalidDocument,
+            "document failed validation",
+        ));
     }
 
-    tracing::info!("storing {:#?}", request.document.content.id);
-    let mut state_mut = state.state_mut.write().unwrap();
-    // TODO: handle duplicates?
-    state_mut
+    store_counted(state, request.document, current_round, false)
+}
+
+/// Stores a validated document, counting it towards the rate limit of its sender and the load
+/// the difficulty is adjusted to.
+fn store_counted(
+    state: &SharedState,
+    document: SignedDocument,
+    current_round: u64,
+    replicated: bool,
+) -> Result<Publication, Rejection> {
+    let chain = document.content.drand.chain.clone();
+    let publication = store_rate_limited(state, document, current_round, replicated)?;
+    if let Publication::Stored = publication {
+        state
+            .document_difficulty
+            .lock()
+            .unwrap()
+            .record(&chain, current_round);
+    }
+    Ok(publication)
+}
+
+/// Checks the signature of a document, which in the open modes must also be made by the key its
+/// sender tag is derived from.
+fn verify_document_signature(
+    state: &SharedState,
+    document: &SignedDocument,
+) -> Result<(), Rejection> {
+    let verified = match state.mode {
+        ModeOfOperation::Open | ModeOfOperation::ReceiverRestricted => {
+            document.verify()
+                && document.public_key().is_some_and(|key| {
+                    crypto::sha256(&key.to_bytes()) == document.content.id.sender_tag
+                })
+        }
+        ModeOfOperation::SenderRestricted | ModeOfOperation::FullyRestricted => {
+            let state_mut = state.state_mut.read().unwrap();
+            document.ring_verify(&state_mut.allowed_sender_ring)
+        }
+    };
+    if verified {
+        Ok(())
+    } else {
+        Err(Rejection::new(
+            ErrorCode::InvalidSignature,
+            "invalid document signature",
+        ))
+    }
+}
+
+/// Stores a document, counting it towards the rate limit of its sender if the deaddrop has one.
+/// Documents `replicated` from peers may be of older epochs than published ones.
+fn store_rate_limited(
+    state: &SharedState,
+    signed_document: SignedDocument,
+    current_round: u64,
+    replicated: bool,
+) -> Result<Publication, Rejection> {
+    let Some(rate_limit) = state.rate_limit else {
+        return store_document(state, signed_document, || Ok(()));
+    };
+
+    let document = &signed_document.content;
+    let chain = &document.drand.chain;
+    let context = rate_limit.context(chain, document.id.round);
+    let key_image = {
+        let state_mut = state.state_mut.read().unwrap();
+        signed_document.ring_verify_linkable(&state_mut.allowed_sender_ring, &context)
+    };
+    let Some(key_image) = key_image else {
+        return Err(Rejection::new(
+            ErrorCode::InvalidSignature,
+            "document is not ring signed for its rate limit epoch",
+        ));
+    };
+
+    // senders only publish in the current epoch, otherwise the acceptance window would let them
+    // spread documents over every epoch it overlaps. Peers may still hold documents of older
+    // epochs, which are counted for as long as documents are retained.
+    let epoch = rate_limit.epoch(document.id.round);
+    let current_epoch = rate_limit.epoch(current_round);
+    let oldest_epoch = match state.max_age_rounds {
+        Some(max_age) => rate_limit.epoch(current_round.saturating_sub(max_age)),
+        None => 0,
+    };
+    let accepted = if replicated {
+        oldest_epoch..=current_epoch
+    } else {
+        current_epoch..=current_epoch
+    };
+    if !accepted.contains(&epoch) {
+        return Err(Rejection::new(
+            ErrorCode::RateLimited,
+            format!(
+                "round {} is not in an accepted rate limit epoch",
+                document.id.round
+            ),
+        ));
+    }
+
+    let chain = chain.clone();
+    let limit = rate_limit.documents_per_epoch;
+    store_document(state, signed_document, || {
+        if state
+            .key_images
+            .lock()
+            .unwrap()
+            .insert(&chain, epoch, oldest_epoch, key_image, limit)
+        {
+            Ok(())
+        } else {
+            Err(Rejection::new(
+                ErrorCode::RateLimited,
+                format!("sender already published {limit} documents in epoch {epoch}"),
+            ))
+        }
+    })
+}
+
+#[inline(never)]
+fn retrieve_digests(
+    state: &SharedState,
+    request: RetrieveDigests,
+    beacon: drand::Beacon,
+) -> Result<Vec<RoundDigest>, Rejection> {
+    verify_request_puzzle(state, &request.beacon, request.nonce_solution, &beacon)?;
+    let state_mut = state.state_mut.read().unwrap();
+    Ok(round_digests(
+        state_mut.published_documents.ids(),
+        request.since_round,
+    ))
+}
+
+#[inline(never)]
+fn retrieve_round_ids(
+    state: &SharedState,
+    request: RetrieveRoundIds,
+    beacon: drand::Beacon,
+) -> Result<Vec<DocumentId>, Rejection> {
+    verify_request_puzzle(state, &request.beacon, request.nonce_solution, &beacon)?;
+    let rounds = request.rounds.into_iter().collect::<HashSet<_>>();
+    let state_mut = state.state_mut.read().unwrap();
+    Ok(state_mut
         .published_documents
-        .insert(request.document.content.id.clone(), request.document);
+        .ids()
+        .into_iter()
+        .filter(|id| rounds.contains(&id.round))
+        .collect())
+}
+
+/// Stores a document received from a peer deaddrop, which is validated and counted as if it was
+/// published directly except that it may be older than the acceptance window. Documents below
+/// the difficulty of the current load are pulled again by a later synchronization.
+fn replicate_document(
+    state: &SharedState,
+    document: SignedDocument,
+    document_chain: drand::ChainInfo,
+    document_beacon: drand::Beacon,
+) -> Result<Publication, Rejection> {
+    verify_document_signature(state, &document)?;
+
+    let current_round = document_beacon.round_number;
+    let difficulty = puzzle_difficulty(state, document.content.puzzle_algorithm)?.saturating_add(
+        difficulty_raise(state, &document.content.drand.chain, current_round),
+    );
+    if !document
+        .content
+        .is_valid(difficulty, 0, &document_chain, &document_beacon)
+    {
+        return Err(Rejection::new(
+            ErrorCode::InvalidDocument,
+            "document failed validation",
+        ));
+    }
 
-    true
+    store_counted(state, document, current_round, true)
 }
 
-fn sign(state: &SharedState, message: Message) -> Signed<Message> {
-    Signed::sign(&state.private_key, message)
+/// Stores a validated document unless it, or another document of the same sender with the same
+/// content in the same topic, is already stored. `admit` is called right before storing a new document and may still reject it.
+fn store_document(
+    state: &SharedState,
+    document: SignedDocument,
+    admit: impl FnOnce() -> Result<(), Rejection>,
+) -> Result<Publication, Rejection> {
+    let id = document.content.id.clone();
+    let mut state_mut = state.state_mut.write().unwrap();
+    let store = &mut state_mut.published_documents;
+
+    let stored = store.get(&id).map_err(|err| {
+        tracing::error!("failed to read document {id:?}: {err}");
+        Rejection::new(ErrorCode::Internal, "failed to read document store")
+    })?;
+    if let Some(stored) = stored {
+        // signatures may differ between publications of the same document
+        if stored.content.serialize_for_signature() != document.content.serialize_for_signature() {
+            return Err(Rejection::new(
+                ErrorCode::Conflict,
+                format!("a different document is stored with id {id:?}"),
+            ));
+        }
+        tracing::debug!("document {id:?} is already stored");
+        return Ok(Publication::AlreadyStored(id));
+    }
+    if let Some(existing) = store.find_content(&document.content.topic, &id) {
+        tracing::debug!("content of document {id:?} is already stored as {existing:?}");
+        return Ok(Publication::AlreadyStored(existing));
+    }
+
+    admit()?;
+    tracing::info!("storing {:#?}", id);
+    if let Err(err) = store.insert(document.clone()) {
+        tracing::error!("failed to store document: {err}");
+        return Err(Rejection::new(
+            ErrorCode::Internal,
+            "failed to store document",
+        ));
+    }
+    // fails only when nobody is subscribed
+    let _ = state.published.send(document);
+
+    Ok(Publication::Stored)
+}
+
+fn sign(state: &SharedState, response: Response) -> Signed<Response> {
+    Signed::sign(&state.private_key, response)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::document::{DocumentDrand, DocumentKind};
+
+    pub(crate) fn create_config(difficulty: u8) -> Config {
+        Config {
+            mode: ModeOfOperation::Open,
+            private_key: crypto::generate().1,
+            address: "127.0.0.1:0".parse().unwrap(),
+            difficulty,
+            difficulty_adjustment: None,
+            puzzle_algorithms: vec![PuzzleRequirement {
+                algorithm: PuzzleAlgorithm::Sha256,
+                difficulty,
+            }],
+            acceptance_window: 10,
+            asset_owner_key: None,
+            asset_owner_update: None,
+            data_dir: None,
+            retention: Default::default(),
+            peers: Vec::new(),
+            peer_keys: Default::default(),
+            sync_interval: Duration::from_secs(60),
+            max_frame_size: crate::DEFAULT_MAX_FRAME_SIZE,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            rate_limit: None,
+            drand_client: Some(
+                drand::BeaconSourceConfig::Local { period_seconds: 1 }
+                    .caching_client(Default::default()),
+            ),
+            prefetch_chains: Vec::new(),
+        }
+    }
+
+    fn create_state(difficulty: u8) -> SharedState {
+        Arc::new(open_state(&create_config(difficulty)).unwrap())
+    }
+
+    fn create_document(key: &PrivateKey, topic: &str, data: &[u8]) -> SignedDocument {
+        let drand = DocumentDrand {
+            chain: "chain".to_string(),
+            beacon: drand::Beacon {
+                round_number: 1,
+                randomness: Default::default(),
+                signature: Default::default(),
+                previous_signature: Default::default(),
+            },
+            scheme: drand::SchemeId::UnchainedOnG1RFC9380,
+        };
+        Signed::sign(key, create_content(key, topic, data, 0, drand))
+    }
+
+    fn create_content(
+        key: &PrivateKey,
+        topic: &str,
+        data: &[u8],
+        difficulty: u8,
+        drand: DocumentDrand,
+    ) -> Document {
+        Document::plaintext(
+            topic,
+            DocumentKind::Inline,
+            data,
+            PuzzleAlgorithm::Sha256,
+            difficulty,
+            crypto::sha256(&key.public_key().to_bytes()),
+            drand,
+        )
+    }
+
+    /// Drand of a document made in the current round of the local chain of `state`.
+    async fn current_drand(state: &SharedState) -> DocumentDrand {
+        let chain = state.drand_client.chain_list().await.unwrap().remove(0);
+        let info = state.drand_client.chain_info(&chain).await.unwrap();
+        let beacon = state
+            .drand_client
+            .chain_latest_randomness(&chain)
+            .await
+            .unwrap();
+        DocumentDrand {
+            chain,
+            beacon,
+            scheme: info.scheme_id,
+        }
+    }
+
+    /// Serves `state` on a local port.
+    async fn serve(state: &SharedState) -> DeaddropAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = DeaddropAddr::Tcp(listener.local_addr().unwrap());
+        let workers = Workers::new(state.clone(), 1);
+        tokio::spawn(accept_connections(
+            state.clone(),
+            workers,
+            listener,
+            DEFAULT_MAX_CONNECTIONS,
+        ));
+        addr
+    }
+
+    async fn connect(state: &SharedState, addr: &DeaddropAddr) -> DeaddropConn {
+        DeaddropConn::connect(addr, Some(&state.public_key), state.max_frame_size)
+            .await
+            .unwrap()
+    }
+
+    /// Request to `state` made in the current round.
+    async fn create_request(state: &SharedState, message: Message) -> Request {
+        let drand = current_drand(state).await;
+        Request {
+            context: RequestContext {
+                nonce: crypto::random_bytes(),
+                deaddrop: state.public_key.clone(),
+                chain: drand.chain,
+                round: drand.beacon.round_number,
+            },
+            message,
+        }
+    }
+
+    async fn error_code(conn: &DeaddropConn, request: Signed<Request>) -> ErrorCode {
+        match exchange(conn.clone(), request, true).await {
+            Ok(Message::Error { code, .. }) => code,
+            other => panic!("request was not rejected: {other:?}"),
+        }
+    }
+
+    async fn publish_request(
+        state: &SharedState,
+        key: &PrivateKey,
+        document: SignedDocument,
+    ) -> Signed<Request> {
+        let message = Message::PublishDocument(PublishDocument { document });
+        Signed::sign(key, create_request(state, message).await)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn invalid_signature_is_answered() {
+        let state = create_state(0);
+        let conn = connect(&state, &serve(&state).await).await;
+        let (_, key) = crypto::generate();
+
+        let mut request = Signed::sign(&key, create_request(&state, Message::RetrieveKeys).await);
+        request.content.context.nonce = crypto::random_bytes();
+        assert_eq!(
+            error_code(&conn, request).await,
+            ErrorCode::InvalidSignature
+        );
+
+        // the connection is still usable
+        let request = Signed::sign(&key, create_request(&state, Message::RetrieveKeys).await);
+        assert_eq!(error_code(&conn, request).await, ErrorCode::KeysUnavailable);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replayed_and_misaddressed_requests_are_rejected() {
+        let state = create_state(0);
+        let conn = connect(&state, &serve(&state).await).await;
+        let (_, key) = crypto::generate();
+
+        let request = Signed::sign(&key, create_request(&state, Message::RetrieveKeys).await);
+        assert_eq!(
+            error_code(&conn, request.clone()).await,
+            ErrorCode::KeysUnavailable
+        );
+        assert_eq!(error_code(&conn, request).await, ErrorCode::ReplayedRequest);
+
+        let mut request = create_request(&state, Message::RetrieveKeys).await;
+        request.context.deaddrop = crypto::generate().0;
+        let request = Signed::sign(&key, request);
+        assert_eq!(error_code(&conn, request).await, ErrorCode::WrongDeaddrop);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn invalid_document_is_answered() {
+        let state = create_state(4);
+        let conn = connect(&state, &serve(&state).await).await;
+        let (_, key) = crypto::generate();
+
+        let drand = current_drand(&state).await;
+        let document = Signed::sign(&key, create_content(&key, "topic", b"data", 0, drand));
+        let request = publish_request(&state, &key, document).await;
+        assert_eq!(error_code(&conn, request).await, ErrorCode::InvalidDocument);
+
+        let drand = current_drand(&state).await;
+        let document = Signed::sign(&key, create_content(&key, "topic", b"data", 4, drand));
+        let request = publish_request(&state, &key, document).await;
+        assert!(matches!(
+            exchange(conn.clone(), request, true).await,
+            Ok(Message::Success)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn conflict_is_answered() {
+        let state = create_state(0);
+        let conn = connect(&state, &serve(&state).await).await;
+        let (_, key) = crypto::generate();
+
+        let drand = current_drand(&state).await;
+        let document = create_content(&key, "topic", b"data", 0, drand);
+        let request = publish_request(&state, &key, Signed::sign(&key, document.clone())).await;
+        assert!(matches!(
+            exchange(conn.clone(), request, true).await,
+            Ok(Message::Success)
+        ));
+
+        // the topic is not part of the id
+        let mut other = document;
+        other.topic = "other-topic".to_string();
+        let request = publish_request(&state, &key, Signed::sign(&key, other)).await;
+        assert_eq!(error_code(&conn, request).await, ErrorCode::Conflict);
+
+        let request = Signed::sign(&key, create_request(&state, Message::RetrieveKeys).await);
+        assert_eq!(error_code(&conn, request).await, ErrorCode::KeysUnavailable);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rate_limited_is_answered() {
+        let mut config = create_config(0);
+        config.mode = ModeOfOperation::SenderRestricted;
+        let rate_limit = RateLimit {
+            documents_per_epoch: 1,
+            epoch_rounds: 1_000_000,
+        };
+        config.rate_limit = Some(rate_limit);
+        let state = Arc::new(open_state(&config).unwrap());
+        let (ring_public_key, ring_private_key) = crypto::ring_generate();
+        let ring = Ring::from(vec![ring_public_key, crypto::ring_generate().0]);
+        state.state_mut.write().unwrap().allowed_sender_ring = ring.clone();
+        let conn = connect(&state, &serve(&state).await).await;
+
+        let (_, key) = crypto::generate();
+        for (data, expected) in [(b"a", None), (b"b", Some(ErrorCode::RateLimited))] {
+            let drand = current_drand(&state).await;
+            let context = rate_limit.context(&drand.chain, drand.beacon.round_number);
+            let document = Signed::ring_sign_linkable(
+                &ring_private_key,
+                &ring,
+                &context,
+                create_content(&key, "topic", data, 0, drand),
+            );
+            let message = Message::PublishDocument(PublishDocument { document });
+            let request = create_request(&state, message).await;
+            let request = Signed::ring_sign(&ring_private_key, &ring, request);
+            match expected {
+                Some(code) => assert_eq!(error_code(&conn, request).await, code),
+                None => assert!(matches!(
+                    exchange(conn.clone(), request, true).await,
+                    Ok(Message::Success)
+                )),
+            }
+        }
+    }
+
+    #[test]
+    fn rate_limit_counts_current_epoch() {
+        let mut config = create_config(0);
+        config.mode = ModeOfOperation::SenderRestricted;
+        let rate_limit = RateLimit {
+            documents_per_epoch: 1,
+            epoch_rounds: 10,
+        };
+        config.rate_limit = Some(rate_limit);
+        let state = Arc::new(open_state(&config).unwrap());
+        let (ring_public_key, ring_private_key) = crypto::ring_generate();
+        let (other_ring_public_key, other_ring_private_key) = crypto::ring_generate();
+        let ring = Ring::from(vec![ring_public_key, other_ring_public_key]);
+        state.state_mut.write().unwrap().allowed_sender_ring = ring.clone();
+
+        let (_, key) = crypto::generate();
+        let ring_signed = |data: &[u8]| {
+            let document = create_document(&key, "topic", data).content;
+            let context = rate_limit.context(&document.drand.chain, document.id.round);
+            Signed::ring_sign_linkable(&ring_private_key, &ring, &context, document)
+        };
+
+        // documents of round 1 are only accepted during the first epoch
+        assert!(std::matches!(
+            store_rate_limited(&state, ring_signed(b"a"), 10, false),
+            Err(Rejection {
+                code: ErrorCode::RateLimited,
+                ..
+            })
+        ));
+        assert!(std::matches!(
+            store_rate_limited(&state, ring_signed(b"a"), 9, false),
+            Ok(Publication::Stored)
+        ));
+        assert!(std::matches!(
+            store_rate_limited(&state, ring_signed(b"b"), 9, false),
+            Err(Rejection {
+                code: ErrorCode::RateLimited,
+                ..
+            })
+        ));
+
+        // replicated documents of past epochs are accepted but share the limit
+        assert!(std::matches!(
+            store_rate_limited(&state, ring_signed(b"b"), 10, true),
+            Err(Rejection {
+                code: ErrorCode::RateLimited,
+                ..
+            })
+        ));
+        let document = create_document(&key, "topic", b"c").content;
+        let context = rate_limit.context(&document.drand.chain, document.id.round);
+        let document =
+            Signed::ring_sign_linkable(&other_ring_private_key, &ring, &context, document);
+        assert!(std::matches!(
+            store_rate_limited(&state, document, 10, true),
+            Ok(Publication::Stored)
+        ));
+    }
+
+    #[test]
+    fn puzzle_difficulty_per_algorithm() {
+        let mut config = create_config(4);
+        config.puzzle_algorithms = vec![
+            PuzzleRequirement {
+                algorithm: PuzzleAlgorithm::Sha256,
+                difficulty: 6,
+            },
+            PuzzleRequirement {
+                algorithm: PuzzleAlgorithm::Argon2id,
+                difficulty: 1,
+            },
+        ];
+        config.difficulty_adjustment = Some(DifficultyAdjustment {
+            min_difficulty: 4,
+            max_difficulty: 8,
+            target_documents_per_round: 1,
+            interval_rounds: 1,
+        });
+        let state = Arc::new(open_state(&config).unwrap());
+        let difficulty = |algorithm, round| {
+            puzzle_difficulty(&state, algorithm).unwrap() + difficulty_raise(&state, "chain", round)
+        };
+        assert_eq!(difficulty(PuzzleAlgorithm::Sha256, 10), 6);
+        assert_eq!(difficulty(PuzzleAlgorithm::Argon2id, 10), 1);
+
+        // the load raises every algorithm by the same number of bits
+        for _ in 0..2 {
+            state
+                .document_difficulty
+                .lock()
+                .unwrap()
+                .record("chain", 10);
+        }
+        assert_eq!(difficulty(PuzzleAlgorithm::Sha256, 11), 7);
+        assert_eq!(difficulty(PuzzleAlgorithm::Argon2id, 11), 2);
+
+        config.puzzle_algorithms.truncate(1);
+        let state = Arc::new(open_state(&config).unwrap());
+        assert!(puzzle_difficulty(&state, PuzzleAlgorithm::Argon2id).is_err());
+    }
+
+    fn document_id(round: u64, data: &[u8]) -> DocumentId {
+        DocumentId {
+            round,
+            content_hash: crypto::sha256(data),
+            sender_tag: crypto::sha256(b"key"),
+        }
+    }
+
+    #[test]
+    fn round_digests_compare_rounds() {
+        let ids = vec![
+            document_id(1, b"a"),
+            document_id(2, b"b"),
+            document_id(2, b"c"),
+            document_id(3, b"d"),
+        ];
+        let digests = round_digests(ids.clone(), 0);
+        assert_eq!(
+            digests
+                .iter()
+                .map(|d| (d.round, d.count))
+                .collect::<Vec<_>>(),
+            vec![(1, 1), (2, 2), (3, 1)]
+        );
+
+        // the digest does not depend on the order of the ids
+        let mut reversed = ids.clone();
+        reversed.reverse();
+        assert_eq!(round_digests(reversed, 0), digests);
+
+        // older rounds are left out
+        assert_eq!(round_digests(ids.clone(), 2), digests[1..]);
+
+        // only the rounds whose documents differ have different digests
+        let mut other = ids;
+        other[2] = document_id(2, b"e");
+        let other_digests = round_digests(other, 0);
+        assert_eq!(other_digests[0], digests[0]);
+        assert_eq!(other_digests[1].count, digests[1].count);
+        assert_ne!(other_digests[1], digests[1]);
+        assert_eq!(other_digests[2], digests[2]);
+    }
+
+    /// Stores `document` in `state` without validating it.
+    fn insert(state: &SharedState, document: SignedDocument) {
+        assert!(matches!(
+            store_document(state, document, || Ok(())),
+            Ok(Publication::Stored)
+        ));
+    }
+
+    /// Serves a deaddrop that stores `documents` without validating them.
+    pub(crate) async fn serve_documents(
+        config: Config,
+        documents: Vec<SignedDocument>,
+    ) -> (DeaddropAddr, PublicKey) {
+        let state = Arc::new(open_state(&config).unwrap());
+        for document in documents {
+            insert(&state, document);
+        }
+        (serve(&state).await, state.public_key.clone())
+    }
+
+    fn contains(state: &SharedState, id: &DocumentId) -> bool {
+        let state_mut = state.state_mut.read().unwrap();
+        state_mut.published_documents.contains(id)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sync_pulls_missing_documents() {
+        let peer = create_state(0);
+        let peer_conn = connect(&peer, &serve(&peer).await).await;
+        let state = create_state(0);
+        let workers = Workers::new(state.clone(), 1);
+        let (_, key) = crypto::generate();
+
+        let drand = current_drand(&peer).await;
+        let documents = [b"a", b"b", b"c"]
+            .map(|data| Signed::sign(&key, create_content(&key, "topic", data, 0, drand.clone())));
+        for document in documents.iter() {
+            insert(&peer, document.clone());
+        }
+        insert(&state, documents[0].clone());
+
+        let replicated = synchronize_peer(&state, &workers, &peer_conn, None)
+            .await
+            .unwrap();
+        assert_eq!(replicated, 2);
+        for document in documents.iter() {
+            assert!(contains(&state, &document.content.id));
+        }
+
+        // nothing is left to pull
+        let replicated = synchronize_peer(&state, &workers, &peer_conn, None)
+            .await
+            .unwrap();
+        assert_eq!(replicated, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sync_rejects_invalid_documents() {
+        let peer = create_state(0);
+        let peer_conn = connect(&peer, &serve(&peer).await).await;
+        let state = create_state(4);
+        let workers = Workers::new(state.clone(), 1);
+        let (_, key) = crypto::generate();
+
+        let drand = current_drand(&peer).await;
+        let valid = Signed::sign(&key, create_content(&key, "topic", b"a", 4, drand.clone()));
+        let mut tampered =
+            Signed::sign(&key, create_content(&key, "topic", b"b", 4, drand.clone()));
+        tampered.content.topic = "other-topic".to_string();
+        // accepted by the peer, but below the difficulty of this deaddrop
+        let easy = Signed::sign(&key, create_content(&key, "topic", b"c", 0, drand));
+        for document in [&valid, &tampered, &easy] {
+            insert(&peer, document.clone());
+        }
+
+        let replicated = synchronize_peer(&state, &workers, &peer_conn, None)
+            .await
+            .unwrap();
+        assert_eq!(replicated, 1);
+        assert!(contains(&state, &valid.content.id));
+        assert!(!contains(&state, &tampered.content.id));
+        assert!(!contains(&state, &easy.content.id));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sync_counts_towards_rate_limit() {
+        let rate_limit = RateLimit {
+            documents_per_epoch: 1,
+            epoch_rounds: 1_000_000,
+        };
+        let (ring_public_key, ring_private_key) = crypto::ring_generate();
+        let ring = Ring::from(vec![ring_public_key, crypto::ring_generate().0]);
+        let restricted_state = |rate_limit| {
+            let mut config = create_config(0);
+            config.mode = ModeOfOperation::SenderRestricted;
+            config.rate_limit = rate_limit;
+            let state = Arc::new(open_state(&config).unwrap());
+            state.state_mut.write().unwrap().allowed_sender_ring = ring.clone();
+            state
+        };
+        // a peer that does not limit the sender
+        let peer = restricted_state(None);
+        let peer_conn = connect(&peer, &serve(&peer).await).await;
+        let state = restricted_state(Some(rate_limit));
+        let workers = Workers::new(state.clone(), 1);
+
+        let (_, key) = crypto::generate();
+        let drand = current_drand(&peer).await;
+        let context = rate_limit.context(&drand.chain, drand.beacon.round_number);
+        let documents = [b"a", b"b"].map(|data| {
+            let document = create_content(&key, "topic", data, 0, drand.clone());
+            Signed::ring_sign_linkable(&ring_private_key, &ring, &context, document)
+        });
+        for document in documents.iter() {
+            insert(&peer, document.clone());
+        }
+
+        let replicated = synchronize_peer(&state, &workers, &peer_conn, None)
+            .await
+            .unwrap();
+        assert_eq!(replicated, 1);
+
+        // the sender cannot publish directly either once the limit is used up by replication
+        let document = create_content(&key, "topic", b"c", 0, drand);
+        let document = Signed::ring_sign_linkable(&ring_private_key, &ring, &context, document);
+        assert!(matches!(
+            store_rate_limited(
+                &state,
+                document,
+                current_drand(&state).await.beacon.round_number,
+                false
+            ),
+            Err(Rejection {
+                code: ErrorCode::RateLimited,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn duplicate_content_is_scoped_to_topic() {
+        let state = create_state(0);
+        let (_, key) = crypto::generate();
+
+        let document = create_document(&key, "topic", b"data");
+        let id = document.content.id.clone();
+        assert!(matches!(
+            store_document(&state, document.clone(), || Ok(())),
+            Ok(Publication::Stored)
+        ));
+        match store_document(&state, document, || Ok(())) {
+            Ok(Publication::AlreadyStored(existing)) => assert_eq!(existing, id),
+            _ => panic!("duplicate publication was not detected"),
+        }
+
+        // the same bytes under another topic are a different document
+        let (_, other_key) = crypto::generate();
+        let other = create_document(&other_key, "other-topic", b"data");
+        assert_eq!(other.content.id.content_hash, id.content_hash);
+        assert!(matches!(
+            store_document(&state, other, || Ok(())),
+            Ok(Publication::Stored)
+        ));
+
+        let state_mut = state.state_mut.read().unwrap();
+        let store = &state_mut.published_documents;
+        assert_eq!(store.topic_ids("topic", 0).len(), 1);
+        assert_eq!(store.topic_ids("other-topic", 0).len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn copied_content_does_not_claim_publication() {
+        let state = create_state(0);
+        let conn = connect(&state, &serve(&state).await).await;
+        let (_, sender) = crypto::generate();
+        let (_, squatter) = crypto::generate();
+
+        // documents must be signed by the key of their sender tag
+        let drand = current_drand(&state).await;
+        let forged = Signed::sign(
+            &squatter,
+            create_content(&sender, "topic", b"data", 0, drand.clone()),
+        );
+        let request = publish_request(&state, &squatter, forged).await;
+        assert_eq!(
+            error_code(&conn, request).await,
+            ErrorCode::InvalidSignature
+        );
+
+        let mut tampered = Signed::sign(
+            &sender,
+            create_content(&sender, "topic", b"data", 0, drand.clone()),
+        );
+        tampered.content.topic = "other-topic".to_string();
+        let request = publish_request(&state, &squatter, tampered).await;
+        assert_eq!(
+            error_code(&conn, request).await,
+            ErrorCode::InvalidSignature
+        );
+
+        let copy = Signed::sign(
+            &squatter,
+            create_content(&squatter, "topic", b"data", 0, drand.clone()),
+        );
+        let request = publish_request(&state, &squatter, copy).await;
+        assert!(matches!(
+            exchange(conn.clone(), request, true).await,
+            Ok(Message::Success)
+        ));
+
+        let document = Signed::sign(&sender, create_content(&sender, "topic", b"data", 0, drand));
+        let id = document.content.id.clone();
+        let request = publish_request(&state, &sender, document).await;
+        assert!(matches!(
+            exchange(conn.clone(), request, true).await,
+            Ok(Message::Success)
+        ));
+
+        // the same content of the same sender is still deduplicated across rounds
+        let mut drand = current_drand(&state).await;
+        let round = id.round - 1;
+        drand.beacon = state
+            .drand_client
+            .chain_randomness(&drand.chain, round)
+            .await
+            .unwrap();
+        let document = Signed::sign(&sender, create_content(&sender, "topic", b"data", 0, drand));
+        assert_ne!(document.content.id, id);
+        let request = publish_request(&state, &sender, document).await;
+        match exchange(conn.clone(), request, true).await {
+            Ok(Message::AlreadyStored(existing)) => assert_eq!(existing, id),
+            other => panic!("duplicate publication was not detected: {other:?}"),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn subscription_requires_puzzle() {
+        let state = create_state(8);
+        let workers = Workers::new(state.clone(), 1);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = DeaddropAddr::Tcp(listener.local_addr().unwrap());
+        let connections = Arc::new(Semaphore::new(1));
+        let handler = tokio::spawn({
+            let state = state.clone();
+            let permit = connections.clone().acquire_owned().await.unwrap();
+            async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                handle_connection(state, workers, stream, permit).await
+            }
+        });
+
+        let conn = DeaddropConn::connect(&addr, Some(&state.public_key), state.max_frame_size)
+            .await
+            .unwrap();
+        let chain = state.drand_client.chain_list().await.unwrap().remove(0);
+        let beacon = state
+            .drand_client
+            .chain_latest_randomness(&chain)
+            .await
+            .unwrap();
+        let (_, key) = crypto::generate();
+        let subscribe = |nonce_solution| {
+            let request = Request {
+                context: RequestContext {
+                    nonce: crypto::random_bytes(),
+                    deaddrop: state.public_key.clone(),
+                    chain: chain.clone(),
+                    round: beacon.round_number,
+                },
+                message: Message::Subscribe(Subscribe {
+                    topic: "topic".to_string(),
+                    since_round: 0,
+                    beacon: beacon.clone(),
+                    chain: chain.clone(),
+                    nonce_solution,
+                }),
+            };
+            Signed::sign(&key, request)
+        };
+
+        let invalid = (0..)
+            .find(|n| !puzzle_verify(PuzzleAlgorithm::Sha256, &[], &beacon, 8, *n))
+            .unwrap();
+        match exchange(conn.clone(), subscribe(invalid), true).await {
+            Ok(Message::Error {
+                code: ErrorCode::InvalidPuzzle,
+                ..
+            }) => {}
+            other => panic!("unexpected subscription response {other:?}"),
+        }
+        assert_eq!(state.published.receiver_count(), 0);
+
+        let valid = crypto_puzzle_solve(&[], &beacon, 8);
+        match exchange(conn.clone(), subscribe(valid), true).await {
+            Ok(Message::DocumentList(list)) => assert!(list.documents.is_empty()),
+            other => panic!("unexpected subscription response {other:?}"),
+        }
+        assert_eq!(state.published.receiver_count(), 1);
+        // subscribed connections count against the connection limit
+        assert_eq!(connections.available_permits(), 0);
+
+        // the subscription ends with the connection even if nothing is published
+        drop(conn);
+        handler.await.unwrap().unwrap();
+        assert_eq!(state.published.receiver_count(), 0);
+        assert_eq!(connections.available_permits(), 1);
+    }
 }
@@ -1,10 +1,26 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use serde::{de::DeserializeOwned, Serialize};
-use tokio::{io::BufStream, net::TcpStream, sync::Mutex, time::Instant};
+use tokio::{
+    io::{AsyncWriteExt, BufStream},
+    net::TcpStream,
+    sync::{Mutex, MutexGuard},
+    time::Instant,
+};
 use tor_stream::TorStream;
 
-use crate::rle;
+use crypto::PublicKey;
+
+use crate::{
+    protocol::{ClientHello, DeaddropHello, Signed, PROTOCOL_VERSION},
+    rle,
+};
 
 #[derive(Debug)]
 pub struct InvalidDeaddropAddr;
@@ -17,38 +33,123 @@ impl std::fmt::Display for InvalidDeaddropAddr {
 
 impl std::error::Error for InvalidDeaddropAddr {}
 
+fn invalid_handshake(reason: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason.into())
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum DeaddropAddr {
     Tor { onion: String, proxy: SocketAddr },
     Tcp(SocketAddr),
 }
 
-#[derive(Debug, Clone)]
-struct Inner(Arc<Mutex<BufStream<TcpStream>>>);
+#[derive(Debug)]
+struct Inner {
+    stream: Mutex<BufStream<TcpStream>>,
+    hello: Signed<DeaddropHello>,
+    public_key: PublicKey,
+    /// Largest frame read from the deaddrop.
+    max_frame_size: u32,
+    /// Set once a read or write failed, which may leave the stream in the middle of a frame.
+    broken: AtomicBool,
+}
 
 #[derive(Debug, Clone)]
-pub struct DeaddropConn(Inner);
+pub struct DeaddropConn(Arc<Inner>);
 
 impl DeaddropConn {
-    pub fn new(stream: TcpStream) -> Self {
-        Self(Inner(Arc::new(Mutex::new(BufStream::new(stream)))))
+    /// Performs the protocol handshake on a freshly connected stream. The deaddrop must sign its
+    /// hello with `key` if set, and otherwise with the key it presents. Frames larger than
+    /// `max_frame_size` sent by the deaddrop are rejected.
+    pub async fn handshake(
+        stream: TcpStream,
+        key: Option<&PublicKey>,
+        max_frame_size: u32,
+    ) -> std::io::Result<Self> {
+        let mut stream = BufStream::new(stream);
+        let nonce = crypto::random_bytes();
+        let hello = ClientHello {
+            version: PROTOCOL_VERSION,
+            nonce,
+        };
+        rle::async_serialize_and_write(&mut stream, &hello).await?;
+        let data = rle::async_read(&mut stream, max_frame_size).await?;
+        let version = rle::deserialize::<u32>(&data)?;
+        if version != PROTOCOL_VERSION {
+            return Err(invalid_handshake(format!(
+                "deaddrop uses protocol version {version}, expected {PROTOCOL_VERSION}"
+            )));
+        }
+        let hello = rle::deserialize::<Signed<DeaddropHello>>(&data)?;
+        let public_key = match key {
+            Some(key) => key.clone(),
+            None => hello.public_key().cloned().ok_or_else(|| {
+                invalid_handshake("deaddrop handshake is not signed with the deaddrop key")
+            })?,
+        };
+        if !hello.verify_with(&public_key) {
+            return Err(invalid_handshake(
+                "deaddrop handshake is not signed with the expected key",
+            ));
+        }
+        if hello.content.nonce != nonce {
+            return Err(invalid_handshake("deaddrop handshake was replayed"));
+        }
+        Ok(Self(Arc::new(Inner {
+            stream: Mutex::new(stream),
+            hello,
+            public_key,
+            max_frame_size,
+            broken: AtomicBool::new(false),
+        })))
     }
 
-    pub async fn connect(addr: &DeaddropAddr) -> std::io::Result<Self> {
+    /// The key identifying the deaddrop, which signed its handshake.
+    pub fn public_key(&self) -> &PublicKey {
+        &self.0.public_key
+    }
+
+    /// The handshake reply of the deaddrop.
+    pub fn hello(&self) -> &Signed<DeaddropHello> {
+        &self.0.hello
+    }
+
+    /// Connects to the deaddrop at `addr`, which must identify with `key` if set, see
+    /// [`DeaddropConn::handshake`].
+    pub async fn connect(
+        addr: &DeaddropAddr,
+        key: Option<&PublicKey>,
+        max_frame_size: u32,
+    ) -> std::io::Result<Self> {
         match addr {
-            DeaddropAddr::Tor { onion, proxy } => Self::connect_tor(onion, *proxy).await,
-            DeaddropAddr::Tcp(addr) => Self::connect_tcp(*addr).await,
+            DeaddropAddr::Tor { onion, proxy } => {
+                Self::connect_tor(onion, *proxy, key, max_frame_size).await
+            }
+            DeaddropAddr::Tcp(addr) => Self::connect_tcp(*addr, key, max_frame_size).await,
         }
     }
 
-    pub async fn connect_tcp(addr: SocketAddr) -> std::io::Result<Self> {
+    pub async fn connect_tcp(
+        addr: SocketAddr,
+        key: Option<&PublicKey>,
+        max_frame_size: u32,
+    ) -> std::io::Result<Self> {
         let stream = TcpStream::connect(addr).await?;
-        Ok(Self::new(stream))
+        Self::handshake(stream, key, max_frame_size).await
     }
 
-    pub async fn connect_tor(onion: &str, proxy: SocketAddr) -> std::io::Result<Self> {
+    pub async fn connect_tor(
+        onion: &str,
+        proxy: SocketAddr,
+        key: Option<&PublicKey>,
+        max_frame_size: u32,
+    ) -> std::io::Result<Self> {
         let (onion_addr, onion_port) = match onion.split_once(":") {
-            Some((addr, port)) => (addr, port.parse::<u16>().unwrap()),
+            Some((addr, port)) => (
+                addr,
+                port.parse::<u16>()
+                    .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?,
+            ),
             None => (onion, 80),
         };
 
@@ -60,41 +161,161 @@ impl DeaddropConn {
             )
         })
         .await
-        .unwrap()?;
+        .map_err(std::io::Error::other)??;
         let tcp_stream = tor_stream.into_inner();
         tcp_stream.set_nonblocking(true)?;
-        Ok(Self::new(TcpStream::from_std(tcp_stream)?))
+        Self::handshake(TcpStream::from_std(tcp_stream)?, key, max_frame_size).await
     }
 
-    pub async fn send<T>(&self, message: &T)
+    pub async fn send<T>(&self, message: &T) -> std::io::Result<()>
     where
         T: Serialize,
     {
-        let mut stream = self.0 .0.lock().await;
-        rle::async_serialize_and_write(&mut *stream, message)
-            .await
-            .unwrap()
+        let data = self.serialize(message)?;
+        let mut stream = self.lock().await?;
+        let result = rle::async_write(&mut *stream, &data).await;
+        self.check(&mut stream, result).await
     }
 
-    pub async fn read<R>(&self) -> R
+    pub async fn read<R>(&self) -> std::io::Result<R>
     where
         R: DeserializeOwned,
     {
-        let mut stream = self.0 .0.lock().await;
-        rle::async_deserialize_and_read(&mut *stream).await.unwrap()
+        let mut stream = self.lock().await?;
+        let result = rle::async_deserialize_and_read(&mut *stream, self.0.max_frame_size).await;
+        self.check(&mut stream, result).await
     }
 
     #[tracing::instrument(skip_all)]
-    pub async fn send_and_read<R, T>(&self, message: &T) -> R
+    pub async fn send_and_read<R, T>(&self, message: &T) -> std::io::Result<R>
     where
         R: DeserializeOwned,
         T: Serialize,
     {
-        let mut stream = self.0 .0.lock().await;
-        rle::async_serialize_and_write(&mut *stream, message)
+        let data = self.serialize(message)?;
+        let mut stream = self.lock().await?;
+        let result = match rle::async_write(&mut *stream, &data).await {
+            Ok(()) => rle::async_deserialize_and_read(&mut *stream, self.0.max_frame_size).await,
+            Err(err) => Err(err),
+        };
+        self.check(&mut stream, result).await
+    }
+
+    /// Locks the stream, failing if an earlier failure left it unusable.
+    async fn lock(&self) -> std::io::Result<MutexGuard<'_, BufStream<TcpStream>>> {
+        let stream = self.0.stream.lock().await;
+        if self.0.broken.load(Ordering::Relaxed) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "connection to the deaddrop was closed after an earlier failure",
+            ));
+        }
+        Ok(stream)
+    }
+
+    /// Closes the connection if `result` is a failure, since the frames that follow can no
+    /// longer be told apart.
+    async fn check<T>(
+        &self,
+        stream: &mut BufStream<TcpStream>,
+        result: std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        if result.is_err() {
+            self.0.broken.store(true, Ordering::Relaxed);
+            let _ = stream.shutdown().await;
+        }
+        result
+    }
+
+    /// Serializes a frame, failing if the deaddrop would reject it for its size.
+    fn serialize<T: Serialize>(&self, message: &T) -> std::io::Result<Vec<u8>> {
+        let data = bincode::serialize(message).unwrap();
+        let max_size = self.0.hello.content.max_frame_size;
+        if data.len() as u64 > u64::from(max_size) {
+            return Err(rle::frame_too_large(data.len() as u64, max_size));
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::ModeOfOperation;
+
+    /// Answers one handshake with a hello signed by `key`, echoing the client nonce unless
+    /// `nonce` is set, then writes `frames`.
+    async fn serve_hello(
+        key: crypto::PrivateKey,
+        nonce: Option<[u8; 16]>,
+        frames: Vec<Vec<u8>>,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufStream::new(stream);
+            let client_hello: ClientHello = rle::async_deserialize_and_read(&mut stream, 1024)
+                .await
+                .unwrap();
+            let hello = DeaddropHello {
+                version: PROTOCOL_VERSION,
+                mode: ModeOfOperation::Open,
+                difficulty: 0,
+                puzzle_algorithms: Vec::new(),
+                acceptance_window: 0,
+                features: Vec::new(),
+                max_frame_size: 1024,
+                rate_limit: None,
+                nonce: nonce.unwrap_or(client_hello.nonce),
+            };
+            rle::async_serialize_and_write(&mut stream, &Signed::sign(&key, hello))
+                .await
+                .unwrap();
+            for frame in frames {
+                rle::async_write(&mut stream, &frame).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn handshake_is_authenticated() {
+        let (public_key, private_key) = crypto::generate();
+        let (other_key, _) = crypto::generate();
+
+        let addr = serve_hello(private_key.clone(), None, Vec::new()).await;
+        let conn = DeaddropConn::connect_tcp(addr, Some(&public_key), 1024)
+            .await
+            .unwrap();
+        assert_eq!(conn.public_key(), &public_key);
+
+        let addr = serve_hello(private_key.clone(), None, Vec::new()).await;
+        assert!(DeaddropConn::connect_tcp(addr, Some(&other_key), 1024)
+            .await
+            .is_err());
+
+        let addr = serve_hello(private_key, Some([0; 16]), Vec::new()).await;
+        assert!(DeaddropConn::connect_tcp(addr, Some(&public_key), 1024)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn oversize_frame_closes_connection() {
+        let (public_key, private_key) = crypto::generate();
+        let frames = vec![vec![0; 2048], bincode::serialize(&7u32).unwrap()];
+        let addr = serve_hello(private_key, None, frames).await;
+        let conn = DeaddropConn::connect_tcp(addr, Some(&public_key), 1024)
             .await
             .unwrap();
-        let r = rle::async_deserialize_and_read(&mut *stream).await.unwrap();
-        r
+
+        let err = conn.read::<u32>().await.unwrap_err();
+        assert!(rle::FrameTooLarge::from_io(&err).is_some());
+        // the frame after it is not mistaken for a response
+        let err = conn.read::<u32>().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
     }
 }
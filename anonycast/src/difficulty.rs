@@ -0,0 +1,148 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Adjusts the puzzle difficulty required from published documents to the publication load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DifficultyAdjustment {
+    pub min_difficulty: u8,
+    pub max_difficulty: u8,
+    /// Documents stored per round above which the difficulty is raised. It is lowered once fewer
+    /// than half as many are stored.
+    pub target_documents_per_round: u32,
+    /// Rounds between two adjustments.
+    pub interval_rounds: u64,
+}
+
+#[derive(Debug)]
+struct ChainDifficulty {
+    /// First round of the current adjustment interval.
+    interval_start: u64,
+    /// Difficulty required during the current interval.
+    difficulty: u8,
+    /// Documents stored per round of the current interval.
+    stored: BTreeMap<u64, u32>,
+}
+
+impl ChainDifficulty {
+    /// Applies the adjustments of every interval that ended by `current_round`.
+    fn adjust(&mut self, adjustment: &DifficultyAdjustment, current_round: u64) {
+        let interval = adjustment.interval_rounds.max(1);
+        let target = u64::from(adjustment.target_documents_per_round) * interval;
+        while self.interval_start + interval <= current_round {
+            let end = self.interval_start + interval;
+            let stored = self
+                .stored
+                .range(..end)
+                .map(|(_, n)| u64::from(*n))
+                .sum::<u64>();
+            let current = self.difficulty;
+            let next = if stored > target {
+                current.saturating_add(1).min(adjustment.max_difficulty)
+            } else if stored * 2 < target {
+                current.saturating_sub(1).max(adjustment.min_difficulty)
+            } else {
+                current
+            };
+            if next != current {
+                tracing::info!("document puzzle difficulty is {next} from round {end}");
+                self.difficulty = next;
+            }
+            self.stored = self.stored.split_off(&end);
+            self.interval_start = end;
+        }
+    }
+}
+
+/// Puzzle difficulty required from documents, per chain.
+#[derive(Debug)]
+pub(crate) struct DifficultyController {
+    initial: u8,
+    adjustment: Option<DifficultyAdjustment>,
+    chains: HashMap<String, ChainDifficulty>,
+}
+
+impl DifficultyController {
+    pub fn new(initial: u8, adjustment: Option<DifficultyAdjustment>) -> Self {
+        let initial = match adjustment {
+            Some(adjustment) => initial.clamp(adjustment.min_difficulty, adjustment.max_difficulty),
+            None => initial,
+        };
+        Self {
+            initial,
+            adjustment,
+            chains: Default::default(),
+        }
+    }
+
+    fn chain(&mut self, chain: &str, current_round: u64) -> &mut ChainDifficulty {
+        let initial = self.initial;
+        let difficulty = self
+            .chains
+            .entry(chain.to_string())
+            .or_insert_with(|| ChainDifficulty {
+                interval_start: current_round,
+                difficulty: initial,
+                stored: Default::default(),
+            });
+        if let Some(adjustment) = &self.adjustment {
+            difficulty.adjust(adjustment, current_round);
+        }
+        difficulty
+    }
+
+    /// Difficulty required from documents published in `current_round`, whatever round they
+    /// claim, once the adjustments up to it are applied.
+    pub fn difficulty(&mut self, chain: &str, current_round: u64) -> u8 {
+        if self.adjustment.is_none() {
+            return self.initial;
+        }
+        self.chain(chain, current_round).difficulty
+    }
+
+    /// Counts a document stored in `current_round` towards the load of its interval.
+    pub fn record(&mut self, chain: &str, current_round: u64) {
+        if self.adjustment.is_none() {
+            return;
+        }
+        *self
+            .chain(chain, current_round)
+            .stored
+            .entry(current_round)
+            .or_default() += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn difficulty_controller() {
+        let adjustment = DifficultyAdjustment {
+            min_difficulty: 1,
+            max_difficulty: 3,
+            target_documents_per_round: 2,
+            interval_rounds: 2,
+        };
+        let mut controller = DifficultyController::new(1, Some(adjustment));
+        assert_eq!(controller.difficulty("chain", 10), 1);
+
+        // 5 documents over rounds 10 and 11 exceed the target of 4
+        for round in [10, 10, 11, 11, 11] {
+            controller.record("chain", round);
+        }
+        assert_eq!(controller.difficulty("chain", 12), 2);
+        assert_eq!(controller.difficulty("other", 12), 1);
+
+        // rounds 12 and 13 meet the target, then no documents are stored
+        for round in [12, 13, 13] {
+            controller.record("chain", round);
+        }
+        assert_eq!(controller.difficulty("chain", 14), 2);
+        assert_eq!(controller.difficulty("chain", 16), 1);
+        assert_eq!(controller.difficulty("chain", 100), 1);
+
+        let mut fixed = DifficultyController::new(4, None);
+        fixed.record("chain", 10);
+        assert_eq!(fixed.difficulty("chain", 10), 4);
+    }
+}
@@ -1,7 +1,7 @@
-use crypto::{PrivateKey, PublicKey, Sha256, SymmetricData, SymmetricKey};
+use crypto::{Algorithm, PrivateKey, PublicKey, Sha256, SymmetricData, SymmetricKey};
 use serde::{Deserialize, Serialize};
 
-use crate::{crypto_puzzle_solve, crypto_puzzle_verify, protocol::Signed};
+use crate::{protocol::Signed, puzzle_solve, puzzle_verify, PuzzleAlgorithm};
 
 pub type SignedDocument = Signed<Document>;
 
@@ -9,22 +9,51 @@ pub type SignedDocument = Signed<Document>;
 pub struct DocumentId {
     pub round: u64,
     pub content_hash: Sha256,
-    pub public_key_hash: Sha256,
+    /// Hash of the sender's public key when the sender is known from the document signature. In
+    /// the sender restricted modes it is a random value chosen for every document, since a
+    /// stable value would link all documents of a sender.
+    pub sender_tag: Sha256,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DocumentKeyPair {
-    pub public_key: PublicKey,
-    // symmetric key encrypted with public key
+/// Symmetric key of an encrypted document wrapped for one receiver. It does not identify the
+/// receiver, who finds it by trying to decrypt every wrapped key of the document.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct DocumentKey {
     pub symmetric_key: Vec<u8>,
 }
 
+/// Error returned when the content cannot be encrypted to one of the receivers.
+#[derive(Debug)]
+pub enum EncryptionError {
+    /// The receiver key is not of the algorithm the document is encrypted with.
+    AlgorithmMismatch {
+        key: Algorithm,
+        document: Algorithm,
+    },
+    InvalidKey(crypto::InvalidPublicKey),
+}
+
+impl std::fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncryptionError::AlgorithmMismatch { key, document } => {
+                write!(f, "{key} receiver key cannot receive {document} documents")
+            }
+            EncryptionError::InvalidKey(err) => write!(f, "receiver key is unusable: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for EncryptionError {}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DocumentContent {
     Plaintext(Vec<u8>),
     Encrypted {
+        /// Algorithm the symmetric key is wrapped with for every receiver.
+        algorithm: Algorithm,
         data: SymmetricData,
-        keys: Vec<DocumentKeyPair>,
+        keys: Vec<DocumentKey>,
     },
 }
 
@@ -37,6 +66,15 @@ impl DocumentContent {
     }
 }
 
+/// How the content of a document is interpreted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DocumentKind {
+    #[default]
+    Inline,
+    /// The content is a [`crate::chunk::Manifest`] listing the chunks of a larger content.
+    Manifest,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DocumentDrand {
     pub chain: String,
@@ -48,8 +86,12 @@ pub struct DocumentDrand {
 pub struct Document {
     pub id: DocumentId,
     pub topic: String,
+    /// Kind of the content. Encrypted documents carry their kind in the ciphertext so deaddrops
+    /// cannot tell manifests apart, and have the default kind until decrypted.
+    pub kind: DocumentKind,
     pub content: DocumentContent,
     pub crypto_difficulty: u8,
+    pub puzzle_algorithm: PuzzleAlgorithm,
     pub nonce_solution: u32,
     pub drand: DocumentDrand,
 }
@@ -58,25 +100,29 @@ impl_signable_serde!(Document);
 impl Document {
     fn new(
         topic: &str,
+        kind: DocumentKind,
         content: DocumentContent,
+        puzzle_algorithm: PuzzleAlgorithm,
         difficulty: u8,
-        public_key_hash: Sha256,
+        sender_tag: Sha256,
         drand: DocumentDrand,
     ) -> Self {
         let topic = topic.to_owned();
         let content_hash = crypto::sha256(content.data());
         let round = drand.beacon.round_number;
-        let solution = crypto_puzzle_solve(content.data(), &drand.beacon, difficulty);
+        let solution = puzzle_solve(puzzle_algorithm, content.data(), &drand.beacon, difficulty);
 
         Document {
             id: DocumentId {
                 round,
                 content_hash,
-                public_key_hash,
+                sender_tag,
             },
             topic,
+            kind,
             content,
             crypto_difficulty: difficulty,
+            puzzle_algorithm,
             nonce_solution: solution,
             drand,
         }
@@ -84,67 +130,104 @@ impl Document {
 
     pub fn plaintext(
         topic: &str,
+        kind: DocumentKind,
         data: &[u8],
+        puzzle_algorithm: PuzzleAlgorithm,
         difficulty: u8,
-        public_key_hash: Sha256,
+        sender_tag: Sha256,
         drand: DocumentDrand,
     ) -> Self {
         Self::new(
             topic,
+            kind,
             DocumentContent::Plaintext(data.to_vec()),
+            puzzle_algorithm,
             difficulty,
-            public_key_hash,
+            sender_tag,
             drand,
         )
     }
 
+    /// Encrypts the content to every receiver, failing if any receiver key cannot be used with
+    /// `algorithm` rather than leaving that receiver out.
+    #[allow(clippy::too_many_arguments)]
     pub fn encrypted(
         topic: &str,
+        kind: DocumentKind,
         data: &[u8],
+        puzzle_algorithm: PuzzleAlgorithm,
         difficulty: u8,
-        public_key_hash: Sha256,
+        sender_tag: Sha256,
+        algorithm: Algorithm,
         receiver_keys: &[PublicKey],
         drand: DocumentDrand,
-    ) -> Self {
+    ) -> Result<Self, EncryptionError> {
         let skey = crypto::symmetric_generate();
-        let mut pairs = Vec::new();
+        let mut keys = Vec::new();
         for key in receiver_keys {
-            let encrypted_skey = crypto::encrypt(key, skey.as_bytes());
-            let pair = DocumentKeyPair {
-                public_key: key.clone(),
+            if key.algorithm() != algorithm {
+                return Err(EncryptionError::AlgorithmMismatch {
+                    key: key.algorithm(),
+                    document: algorithm,
+                });
+            }
+            let encrypted_skey =
+                crypto::encrypt(key, skey.as_bytes()).map_err(EncryptionError::InvalidKey)?;
+            keys.push(DocumentKey {
                 symmetric_key: encrypted_skey,
-            };
-            pairs.push(pair);
+            });
         }
-        let encrypted_data = crypto::symmetric_encrypt(&skey, data);
+        // wrapped keys are randomized, so sorting them hides the order of the receivers
+        keys.sort();
+        let plaintext = bincode::serialize(&(kind, data)).unwrap();
+        let encrypted_data = crypto::symmetric_encrypt(&skey, &plaintext);
         let content = DocumentContent::Encrypted {
+            algorithm,
             data: encrypted_data,
-            keys: pairs,
+            keys,
         };
-        Self::new(topic, content, difficulty, public_key_hash, drand)
+        Ok(Self::new(
+            topic,
+            DocumentKind::default(),
+            content,
+            puzzle_algorithm,
+            difficulty,
+            sender_tag,
+            drand,
+        ))
     }
 
+    /// Decrypts the content and its kind if one of its wrapped keys was made for `key`, which is
+    /// found by trial decryption.
     pub fn decrypt(&mut self, key: &PrivateKey) -> bool {
-        let public_key = key.public_key();
-        let (data, skey) = match &self.content {
+        let (algorithm, data, keys) = match &self.content {
             DocumentContent::Plaintext(_) => return true,
-            DocumentContent::Encrypted { data, keys } => {
-                let pair = match keys.iter().find(|p| p.public_key == public_key) {
-                    Some(pair) => pair,
-                    None => return false,
-                };
-                (
-                    data,
-                    <SymmetricKey as TryFrom<&[u8]>>::try_from(
-                        crypto::decrypt(key, &pair.symmetric_key).as_slice(),
-                    )
-                    .unwrap(),
-                )
-            }
+            DocumentContent::Encrypted {
+                algorithm,
+                data,
+                keys,
+            } => (algorithm, data, keys),
         };
-        let content = DocumentContent::Plaintext(crypto::symmetric_decrypt(&skey, data));
-        self.content = content;
-        true
+        if key.algorithm() != *algorithm {
+            return false;
+        }
+        // a wrong key may still unwrap legacy rsa keys, the content authenticates the result
+        let plaintext = keys.iter().find_map(|wrapped| {
+            let skey = crypto::decrypt(key, &wrapped.symmetric_key)?;
+            let skey = SymmetricKey::try_from(skey.as_slice()).ok()?;
+            crypto::symmetric_try_decrypt(&skey, data)
+        });
+        let Some(plaintext) = plaintext else {
+            return false;
+        };
+        match bincode::deserialize::<(DocumentKind, Vec<u8>)>(&plaintext) {
+            Ok((kind, data)) => {
+                self.kind = kind;
+                self.content = DocumentContent::Plaintext(data);
+                true
+            }
+            Err(_) => false,
+        }
     }
 
     pub fn is_valid(
@@ -154,7 +237,39 @@ impl Document {
         drand_chain: &drand::ChainInfo,
         drand_beacon: &drand::Beacon,
     ) -> bool {
-        if !crypto_puzzle_verify(
+        if self.drand.scheme != drand_chain.scheme_id {
+            tracing::warn!(
+                "document beacon scheme {:?} does not match chain scheme {:?}",
+                self.drand.scheme,
+                drand_chain.scheme_id
+            );
+            return false;
+        }
+        // duplicates are detected by content hash, a forged one would shadow other documents
+        if self.id.content_hash != crypto::sha256(self.content.data()) {
+            tracing::warn!("document content hash does not match its content");
+            return false;
+        }
+        if self.id.round != self.drand.beacon.round_number {
+            tracing::warn!(
+                "document round {} does not match beacon round {}",
+                self.id.round,
+                self.drand.beacon.round_number
+            );
+            return false;
+        }
+        if self.crypto_difficulty < expected_difficulty {
+            tracing::warn!(
+                "message verify crypto difficulty too low: expected at least {} got {}",
+                expected_difficulty,
+                self.crypto_difficulty
+            );
+            return false;
+        }
+        // the puzzle is cheaper to check than the beacon signatures, so forged documents are
+        // rejected before any pairing is computed
+        if !puzzle_verify(
+            self.puzzle_algorithm,
             self.content.data(),
             &self.drand.beacon,
             self.crypto_difficulty,
@@ -163,30 +278,177 @@ impl Document {
             tracing::warn!("document contained invalid crypto solution");
             return false;
         }
-        if self.crypto_difficulty != expected_difficulty {
-            tracing::warn!(
-                "message verify crypto difficulty missmatch: expected {} got {}",
-                expected_difficulty,
-                self.crypto_difficulty
-            );
+
+        if acceptance_window != 0
+            && self.drand.beacon.round_number + acceptance_window <= drand_beacon.round_number
+        {
+            tracing::error!("Failed to verify beacon round");
             return false;
         }
 
-        if drand_beacon
+        // the puzzle and the round are only meaningful for a beacon the chain produced
+        if self
+            .drand
+            .beacon
             .verify(drand_chain.scheme_id, &drand_chain.public_key)
             .is_err()
         {
-            tracing::error!("Failed to verify beacon");
+            tracing::warn!("document contained invalid beacon");
             return false;
         }
 
-        if acceptance_window != 0
-            && self.drand.beacon.round_number + acceptance_window <= drand_beacon.round_number
+        if drand_beacon
+            .verify(drand_chain.scheme_id, &drand_chain.public_key)
+            .is_err()
         {
-            tracing::error!("Failed to verify beacon round");
+            tracing::error!("Failed to verify beacon");
             return false;
         }
 
         true
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn document_drand() -> DocumentDrand {
+        DocumentDrand {
+            chain: "chain".to_string(),
+            beacon: drand::Beacon {
+                round_number: 1,
+                randomness: Default::default(),
+                signature: Default::default(),
+                previous_signature: Default::default(),
+            },
+            scheme: drand::SchemeId::UnchainedOnG1RFC9380,
+        }
+    }
+
+    #[test]
+    fn document_beacon() {
+        let source = drand::LocalBeaconSource::from_period(3);
+        let chain = source.info();
+        let latest = source.beacon(10);
+        let drand = DocumentDrand {
+            chain: source.chain().to_string(),
+            beacon: source.beacon(9),
+            scheme: chain.scheme_id,
+        };
+        let document = Document::plaintext(
+            "topic",
+            DocumentKind::Inline,
+            b"data",
+            PuzzleAlgorithm::Sha256,
+            1,
+            crypto::sha256(b"key"),
+            drand,
+        );
+        assert!(document.is_valid(1, 5, chain, &latest));
+
+        let mut wrong_round = document.clone();
+        wrong_round.id.round = 10;
+        assert!(!wrong_round.is_valid(1, 5, chain, &latest));
+
+        let mut wrong_scheme = document.clone();
+        wrong_scheme.drand.scheme = drand::SchemeId::UnchainedOnG1;
+        assert!(!wrong_scheme.is_valid(1, 5, chain, &latest));
+
+        // a fabricated beacon from a later round, the puzzle being solved for it
+        let mut fabricated = document.clone();
+        fabricated.drand.beacon.round_number = 10;
+        fabricated.id.round = 10;
+        fabricated.nonce_solution = puzzle_solve(
+            PuzzleAlgorithm::Sha256,
+            fabricated.content.data(),
+            &fabricated.drand.beacon,
+            1,
+        );
+        assert!(!fabricated.is_valid(1, 5, chain, &latest));
+    }
+
+    #[test]
+    fn anonymous_receivers() {
+        let receivers = (0..3).map(|_| crypto::generate()).collect::<Vec<_>>();
+        let (_, outsider) = crypto::generate();
+        let public_keys = receivers
+            .iter()
+            .map(|(public_key, _)| public_key.clone())
+            .collect::<Vec<_>>();
+        let document = Document::encrypted(
+            "topic",
+            DocumentKind::Inline,
+            b"data",
+            PuzzleAlgorithm::Sha256,
+            0,
+            crypto::sha256(b"key"),
+            Algorithm::Ed25519X25519,
+            &public_keys,
+            document_drand(),
+        )
+        .unwrap();
+
+        // neither the signing nor the encryption key of a receiver appears in the document
+        let serialized = bincode::serialize(&document).unwrap();
+        for public_key in public_keys.iter() {
+            let public_key = public_key.to_bytes();
+            for key in public_key[public_key.len() - 64..].chunks(32) {
+                assert!(!serialized.windows(32).any(|window| window == key));
+            }
+        }
+
+        for (_, private_key) in receivers.iter() {
+            let mut document = document.clone();
+            assert!(document.decrypt(private_key));
+            assert!(std::matches!(
+                document.content,
+                DocumentContent::Plaintext(ref data) if data == b"data"
+            ));
+        }
+        assert!(!document.clone().decrypt(&outsider));
+
+        // a receiver of another algorithm fails the encryption instead of being left out
+        let (legacy_key, _) = crypto::generate_with(Algorithm::LegacyRsa);
+        let result = Document::encrypted(
+            "topic",
+            DocumentKind::Inline,
+            b"data",
+            PuzzleAlgorithm::Sha256,
+            0,
+            crypto::sha256(b"key"),
+            Algorithm::Ed25519X25519,
+            &[public_keys[0].clone(), legacy_key],
+            document_drand(),
+        );
+        assert!(std::matches!(
+            result,
+            Err(EncryptionError::AlgorithmMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn encrypted_kind() {
+        let (public_key, private_key) = crypto::generate();
+        let mut document = Document::encrypted(
+            "topic",
+            DocumentKind::Manifest,
+            b"manifest",
+            PuzzleAlgorithm::Sha256,
+            0,
+            crypto::sha256(b"key"),
+            Algorithm::Ed25519X25519,
+            &[public_key],
+            document_drand(),
+        )
+        .unwrap();
+        assert_eq!(document.kind, DocumentKind::Inline);
+
+        assert!(document.decrypt(&private_key));
+        assert_eq!(document.kind, DocumentKind::Manifest);
+        assert!(std::matches!(
+            document.content,
+            DocumentContent::Plaintext(ref data) if data == b"manifest"
+        ));
+    }
+}
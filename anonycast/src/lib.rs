@@ -1,19 +1,35 @@
 use crypto::Sha256Hasher;
 use drand::Beacon;
+use serde::{Deserialize, Serialize};
 
 #[macro_use]
 pub mod protocol;
 
 pub mod asset_owner;
+pub mod chunk;
 pub mod client;
 pub mod deaddrop;
 mod deaddrop_conn;
+mod difficulty;
 mod document;
+mod puzzle;
+mod rate_limit;
+mod replay;
+mod retention;
 mod rle;
 pub mod stats;
+pub mod store;
 
 pub(crate) use deaddrop_conn::DeaddropConn;
 pub use deaddrop_conn::{DeaddropAddr, InvalidDeaddropAddr};
+pub use document::{
+    Document, DocumentContent, DocumentId, DocumentKind, EncryptionError, SignedDocument,
+};
+pub use puzzle::{
+    puzzle_solve, puzzle_verify, InvalidPuzzleAlgorithm, PuzzleAlgorithm, PuzzleRequirement,
+    ARGON2_MEMORY_KIB, ARGON2_PRECHECK_DIFFICULTY,
+};
+pub use rle::{FrameTooLarge, DEFAULT_MAX_FRAME_SIZE};
 
 #[derive(Debug)]
 pub struct InvalidModeOfOperation;
@@ -26,7 +42,7 @@ impl std::fmt::Display for InvalidModeOfOperation {
 
 impl std::error::Error for InvalidModeOfOperation {}
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ModeOfOperation {
     Open,
     SenderRestricted,
@@ -69,17 +85,8 @@ pub fn crypto_puzzle_solve(data: &[u8], beacon: &Beacon, difficulty: u8) -> u32
         hasher.update(&beacon.signature);
         hasher.update(&nonce.to_le_bytes());
         let result = hasher.finalize();
-        let mut counter = difficulty;
-        for byte in result.as_bytes() {
-            if counter == 0 {
-                return nonce;
-            }
-            let to_verify = 8.min(counter);
-            counter -= to_verify;
-            let compare = 0b11111111 >> (8 - to_verify);
-            if byte & compare != 0 {
-                break;
-            }
+        if leading_zero_bits(result.as_bytes(), difficulty) {
+            return nonce;
         }
         nonce += 1;
     }
@@ -92,8 +99,13 @@ pub fn crypto_puzzle_verify(data: &[u8], beacon: &Beacon, difficulty: u8, soluti
     hasher.update(&beacon.signature);
     hasher.update(&solution.to_le_bytes());
     let result = hasher.finalize();
+    leading_zero_bits(result.as_bytes(), difficulty)
+}
+
+/// Whether `hash` starts with at least `difficulty` zero bits.
+pub(crate) fn leading_zero_bits(hash: &[u8], difficulty: u8) -> bool {
     let mut counter = difficulty;
-    for byte in result.as_bytes() {
+    for byte in hash {
         if counter == 0 {
             return true;
         }
@@ -104,5 +116,5 @@ pub fn crypto_puzzle_verify(data: &[u8], beacon: &Beacon, difficulty: u8, soluti
             return false;
         }
     }
-    true
+    counter == 0
 }
@@ -1,12 +1,32 @@
-use crypto::{PrivateKey, PublicKey, Ring, RingPrivateKey, RingPublicKey};
+use crypto::{
+    Algorithm, KeyImage, PrivateKey, PublicKey, Ring, RingPrivateKey, RingPublicKey, Sha256,
+};
 use drand::Beacon;
 use serde::{Deserialize, Serialize};
 
-use crate::document::{DocumentId, SignedDocument};
+use crate::{
+    document::{DocumentId, SignedDocument},
+    DeaddropConn, ModeOfOperation, PuzzleAlgorithm, PuzzleRequirement,
+};
+
+/// Version of the wire protocol, bumped on every incompatible change to the exchanged frames.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Feature advertised by deaddrops that accept [`Message::Subscribe`].
+pub const FEATURE_SUBSCRIBE: &str = "subscribe";
+
+/// Feature advertised by deaddrops that store the chunks of large documents.
+pub const FEATURE_CHUNKS: &str = "chunks";
+
+/// Feature advertised by deaddrops that adjust the puzzle difficulty of documents to their load,
+/// which is retrieved with [`Message::RetrieveDifficulty`].
+pub const FEATURE_ADAPTIVE_DIFFICULTY: &str = "adaptive-difficulty";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Signature {
     Asymmetric {
+        /// Must match the algorithm of both `key` and `signature`.
+        algorithm: Algorithm,
         key: PublicKey,
         signature: crypto::Signature,
     },
@@ -46,6 +66,7 @@ impl<T: Signable> Signed<T> {
         Self {
             content,
             signature: Signature::Asymmetric {
+                algorithm: key.algorithm(),
                 key: key.public_key(),
                 signature,
             },
@@ -61,6 +82,30 @@ impl<T: Signable> Signed<T> {
         }
     }
 
+    /// Ring signs `content` so that signatures made by the same member with the same `context`
+    /// share a key image.
+    pub fn ring_sign_linkable(
+        key: &RingPrivateKey,
+        ring: &Ring,
+        context: &[u8],
+        content: T,
+    ) -> Self {
+        let serialized = content.serialize_for_signature();
+        let signature = crypto::ring_sign_linkable(key, ring, context, &serialized);
+        Self {
+            content,
+            signature: Signature::RingAsymmetric { signature },
+        }
+    }
+
+    /// The key that produced an asymmetric signature.
+    pub fn public_key(&self) -> Option<&PublicKey> {
+        match self.signature {
+            Signature::Asymmetric { ref key, .. } => Some(key),
+            _ => None,
+        }
+    }
+
     pub fn is_asymmetric(&self) -> bool {
         std::matches!(self.signature, Signature::Asymmetric { .. })
     }
@@ -70,30 +115,29 @@ impl<T: Signable> Signed<T> {
     }
 
     pub fn verify(&self) -> bool {
-        let (signature_key, signature) = match self.signature {
+        let (algorithm, signature_key, signature) = match self.signature {
             Signature::Asymmetric {
+                algorithm,
                 ref key,
                 ref signature,
-            } => (key, signature),
+            } => (algorithm, key, signature),
             _ => return false,
         };
+        if signature_key.algorithm() != algorithm || signature.algorithm() != algorithm {
+            return false;
+        }
         let serialized = self.content.serialize_for_signature();
         crypto::verify(signature_key, &serialized, signature)
     }
 
     pub fn verify_with(&self, key: &PublicKey) -> bool {
-        let (signature_key, signature) = match self.signature {
+        match self.signature {
             Signature::Asymmetric {
-                ref key,
-                ref signature,
-            } => (key, signature),
-            _ => return false,
-        };
-        if key != signature_key {
-            return false;
+                key: ref signature_key,
+                ..
+            } if signature_key == key => self.verify(),
+            _ => false,
         }
-        let serialized = self.content.serialize_for_signature();
-        crypto::verify(key, &serialized, signature)
     }
 
     pub fn ring_verify(&self, ring: &Ring) -> bool {
@@ -104,31 +148,332 @@ impl<T: Signable> Signed<T> {
         let serialized = self.content.serialize_for_signature();
         crypto::ring_verify(ring, &serialized, signature)
     }
+
+    /// Verifies a ring signature made with `context`, returning the key image of the signer.
+    pub fn ring_verify_linkable(&self, ring: &Ring, context: &[u8]) -> Option<KeyImage> {
+        match self.signature {
+            Signature::RingAsymmetric { ref signature }
+                if signature.context() == context && self.ring_verify(ring) =>
+            {
+                Some(signature.key_image())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Limits how many documents each allowed sender can publish per epoch of drand rounds. Senders
+/// stay anonymous, but their signatures within an epoch share a key image the deaddrop counts.
+/// Documents are only accepted during the epoch of their round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub documents_per_epoch: u32,
+    pub epoch_rounds: u64,
+}
+
+impl RateLimit {
+    pub fn epoch(&self, round: u64) -> u64 {
+        round / self.epoch_rounds.max(1)
+    }
+
+    /// Context the documents published in `round` of `chain` must be ring signed with.
+    pub fn context(&self, chain: &str, round: u64) -> Vec<u8> {
+        bincode::serialize(&("anonycast rate limit", chain, self.epoch(round))).unwrap()
+    }
+}
+
+/// First frame sent by a client after connecting to a deaddrop. The version must stay the first
+/// field so it can be read from the hellos of every protocol version.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientHello {
+    pub version: u32,
+    /// Random value the deaddrop signs its hello with, so the hello cannot be replayed.
+    pub nonce: [u8; 16],
+}
+
+/// Reply of the deaddrop to a [`ClientHello`], describing what its requests must satisfy. The
+/// deaddrop closes the connection after sending it if the versions do not match. Like the
+/// [`ClientHello`], the version must stay the first field.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeaddropHello {
+    pub version: u32,
+    pub mode: ModeOfOperation,
+    /// Puzzle difficulty of the requests other than [`RetrieveDocumentIds`], which always use
+    /// [`PuzzleAlgorithm::Sha256`].
+    pub difficulty: u8,
+    /// Puzzle algorithms accepted for documents and [`RetrieveDocumentIds`] requests, with the
+    /// least difficulty required from each.
+    pub puzzle_algorithms: Vec<PuzzleRequirement>,
+    pub acceptance_window: u64,
+    /// Optional protocol features supported by the deaddrop.
+    pub features: Vec<String>,
+    /// Largest frame the deaddrop accepts, larger requests are rejected and close the connection.
+    pub max_frame_size: u32,
+    /// Rate limit enforced on the ring signed documents of sender restricted deaddrops.
+    pub rate_limit: Option<RateLimit>,
+    /// Nonce of the [`ClientHello`] being answered.
+    pub nonce: [u8; 16],
+}
+impl_signable_serde!(DeaddropHello);
+
+/// Binds a signed request to a single deaddrop and drand round so it cannot be replayed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestContext {
+    /// Random value identifying the request, echoed back in the [`Response`].
+    pub nonce: [u8; 16],
+    /// Public key of the deaddrop the request is addressed to.
+    pub deaddrop: PublicKey,
+    pub chain: String,
+    pub round: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub context: RequestContext,
+    pub message: Message,
+}
+impl_signable_serde!(Request);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    /// Nonce of the request being answered.
+    pub nonce: [u8; 16],
+    pub message: Message,
+}
+impl_signable_serde!(Response);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ErrorCode {
+    InvalidSignature,
+    InvalidDocument,
+    InvalidPuzzle,
+    ExpiredBeacon,
+    BeaconUnavailable,
+    UnknownDocument,
+    KeysUnavailable,
+    UnexpectedMessage,
+    /// A different document is already stored under the same id.
+    Conflict,
+    WrongDeaddrop,
+    ReplayedRequest,
+    FrameTooLarge,
+    /// The sender already published as many documents as allowed in the current epoch.
+    RateLimited,
+    MalformedRequest,
+    Internal,
+    /// The deaddrop has no room left for the upload.
+    StorageFull,
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ErrorCode::InvalidSignature => "invalid-signature",
+            ErrorCode::InvalidDocument => "invalid-document",
+            ErrorCode::InvalidPuzzle => "invalid-puzzle",
+            ErrorCode::ExpiredBeacon => "expired-beacon",
+            ErrorCode::BeaconUnavailable => "beacon-unavailable",
+            ErrorCode::UnknownDocument => "unknown-document",
+            ErrorCode::KeysUnavailable => "keys-unavailable",
+            ErrorCode::UnexpectedMessage => "unexpected-message",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::WrongDeaddrop => "wrong-deaddrop",
+            ErrorCode::ReplayedRequest => "replayed-request",
+            ErrorCode::FrameTooLarge => "frame-too-large",
+            ErrorCode::RateLimited => "rate-limited",
+            ErrorCode::MalformedRequest => "malformed-request",
+            ErrorCode::Internal => "internal",
+            ErrorCode::StorageFull => "storage-full",
+        })
+    }
+}
+
+/// A request rejected by a deaddrop.
+#[derive(Debug, Clone)]
+pub struct DeaddropError {
+    pub code: ErrorCode,
+    pub reason: String,
+}
+
+impl std::fmt::Display for DeaddropError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "deaddrop rejected request ({}): {}",
+            self.code, self.reason
+        )
+    }
+}
+
+impl std::error::Error for DeaddropError {}
+
+/// Failure to exchange a request with a deaddrop.
+#[derive(Debug)]
+pub enum ExchangeError {
+    /// Failed to communicate with the deaddrop.
+    Transport(std::io::Error),
+    /// The response carried an invalid signature.
+    Signature(String),
+    /// The response does not answer the request.
+    Protocol(String),
+    /// The deaddrop rejected the request.
+    Rejected(DeaddropError),
+}
+
+impl std::fmt::Display for ExchangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExchangeError::Transport(err) => write!(f, "deaddrop transport error: {err}"),
+            ExchangeError::Signature(reason) => write!(f, "invalid signature: {reason}"),
+            ExchangeError::Protocol(reason) => write!(f, "protocol error: {reason}"),
+            ExchangeError::Rejected(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ExchangeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ExchangeError::Transport(err) => Some(err),
+            ExchangeError::Rejected(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ExchangeError {
+    fn from(value: std::io::Error) -> Self {
+        ExchangeError::Transport(value)
+    }
+}
+
+/// Sends a request to a deaddrop and returns the message it answered with. The response signature
+/// is only checked if `verify` is set.
+pub(crate) async fn exchange(
+    deaddrop: DeaddropConn,
+    request: Signed<Request>,
+    verify: bool,
+) -> Result<Message, ExchangeError> {
+    let response: Signed<Response> = deaddrop.send_and_read(&request).await?;
+    if response.content.nonce != request.content.context.nonce {
+        return Err(ExchangeError::Protocol(
+            "deaddrop response does not match the request".to_string(),
+        ));
+    }
+    if verify && !response.verify_with(deaddrop.public_key()) {
+        return Err(ExchangeError::Signature(
+            "invalid deaddrop response signature".to_string(),
+        ));
+    }
+    Ok(response.content.message)
+}
+
+/// Converts a response that does not answer `request` into an error.
+pub(crate) fn unexpected_response(request: &str, response: Message) -> ExchangeError {
+    match response {
+        Message::Error { code, reason } => ExchangeError::Rejected(DeaddropError { code, reason }),
+        _ => ExchangeError::Protocol(format!("unexpected deaddrop response to {request}")),
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
     Success,
+    /// Sent by the deaddrop when it rejects a request.
+    Error {
+        code: ErrorCode,
+        reason: String,
+    },
     RetrieveDocumentIds(RetrieveDocumentIds),
     RetrieveDocuments(RetrieveDocuments),
     PublishDocument(PublishDocument),
     DocumentIdList(DocumentIdList),
     DocumentList(DocumentList),
-    UpdateAllowedKeys(UpdateAllowedKeys),
+    /// Sent by the asset owner, which signs both the update and the enclosing request.
+    UpdateAllowedKeys(Signed<UpdateAllowedKeys>),
     RetrieveKeys,
+    AllowedKeys(AllowedKeys),
+    /// Keeps the connection open and has the deaddrop push the documents published to `topic`,
+    /// starting with the ones published in or after round `since_round`. Every push is a
+    /// [`Message::DocumentList`] answering the request.
+    Subscribe(Subscribe),
+    UploadChunk(UploadChunk),
+    /// Asks which of the listed chunks the deaddrop does not store, answered with
+    /// [`Message::ChunkHashes`].
+    RetrieveMissingChunks(RetrieveMissingChunks),
+    RetrieveChunk(RetrieveChunk),
+    ChunkHashes(ChunkHashes),
+    Chunk(Chunk),
+    /// Asks for the puzzle difficulty currently required from the documents of `chain`,
+    /// answered with [`Message::Difficulty`].
+    RetrieveDifficulty {
+        chain: String,
+    },
+    Difficulty(Signed<PuzzleDifficulty>),
+    /// Reply to a publication of a document the deaddrop already stores, with the id it is
+    /// stored under.
+    AlreadyStored(DocumentId),
+    /// Sent by peer deaddrops to compare their stores.
+    RetrieveDigests(RetrieveDigests),
+    RetrieveRoundIds(RetrieveRoundIds),
+    DigestList(DigestList),
+}
+
+impl Message {
+    /// Whether this message is a request sent to a deaddrop, as opposed to a response.
+    pub fn is_request(&self) -> bool {
+        match self {
+            Message::RetrieveDocumentIds(_)
+            | Message::RetrieveDocuments(_)
+            | Message::RetrieveDigests(_)
+            | Message::RetrieveRoundIds(_)
+            | Message::PublishDocument(_)
+            | Message::UpdateAllowedKeys(_)
+            | Message::RetrieveKeys
+            | Message::Subscribe(_)
+            | Message::UploadChunk(_)
+            | Message::RetrieveMissingChunks(_)
+            | Message::RetrieveChunk(_)
+            | Message::RetrieveDifficulty { .. } => true,
+            Message::Success
+            | Message::AlreadyStored(_)
+            | Message::Error { .. }
+            | Message::DocumentIdList(_)
+            | Message::DocumentList(_)
+            | Message::AllowedKeys(_)
+            | Message::DigestList(_)
+            | Message::ChunkHashes(_)
+            | Message::Chunk(_)
+            | Message::Difficulty(_) => false,
+        }
+    }
 }
 
 impl Signable for Message {
     fn serialize_for_signature(&self) -> Vec<u8> {
         match self {
-            Message::Success => Default::default(), // TODO: replays?
+            Message::Success => Default::default(),
+            Message::AlreadyStored(v) => bincode::serialize(v).unwrap(),
+            Message::Error { code, reason } => bincode::serialize(&(code, reason)).unwrap(),
             Message::RetrieveDocumentIds(v) => v.serialize_for_signature(),
             Message::RetrieveDocuments(v) => v.serialize_for_signature(),
+            Message::RetrieveDigests(v) => v.serialize_for_signature(),
+            Message::RetrieveRoundIds(v) => v.serialize_for_signature(),
+            Message::DigestList(v) => v.serialize_for_signature(),
             Message::PublishDocument(v) => v.serialize_for_signature(),
             Message::DocumentIdList(v) => v.serialize_for_signature(),
             Message::DocumentList(v) => v.serialize_for_signature(),
-            Message::UpdateAllowedKeys(v) => v.serialize_for_signature(),
+            Message::UpdateAllowedKeys(v) => bincode::serialize(v).unwrap(),
             Message::RetrieveKeys => Default::default(),
+            Message::AllowedKeys(v) => v.serialize_for_signature(),
+            Message::Subscribe(v) => v.serialize_for_signature(),
+            Message::UploadChunk(v) => v.serialize_for_signature(),
+            Message::RetrieveMissingChunks(v) => v.serialize_for_signature(),
+            Message::RetrieveChunk(v) => v.serialize_for_signature(),
+            Message::ChunkHashes(v) => v.serialize_for_signature(),
+            Message::Chunk(v) => v.serialize_for_signature(),
+            Message::RetrieveDifficulty { chain } => bincode::serialize(chain).unwrap(),
+            Message::Difficulty(v) => bincode::serialize(v).unwrap(),
         }
     }
 }
@@ -139,6 +484,7 @@ pub struct RetrieveDocumentIds {
     pub since_round: u64,
     pub beacon: Beacon,
     pub chain: String,
+    pub puzzle_algorithm: PuzzleAlgorithm,
     pub nonce_solution: u32,
 }
 impl_signable_serde!(RetrieveDocumentIds);
@@ -152,12 +498,107 @@ pub struct RetrieveDocuments {
 }
 impl_signable_serde!(RetrieveDocuments);
 
+/// Subscription to a topic, whose puzzle is solved like the one of [`RetrieveDocuments`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscribe {
+    pub topic: String,
+    pub since_round: u64,
+    pub beacon: Beacon,
+    pub chain: String,
+    pub nonce_solution: u32,
+}
+impl_signable_serde!(Subscribe);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PublishDocument {
     pub document: SignedDocument,
 }
 impl_signable_serde!(PublishDocument);
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetrieveDigests {
+    pub since_round: u64,
+    pub beacon: Beacon,
+    pub chain: String,
+    pub nonce_solution: u32,
+}
+impl_signable_serde!(RetrieveDigests);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetrieveRoundIds {
+    pub rounds: Vec<u64>,
+    pub beacon: Beacon,
+    pub chain: String,
+    pub nonce_solution: u32,
+}
+impl_signable_serde!(RetrieveRoundIds);
+
+/// Summary of the documents a deaddrop stores for a round.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoundDigest {
+    pub round: u64,
+    pub count: u64,
+    /// Hash of the sorted ids of the documents.
+    pub digest: Sha256,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigestList {
+    pub digests: Vec<RoundDigest>,
+}
+impl_signable_serde!(DigestList);
+
+/// Chunk of a large document, see [`crate::chunk::Manifest`]. The puzzle is solved over the
+/// chunk's data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadChunk {
+    pub data: Vec<u8>,
+    pub beacon: Beacon,
+    pub chain: String,
+    pub nonce_solution: u32,
+}
+impl_signable_serde!(UploadChunk);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetrieveMissingChunks {
+    pub chunks: Vec<Sha256>,
+    pub beacon: Beacon,
+    pub chain: String,
+    pub nonce_solution: u32,
+}
+impl_signable_serde!(RetrieveMissingChunks);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetrieveChunk {
+    pub hash: Sha256,
+    pub beacon: Beacon,
+    pub chain: String,
+    pub nonce_solution: u32,
+}
+impl_signable_serde!(RetrieveChunk);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkHashes {
+    pub chunks: Vec<Sha256>,
+}
+impl_signable_serde!(ChunkHashes);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chunk {
+    pub data: Vec<u8>,
+}
+impl_signable_serde!(Chunk);
+
+/// Puzzle difficulty a deaddrop requires from the documents of `chain` published in `round`,
+/// for every accepted puzzle algorithm, signed by the deaddrop.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PuzzleDifficulty {
+    pub chain: String,
+    pub round: u64,
+    pub difficulties: Vec<PuzzleRequirement>,
+}
+impl_signable_serde!(PuzzleDifficulty);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DocumentIdList {
     pub message_ids: Vec<DocumentId>,
@@ -165,6 +606,8 @@ pub struct DocumentIdList {
 }
 impl_signable_serde!(DocumentIdList);
 
+/// Documents in the order they were requested. Only a prefix of them is returned when they do not
+/// all fit in a frame, the others are requested again.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DocumentList {
     pub documents: Vec<SignedDocument>,
@@ -178,3 +621,9 @@ pub struct UpdateAllowedKeys {
     pub beacon: drand::Beacon,
 }
 impl_signable_serde!(UpdateAllowedKeys);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AllowedKeys {
+    pub allowed_keys: Signed<UpdateAllowedKeys>,
+}
+impl_signable_serde!(AllowedKeys);
@@ -0,0 +1,204 @@
+use argon2::{Argon2, Block, Params, Version};
+use drand::Beacon;
+use serde::{Deserialize, Serialize};
+
+use crate::{crypto_puzzle_solve, crypto_puzzle_verify, leading_zero_bits};
+
+/// Memory, in KiB, filled by every attempt at an Argon2id puzzle.
+pub const ARGON2_MEMORY_KIB: u32 = 4 * 1024;
+
+/// Difficulty of the SHA-256 puzzle an Argon2id solution must also solve. Verifiers check it
+/// first, so each Argon2id hash they compute costs the sender thousands of SHA-256 hashes.
+pub const ARGON2_PRECHECK_DIFFICULTY: u8 = 12;
+
+#[derive(Debug)]
+pub struct InvalidPuzzleAlgorithm;
+
+impl std::fmt::Display for InvalidPuzzleAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("invalid puzzle algorithm")
+    }
+}
+
+impl std::error::Error for InvalidPuzzleAlgorithm {}
+
+/// Function whose output must start with as many zero bits as the puzzle difficulty.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PuzzleAlgorithm {
+    /// SHA-256 of the data, the beacon signature and the nonce.
+    #[default]
+    Sha256,
+    /// Argon2id filling [`ARGON2_MEMORY_KIB`] of memory per attempt, which keeps GPUs and ASICs
+    /// from solving it much faster than a laptop. Attempts are far slower than with SHA-256, so
+    /// deaddrops accepting it use lower difficulties. Only nonces solving the SHA-256 puzzle of
+    /// [`ARGON2_PRECHECK_DIFFICULTY`] are attempted.
+    Argon2id,
+}
+
+impl std::fmt::Display for PuzzleAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PuzzleAlgorithm::Sha256 => "sha256",
+            PuzzleAlgorithm::Argon2id => "argon2id",
+        })
+    }
+}
+
+impl std::str::FromStr for PuzzleAlgorithm {
+    type Err = InvalidPuzzleAlgorithm;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(Self::Sha256),
+            "argon2id" => Ok(Self::Argon2id),
+            _ => Err(InvalidPuzzleAlgorithm),
+        }
+    }
+}
+
+/// Puzzle algorithm accepted by a deaddrop and the least difficulty it requires with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PuzzleRequirement {
+    pub algorithm: PuzzleAlgorithm,
+    pub difficulty: u8,
+}
+
+#[doc(hidden)]
+pub fn puzzle_solve(
+    algorithm: PuzzleAlgorithm,
+    data: &[u8],
+    beacon: &Beacon,
+    difficulty: u8,
+) -> u32 {
+    match algorithm {
+        PuzzleAlgorithm::Sha256 => crypto_puzzle_solve(data, beacon, difficulty),
+        PuzzleAlgorithm::Argon2id => {
+            let mut puzzle = Argon2Puzzle::new(data, beacon);
+            (0..)
+                .filter(|nonce| {
+                    crypto_puzzle_verify(data, beacon, ARGON2_PRECHECK_DIFFICULTY, *nonce)
+                })
+                .find(|nonce| leading_zero_bits(&puzzle.hash(*nonce), difficulty))
+                .expect("a solution exists")
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn puzzle_verify(
+    algorithm: PuzzleAlgorithm,
+    data: &[u8],
+    beacon: &Beacon,
+    difficulty: u8,
+    solution: u32,
+) -> bool {
+    match algorithm {
+        PuzzleAlgorithm::Sha256 => crypto_puzzle_verify(data, beacon, difficulty, solution),
+        PuzzleAlgorithm::Argon2id => {
+            // garbage solutions are turned away before allocating the Argon2id memory
+            if !crypto_puzzle_verify(data, beacon, ARGON2_PRECHECK_DIFFICULTY, solution) {
+                return false;
+            }
+            let hash = Argon2Puzzle::new(data, beacon).hash(solution);
+            leading_zero_bits(&hash, difficulty)
+        }
+    }
+}
+
+/// Argon2id over the hash of the data and a nonce, salted with the hash of the beacon signature.
+/// The memory is allocated once and reused by every attempt.
+struct Argon2Puzzle {
+    argon2: Argon2<'static>,
+    blocks: Vec<Block>,
+    data_hash: crypto::Sha256,
+    salt: crypto::Sha256,
+}
+
+impl Argon2Puzzle {
+    fn new(data: &[u8], beacon: &Beacon) -> Self {
+        let params =
+            Params::new(ARGON2_MEMORY_KIB, 1, 1, Some(32)).expect("argon2 parameters are valid");
+        let blocks = vec![Block::default(); params.block_count()];
+        Self {
+            argon2: Argon2::new(argon2::Algorithm::Argon2id, Version::V0x13, params),
+            blocks,
+            data_hash: crypto::sha256(data),
+            salt: crypto::sha256(&beacon.signature),
+        }
+    }
+
+    fn hash(&mut self, nonce: u32) -> [u8; 32] {
+        let mut password = [0u8; 36];
+        password[..32].copy_from_slice(self.data_hash.as_bytes());
+        password[32..].copy_from_slice(&nonce.to_le_bytes());
+        let mut output = [0u8; 32];
+        self.argon2
+            .hash_password_into_with_memory(
+                &password,
+                self.salt.as_bytes(),
+                &mut output,
+                &mut self.blocks,
+            )
+            .expect("argon2 inputs are valid");
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn argon2_puzzle() {
+        let beacon = Beacon {
+            round_number: 1,
+            randomness: Default::default(),
+            signature: vec![1; 48],
+            previous_signature: Default::default(),
+        };
+        let data = b"document";
+        let solution = puzzle_solve(PuzzleAlgorithm::Argon2id, data, &beacon, 3);
+        assert!(puzzle_verify(
+            PuzzleAlgorithm::Argon2id,
+            data,
+            &beacon,
+            3,
+            solution
+        ));
+        assert!(!puzzle_verify(
+            PuzzleAlgorithm::Argon2id,
+            b"other",
+            &beacon,
+            16,
+            solution
+        ));
+        // nonces failing the SHA-256 pre-check are rejected whatever the difficulty
+        let unchecked = (0..)
+            .find(|nonce| !crypto_puzzle_verify(data, &beacon, ARGON2_PRECHECK_DIFFICULTY, *nonce))
+            .unwrap();
+        assert!(!puzzle_verify(
+            PuzzleAlgorithm::Argon2id,
+            data,
+            &beacon,
+            0,
+            unchecked
+        ));
+
+        // solutions are not interchangeable between algorithms
+        let solution = puzzle_solve(PuzzleAlgorithm::Sha256, data, &beacon, 16);
+        assert!(puzzle_verify(
+            PuzzleAlgorithm::Sha256,
+            data,
+            &beacon,
+            16,
+            solution
+        ));
+        assert!(!puzzle_verify(
+            PuzzleAlgorithm::Argon2id,
+            data,
+            &beacon,
+            16,
+            solution
+        ));
+    }
+}
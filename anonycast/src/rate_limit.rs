@@ -0,0 +1,63 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crypto::KeyImage;
+
+/// Documents published per key image, per chain and epoch.
+#[derive(Debug, Default)]
+pub(crate) struct KeyImageLimiter {
+    chains: HashMap<String, BTreeMap<u64, HashMap<KeyImage, u32>>>,
+}
+
+impl KeyImageLimiter {
+    /// Records a publication by `key_image` in `epoch` of `chain`, forgetting the epochs before
+    /// `oldest_epoch`. Returns false, without recording it, if `limit` publications were already
+    /// recorded.
+    pub fn insert(
+        &mut self,
+        chain: &str,
+        epoch: u64,
+        oldest_epoch: u64,
+        key_image: KeyImage,
+        limit: u32,
+    ) -> bool {
+        let epochs = self.chains.entry(chain.to_string()).or_default();
+        *epochs = epochs.split_off(&oldest_epoch);
+        let count = epochs
+            .entry(epoch)
+            .or_default()
+            .entry(key_image)
+            .or_default();
+        if *count >= limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn key_image_limiter() {
+        let (pub0, priv0) = crypto::ring_generate();
+        let (pub1, priv1) = crypto::ring_generate();
+        let ring = crypto::Ring::from(vec![pub0, pub1]);
+        let image =
+            |key, context: &[u8]| crypto::ring_sign_linkable(key, &ring, context, b"").key_image();
+        let image0 = image(&priv0, b"epoch 1");
+        let image1 = image(&priv1, b"epoch 1");
+
+        let mut limiter = KeyImageLimiter::default();
+        assert!(limiter.insert("chain", 1, 0, image0, 2));
+        assert!(limiter.insert("chain", 1, 0, image0, 2));
+        assert!(!limiter.insert("chain", 1, 0, image0, 2));
+        assert!(limiter.insert("chain", 1, 0, image1, 2));
+        assert!(limiter.insert("other", 1, 0, image0, 2));
+
+        // epoch 1 is forgotten once it is too old
+        assert!(limiter.insert("chain", 2, 2, image0, 2));
+        assert!(limiter.chains["chain"].keys().all(|epoch| *epoch >= 2));
+    }
+}
@@ -0,0 +1,45 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::protocol::RequestContext;
+
+/// Nonces of the requests accepted within the acceptance window, per chain and round.
+#[derive(Debug, Default)]
+pub(crate) struct ReplayCache {
+    chains: HashMap<String, BTreeMap<u64, HashSet<[u8; 16]>>>,
+}
+
+impl ReplayCache {
+    /// Records the nonce of a request, forgetting the rounds of its chain that are no longer
+    /// within `window` rounds of `current_round`. Returns false if the nonce was already recorded.
+    pub fn insert(&mut self, context: &RequestContext, current_round: u64, window: u64) -> bool {
+        let rounds = self.chains.entry(context.chain.clone()).or_default();
+        let oldest_round = (current_round + 1).saturating_sub(window);
+        *rounds = rounds.split_off(&oldest_round);
+        rounds.entry(context.round).or_default().insert(context.nonce)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn replay_cache() {
+        let (deaddrop, _) = crypto::generate();
+        let context = |nonce: u8, round: u64| RequestContext {
+            nonce: [nonce; 16],
+            deaddrop: deaddrop.clone(),
+            chain: "chain".to_string(),
+            round,
+        };
+
+        let mut cache = ReplayCache::default();
+        assert!(cache.insert(&context(1, 10), 10, 5));
+        assert!(!cache.insert(&context(1, 10), 12, 5));
+        assert!(cache.insert(&context(2, 10), 12, 5));
+
+        // round 10 falls out of the window and is forgotten
+        assert!(cache.insert(&context(3, 15), 15, 5));
+        assert!(cache.chains["chain"].keys().all(|round| *round > 10));
+    }
+}
@@ -0,0 +1,219 @@
+use std::{collections::HashMap, time::Duration};
+
+use crypto::Sha256;
+
+use crate::{
+    document::DocumentId,
+    store::{ChunkMetadata, DocumentMetadata},
+};
+
+/// Limits on the documents kept by a deaddrop. Documents are evicted oldest round first when
+/// a limit is exceeded.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// Maximum age of a document, or of a chunk since its upload, in drand rounds of its chain.
+    pub max_age_rounds: Option<u64>,
+    /// Maximum total size of the stored documents in bytes.
+    pub max_total_bytes: Option<u64>,
+    /// Maximum number of documents kept per topic, the newest ones are kept.
+    pub max_documents_per_topic: Option<usize>,
+    /// Maximum total size of the stored chunks in bytes. Chunks are not evicted to make room,
+    /// uploads are rejected until older chunks exceed `max_age_rounds`.
+    pub max_chunk_bytes: Option<u64>,
+    /// How often the policy is enforced.
+    pub interval: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            max_age_rounds: None,
+            max_total_bytes: None,
+            max_documents_per_topic: None,
+            max_chunk_bytes: None,
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age_rounds.is_none()
+            && self.max_total_bytes.is_none()
+            && self.max_documents_per_topic.is_none()
+    }
+
+    /// Selects the documents that must be evicted to satisfy the policy.
+    ///
+    /// `current_rounds` maps a drand chain to its latest round. Documents of chains missing from
+    /// it are never evicted because of their age.
+    pub(crate) fn select_evictions(
+        &self,
+        mut documents: Vec<DocumentMetadata>,
+        current_rounds: &HashMap<String, u64>,
+    ) -> Vec<DocumentId> {
+        let mut evicted = Vec::new();
+        // newest first, ties broken by content hash so the selection is deterministic
+        documents.sort_by(|a, b| {
+            b.id.round.cmp(&a.id.round).then_with(|| {
+                a.id.content_hash
+                    .as_bytes()
+                    .cmp(b.id.content_hash.as_bytes())
+            })
+        });
+
+        if let Some(max_age) = self.max_age_rounds {
+            documents.retain(|document| {
+                let expired = match current_rounds.get(&document.chain) {
+                    Some(current_round) => document.id.round + max_age < *current_round,
+                    None => false,
+                };
+                if expired {
+                    evicted.push(document.id.clone());
+                }
+                !expired
+            });
+        }
+
+        if let Some(max_documents) = self.max_documents_per_topic {
+            let mut topic_counts = HashMap::<String, usize>::new();
+            documents.retain(|document| {
+                let count = topic_counts.entry(document.topic.clone()).or_default();
+                *count += 1;
+                if *count > max_documents {
+                    evicted.push(document.id.clone());
+                    return false;
+                }
+                true
+            });
+        }
+
+        if let Some(max_bytes) = self.max_total_bytes {
+            let mut total_bytes = documents.iter().map(|d| d.size).sum::<u64>();
+            while total_bytes > max_bytes {
+                let document = match documents.pop() {
+                    Some(document) => document,
+                    None => break,
+                };
+                total_bytes -= document.size;
+                evicted.push(document.id);
+            }
+        }
+
+        evicted
+    }
+
+    /// Selects the chunks older than `max_age_rounds`. Chunks of chains missing from
+    /// `current_rounds` are never evicted.
+    pub(crate) fn select_chunk_evictions(
+        &self,
+        chunks: &[ChunkMetadata],
+        current_rounds: &HashMap<String, u64>,
+    ) -> Vec<Sha256> {
+        let Some(max_age) = self.max_age_rounds else {
+            return Vec::new();
+        };
+        chunks
+            .iter()
+            .filter(|chunk| match current_rounds.get(&chunk.chain) {
+                Some(current_round) => chunk.round + max_age < *current_round,
+                None => false,
+            })
+            .map(|chunk| chunk.hash)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn metadata(topic: &str, round: u64, size: u64) -> DocumentMetadata {
+        DocumentMetadata {
+            id: DocumentId {
+                round,
+                content_hash: crypto::sha256(format!("{topic}{round}").as_bytes()),
+                sender_tag: crypto::sha256(b"key"),
+            },
+            topic: topic.to_string(),
+            chain: "chain".to_string(),
+            size,
+        }
+    }
+
+    fn evicted_rounds(policy: &RetentionPolicy, documents: Vec<DocumentMetadata>) -> Vec<u64> {
+        let current_rounds = HashMap::from([("chain".to_string(), 100)]);
+        let mut rounds = policy
+            .select_evictions(documents, &current_rounds)
+            .into_iter()
+            .map(|id| id.round)
+            .collect::<Vec<_>>();
+        rounds.sort();
+        rounds
+    }
+
+    #[test]
+    fn select_evictions() {
+        let documents = vec![
+            metadata("a", 10, 100),
+            metadata("a", 50, 100),
+            metadata("a", 90, 100),
+            metadata("b", 95, 100),
+        ];
+
+        let policy = RetentionPolicy::default();
+        assert!(evicted_rounds(&policy, documents.clone()).is_empty());
+
+        let policy = RetentionPolicy {
+            max_age_rounds: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(evicted_rounds(&policy, documents.clone()), vec![10]);
+
+        let policy = RetentionPolicy {
+            max_documents_per_topic: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(evicted_rounds(&policy, documents.clone()), vec![10, 50]);
+
+        let policy = RetentionPolicy {
+            max_total_bytes: Some(250),
+            ..Default::default()
+        };
+        assert_eq!(evicted_rounds(&policy, documents.clone()), vec![10, 50]);
+
+        let policy = RetentionPolicy {
+            max_age_rounds: Some(60),
+            max_total_bytes: Some(100),
+            max_documents_per_topic: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(evicted_rounds(&policy, documents), vec![10, 50, 90]);
+    }
+
+    #[test]
+    fn select_chunk_evictions() {
+        let chunk = |chain: &str, round: u64| ChunkMetadata {
+            hash: crypto::sha256(format!("{chain}{round}").as_bytes()),
+            chain: chain.to_string(),
+            round,
+            size: 100,
+        };
+        let chunks = vec![chunk("chain", 10), chunk("chain", 90), chunk("other", 10)];
+        let current_rounds = HashMap::from([("chain".to_string(), 100)]);
+
+        let policy = RetentionPolicy::default();
+        assert!(policy
+            .select_chunk_evictions(&chunks, &current_rounds)
+            .is_empty());
+
+        let policy = RetentionPolicy {
+            max_age_rounds: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.select_chunk_evictions(&chunks, &current_rounds),
+            vec![chunks[0].hash]
+        );
+    }
+}
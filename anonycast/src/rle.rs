@@ -3,6 +3,42 @@ use std::io::{Read, Write};
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
 
+/// Default limit on the size of the frames read from a peer.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Error wrapped by the [`std::io::Error`] returned for frames exceeding the size limit.
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub size: u64,
+    pub max_size: u32,
+}
+
+impl FrameTooLarge {
+    /// The size limit error wrapped by `err`, if any.
+    pub fn from_io(err: &std::io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl std::fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "frame of {} bytes exceeds the limit of {} bytes",
+            self.size, self.max_size
+        )
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+pub fn frame_too_large(size: u64, max_size: u32) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        FrameTooLarge { size, max_size },
+    )
+}
+
 // run length encoding
 pub fn write<W: Write>(mut stream: W, data: &[u8]) -> std::io::Result<()> {
     let size = u32::to_be_bytes(data.len().try_into().unwrap());
@@ -14,7 +50,9 @@ pub fn write<W: Write>(mut stream: W, data: &[u8]) -> std::io::Result<()> {
 }
 
 pub async fn async_write<W: AsyncWrite + Unpin>(mut stream: W, data: &[u8]) -> std::io::Result<()> {
-    let size = u32::to_be_bytes(data.len().try_into().unwrap());
+    let size =
+        u32::try_from(data.len()).map_err(|_| frame_too_large(data.len() as u64, u32::MAX))?;
+    let size = u32::to_be_bytes(size);
     stream.write_all(&size).await?;
     stream.write_all(data).await?;
     stream.flush().await?;
@@ -32,16 +70,45 @@ pub fn read<R: Read>(mut stream: R) -> std::io::Result<Vec<u8>> {
     Ok(data)
 }
 
-pub async fn async_read<R: AsyncRead + Unpin>(mut stream: R) -> std::io::Result<Vec<u8>> {
+pub async fn async_read<R: AsyncRead + Unpin>(
+    mut stream: R,
+    max_size: u32,
+) -> std::io::Result<Vec<u8>> {
+    let size = async_read_size(&mut stream, max_size).await?;
+    async_read_data(&mut stream, size).await
+}
+
+/// Reads the header of a frame, failing without reading further if the frame exceeds `max_size`.
+pub async fn async_read_size<R: AsyncRead + Unpin>(
+    mut stream: R,
+    max_size: u32,
+) -> std::io::Result<u32> {
     let mut size = [0u8; 4];
     stream.read_exact(&mut size).await?;
     let size = u32::from_be_bytes(size);
+    if size > max_size {
+        return Err(frame_too_large(size.into(), max_size));
+    }
+    Ok(size)
+}
+
+/// Reads the data of a frame whose header was read with [`async_read_size`].
+pub async fn async_read_data<R: AsyncRead + Unpin>(
+    mut stream: R,
+    size: u32,
+) -> std::io::Result<Vec<u8>> {
     let mut data = vec![0u8; size as usize];
     stream.read_exact(&mut data).await?;
     tracing::debug!("read {size} bytes");
     Ok(data)
 }
 
+/// Deserializes a frame, reporting malformed data as an error instead of panicking.
+pub fn deserialize<T: DeserializeOwned>(data: &[u8]) -> std::io::Result<T> {
+    bincode::deserialize(data)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
 pub fn serialize_and_write<T: Serialize, W: Write>(stream: W, data: &T) -> std::io::Result<()> {
     let serialized = bincode::serialize(data).unwrap();
     write(stream, &serialized)
@@ -64,7 +131,46 @@ pub fn deserialize_and_read<T: DeserializeOwned, R: Read>(stream: R) -> std::io:
 #[tracing::instrument(skip_all)]
 pub async fn async_deserialize_and_read<T: DeserializeOwned, R: AsyncRead + Unpin>(
     stream: R,
+    max_size: u32,
 ) -> std::io::Result<T> {
-    let data = async_read(stream).await?;
-    Ok(bincode::deserialize::<T>(&data).unwrap())
+    let data = async_read(stream, max_size).await?;
+    deserialize(&data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn frame_size_is_limited() {
+        let mut buffer = Vec::new();
+        async_serialize_and_write(&mut buffer, &vec![1u8; 100])
+            .await
+            .unwrap();
+        let size = buffer.len() as u32 - 4;
+
+        let read: Vec<u8> = async_deserialize_and_read(&buffer[..], size).await.unwrap();
+        assert_eq!(read, vec![1u8; 100]);
+
+        let err = async_read(&buffer[..], size - 1).await.unwrap_err();
+        let too_large = FrameTooLarge::from_io(&err).unwrap();
+        assert_eq!(too_large.size, u64::from(size));
+        assert_eq!(too_large.max_size, size - 1);
+
+        // only the header of a rejected frame is read
+        let mut stream = &buffer[..];
+        assert!(async_read_size(&mut stream, size - 1).await.is_err());
+        assert_eq!(stream.len(), size as usize);
+    }
+
+    #[tokio::test]
+    async fn malformed_frame_is_an_error() {
+        let mut buffer = Vec::new();
+        async_write(&mut buffer, &[1, 2]).await.unwrap();
+        let err = async_deserialize_and_read::<u64, _>(&buffer[..], 1024)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(FrameTooLarge::from_io(&err).is_none());
+    }
 }
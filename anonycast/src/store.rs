@@ -0,0 +1,890 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crypto::Sha256;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    document::{DocumentId, SignedDocument},
+    rle,
+};
+
+/// Minimum amount of bytes used by removed documents before [`LogStore`] rewrites its log.
+const LOG_COMPACTION_MIN_GARBAGE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct DocumentMetadata {
+    pub id: DocumentId,
+    pub topic: String,
+    pub chain: String,
+    /// Size of the stored document in bytes.
+    pub size: u64,
+}
+
+impl DocumentMetadata {
+    fn new(document: &SignedDocument, size: u64) -> Self {
+        Self {
+            id: document.content.id.clone(),
+            topic: document.content.topic.clone(),
+            chain: document.content.drand.chain.clone(),
+            size,
+        }
+    }
+}
+
+/// Storage backend for the documents published to a deaddrop.
+pub trait DocumentStore: Send + Sync {
+    fn insert(&mut self, document: SignedDocument) -> std::io::Result<()>;
+
+    /// Removes a document from the store, returns false if the document was not present.
+    fn remove(&mut self, id: &DocumentId) -> std::io::Result<bool>;
+
+    fn get(&self, id: &DocumentId) -> std::io::Result<Option<SignedDocument>>;
+
+    fn contains(&self, id: &DocumentId) -> bool;
+
+    /// Id of a stored document of `topic` with the content hash and sender tag of `id`, which
+    /// may have been published in another round.
+    fn find_content(&self, topic: &str, id: &DocumentId) -> Option<DocumentId>;
+
+    fn ids(&self) -> Vec<DocumentId>;
+
+    /// Ids of the documents published to `topic` in or after round `since_round`.
+    fn topic_ids(&self, topic: &str, since_round: u64) -> Vec<DocumentId>;
+
+    fn metadata(&self) -> Vec<DocumentMetadata>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Starts reclaiming the space used by removed documents, if worth it. The returned
+    /// compaction is run without access to the store, which stays usable in the meantime, and is
+    /// then applied with [`DocumentStore::finish_compaction`].
+    fn start_compaction(&self) -> Option<Compaction> {
+        None
+    }
+
+    fn finish_compaction(&mut self, _compaction: Compaction) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    documents: HashMap<DocumentId, DocumentMetadata>,
+    topics: HashMap<String, HashSet<DocumentId>>,
+    content_hashes: HashMap<(String, Sha256, Sha256), DocumentId>,
+}
+
+/// Content is only deduplicated between documents of the same sender, so that nobody can claim
+/// the content of another sender by publishing a copy first.
+fn content_key(topic: &str, id: &DocumentId) -> (String, Sha256, Sha256) {
+    (topic.to_string(), id.content_hash, id.sender_tag)
+}
+
+impl Index {
+    fn insert(&mut self, metadata: DocumentMetadata) {
+        match self.topics.get_mut(&metadata.topic) {
+            Some(ids) => {
+                ids.insert(metadata.id.clone());
+            }
+            None => {
+                self.topics
+                    .insert(metadata.topic.clone(), HashSet::from([metadata.id.clone()]));
+            }
+        }
+        self.content_hashes
+            .entry(content_key(&metadata.topic, &metadata.id))
+            .or_insert_with(|| metadata.id.clone());
+        self.documents.insert(metadata.id.clone(), metadata);
+    }
+
+    fn remove(&mut self, id: &DocumentId) -> Option<DocumentMetadata> {
+        let metadata = self.documents.remove(id)?;
+        if let Some(ids) = self.topics.get_mut(&metadata.topic) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.topics.remove(&metadata.topic);
+            }
+        }
+        let key = content_key(&metadata.topic, id);
+        if self.content_hashes.get(&key) == Some(id) {
+            self.content_hashes.remove(&key);
+        }
+        Some(metadata)
+    }
+
+    fn contains(&self, id: &DocumentId) -> bool {
+        self.documents.contains_key(id)
+    }
+
+    fn find_content(&self, topic: &str, id: &DocumentId) -> Option<DocumentId> {
+        self.content_hashes.get(&content_key(topic, id)).cloned()
+    }
+
+    fn ids(&self) -> Vec<DocumentId> {
+        self.documents.keys().cloned().collect()
+    }
+
+    fn topic_ids(&self, topic: &str, since_round: u64) -> Vec<DocumentId> {
+        match self.topics.get(topic) {
+            Some(ids) => ids
+                .iter()
+                .filter(|id| id.round >= since_round)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn metadata(&self) -> Vec<DocumentMetadata> {
+        self.documents.values().cloned().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.documents.len()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    documents: HashMap<DocumentId, SignedDocument>,
+    index: Index,
+}
+
+impl DocumentStore for MemoryStore {
+    fn insert(&mut self, document: SignedDocument) -> std::io::Result<()> {
+        let size = bincode::serialized_size(&document).map_err(std::io::Error::other)?;
+        self.index.insert(DocumentMetadata::new(&document, size));
+        self.documents.insert(document.content.id.clone(), document);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &DocumentId) -> std::io::Result<bool> {
+        self.index.remove(id);
+        Ok(self.documents.remove(id).is_some())
+    }
+
+    fn get(&self, id: &DocumentId) -> std::io::Result<Option<SignedDocument>> {
+        Ok(self.documents.get(id).cloned())
+    }
+
+    fn contains(&self, id: &DocumentId) -> bool {
+        self.index.contains(id)
+    }
+
+    fn find_content(&self, topic: &str, id: &DocumentId) -> Option<DocumentId> {
+        self.index.find_content(topic, id)
+    }
+
+    fn ids(&self) -> Vec<DocumentId> {
+        self.index.ids()
+    }
+
+    fn topic_ids(&self, topic: &str, since_round: u64) -> Vec<DocumentId> {
+        self.index.topic_ids(topic, since_round)
+    }
+
+    fn metadata(&self) -> Vec<DocumentMetadata> {
+        self.index.metadata()
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum LogRecord {
+    Insert(Box<SignedDocument>),
+    Remove(DocumentId),
+}
+
+/// Append-only log of documents on disk with an in-memory index of record offsets.
+///
+/// The index is rebuilt by scanning the log when the store is opened. A partially written
+/// record at the end of the log (e.g. after a crash) is truncated away. Removed documents are
+/// recorded in the log and their space is reclaimed by [`DocumentStore::start_compaction`].
+#[derive(Debug)]
+pub struct LogStore {
+    path: PathBuf,
+    file: Mutex<File>,
+    offsets: HashMap<DocumentId, u64>,
+    index: Index,
+    /// Bytes of the log used by records of documents still in the store.
+    live: u64,
+    end: u64,
+}
+
+impl LogStore {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut offsets = HashMap::new();
+        let mut index = Index::default();
+        let mut live = 0;
+        let mut end = 0;
+        let mut reader = BufReader::new(&file);
+        loop {
+            let data = match rle::read(&mut reader) {
+                Ok(data) => data,
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            };
+            let record = match bincode::deserialize::<LogRecord>(&data) {
+                Ok(record) => record,
+                Err(err) => {
+                    tracing::warn!("invalid record in document log at offset {end}: {err}");
+                    break;
+                }
+            };
+            let size = 4 + data.len() as u64;
+            match record {
+                LogRecord::Insert(document) => {
+                    if let Some(previous) = index.remove(&document.content.id) {
+                        live -= previous.size;
+                    }
+                    offsets.insert(document.content.id.clone(), end);
+                    index.insert(DocumentMetadata::new(&document, size));
+                    live += size;
+                }
+                LogRecord::Remove(id) => {
+                    offsets.remove(&id);
+                    if let Some(previous) = index.remove(&id) {
+                        live -= previous.size;
+                    }
+                }
+            }
+            end += size;
+        }
+        drop(reader);
+
+        if file.metadata()?.len() != end {
+            tracing::warn!("truncating document log {} to {end} bytes", path.display());
+            file.set_len(end)?;
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            offsets,
+            index,
+            live,
+            end,
+        })
+    }
+
+    fn append(&mut self, record: &LogRecord) -> std::io::Result<(u64, u64)> {
+        let offset = self.end;
+        let file = self.file.get_mut().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        let data = bincode::serialize(record).map_err(std::io::Error::other)?;
+        rle::write(&mut *file, &data)?;
+        let size = 4 + data.len() as u64;
+        self.end += size;
+        Ok((offset, size))
+    }
+
+    fn read_record(file: &mut File, offset: u64) -> std::io::Result<Vec<u8>> {
+        file.seek(SeekFrom::Start(offset))?;
+        rle::read(&mut *file)
+    }
+}
+
+impl DocumentStore for LogStore {
+    fn insert(&mut self, document: SignedDocument) -> std::io::Result<()> {
+        let (offset, size) = self.append(&LogRecord::Insert(Box::new(document.clone())))?;
+        if let Some(previous) = self.index.remove(&document.content.id) {
+            self.live -= previous.size;
+        }
+        self.offsets.insert(document.content.id.clone(), offset);
+        self.index.insert(DocumentMetadata::new(&document, size));
+        self.live += size;
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &DocumentId) -> std::io::Result<bool> {
+        if !self.index.contains(id) {
+            return Ok(false);
+        }
+        self.append(&LogRecord::Remove(id.clone()))?;
+        self.offsets.remove(id);
+        if let Some(previous) = self.index.remove(id) {
+            self.live -= previous.size;
+        }
+        Ok(true)
+    }
+
+    fn get(&self, id: &DocumentId) -> std::io::Result<Option<SignedDocument>> {
+        let offset = match self.offsets.get(id) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        let mut file = self.file.lock().unwrap();
+        let data = Self::read_record(&mut file, offset)?;
+        match bincode::deserialize::<LogRecord>(&data).map_err(std::io::Error::other)? {
+            LogRecord::Insert(document) => Ok(Some(*document)),
+            LogRecord::Remove(_) => Err(std::io::Error::other("document log index is corrupted")),
+        }
+    }
+
+    fn contains(&self, id: &DocumentId) -> bool {
+        self.index.contains(id)
+    }
+
+    fn find_content(&self, topic: &str, id: &DocumentId) -> Option<DocumentId> {
+        self.index.find_content(topic, id)
+    }
+
+    fn ids(&self) -> Vec<DocumentId> {
+        self.index.ids()
+    }
+
+    fn topic_ids(&self, topic: &str, since_round: u64) -> Vec<DocumentId> {
+        self.index.topic_ids(topic, since_round)
+    }
+
+    fn metadata(&self) -> Vec<DocumentMetadata> {
+        self.index.metadata()
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn start_compaction(&self) -> Option<Compaction> {
+        let garbage = self.end - self.live;
+        if garbage < LOG_COMPACTION_MIN_GARBAGE || garbage < self.live {
+            return None;
+        }
+        tracing::info!(
+            "compacting document log {}, reclaiming {garbage} bytes",
+            self.path.display()
+        );
+        Some(self.compaction())
+    }
+
+    fn finish_compaction(&mut self, compaction: Compaction) -> std::io::Result<()> {
+        if compaction.path != self.path || compaction.end > self.end {
+            return Err(std::io::Error::other(
+                "compaction was started on another document log",
+            ));
+        }
+        let Compaction {
+            compact_path,
+            end,
+            mut compacted_offsets,
+            mut compacted_end,
+            ..
+        } = compaction;
+
+        // records appended since the compaction started are copied over, and documents removed
+        // in the meantime are removed again from the compacted log
+        let removed = compacted_offsets
+            .keys()
+            .filter(|id| !self.offsets.contains_key(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        {
+            let file = self.file.get_mut().unwrap();
+            let compact_file = OpenOptions::new().append(true).open(&compact_path)?;
+            let mut writer = BufWriter::new(compact_file);
+            for id in removed {
+                compacted_offsets.remove(&id);
+                let data =
+                    bincode::serialize(&LogRecord::Remove(id)).map_err(std::io::Error::other)?;
+                rle::write(&mut writer, &data)?;
+                compacted_end += 4 + data.len() as u64;
+            }
+            for (id, offset) in self.offsets.iter().filter(|(_, offset)| **offset >= end) {
+                let data = Self::read_record(file, *offset)?;
+                rle::write(&mut writer, &data)?;
+                compacted_offsets.insert(id.clone(), compacted_end);
+                compacted_end += 4 + data.len() as u64;
+            }
+            writer
+                .into_inner()
+                .map_err(|err| err.into_error())?
+                .sync_all()?;
+        }
+        std::fs::rename(&compact_path, &self.path)?;
+
+        let file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.file = Mutex::new(file);
+        self.offsets = compacted_offsets;
+        self.end = compacted_end;
+        Ok(())
+    }
+}
+
+impl LogStore {
+    fn compaction(&self) -> Compaction {
+        Compaction {
+            path: self.path.clone(),
+            compact_path: self.path.with_extension("compact"),
+            offsets: self
+                .offsets
+                .iter()
+                .map(|(id, offset)| (id.clone(), *offset))
+                .collect(),
+            end: self.end,
+            compacted_offsets: HashMap::with_capacity(self.offsets.len()),
+            compacted_end: 0,
+        }
+    }
+}
+
+/// Rewrite of a [`LogStore`] with only the records of the documents it stored when the
+/// compaction started.
+#[derive(Debug)]
+pub struct Compaction {
+    path: PathBuf,
+    compact_path: PathBuf,
+    offsets: Vec<(DocumentId, u64)>,
+    /// End of the log when the compaction started.
+    end: u64,
+    compacted_offsets: HashMap<DocumentId, u64>,
+    compacted_end: u64,
+}
+
+impl Compaction {
+    /// Copies the records into the compacted log, reading the log through its own handle since
+    /// records before `end` are never modified.
+    pub fn run(&mut self) -> std::io::Result<()> {
+        let mut log = File::open(&self.path)?;
+        let mut writer = BufWriter::new(File::create(&self.compact_path)?);
+        for (id, offset) in self.offsets.iter() {
+            let data = LogStore::read_record(&mut log, *offset)?;
+            rle::write(&mut writer, &data)?;
+            self.compacted_offsets
+                .insert(id.clone(), self.compacted_end);
+            self.compacted_end += 4 + data.len() as u64;
+        }
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()
+    }
+}
+
+/// Chunk stored by a deaddrop, with the round it was uploaded in.
+#[derive(Debug, Clone)]
+pub struct ChunkMetadata {
+    pub hash: Sha256,
+    pub chain: String,
+    pub round: u64,
+    /// Size of the chunk in bytes.
+    pub size: u64,
+}
+
+/// Storage backend for the content-addressed chunks of large documents.
+pub trait ChunkStore: Send + Sync {
+    /// Stores a chunk uploaded in `round` of `chain` under its hash, which is returned. A chunk
+    /// that is already stored keeps its round.
+    fn insert(&mut self, chunk: &[u8], chain: &str, round: u64) -> std::io::Result<Sha256>;
+
+    /// Removes a chunk from the store, returns false if the chunk was not present.
+    fn remove(&mut self, hash: &Sha256) -> std::io::Result<bool>;
+
+    fn get(&self, hash: &Sha256) -> std::io::Result<Option<Vec<u8>>>;
+
+    fn contains(&self, hash: &Sha256) -> bool;
+
+    fn metadata(&self) -> Vec<ChunkMetadata>;
+
+    /// Total size of the stored chunks in bytes.
+    fn size(&self) -> u64;
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryChunkStore {
+    chunks: HashMap<Sha256, (ChunkMetadata, Vec<u8>)>,
+    size: u64,
+}
+
+impl ChunkStore for MemoryChunkStore {
+    fn insert(&mut self, chunk: &[u8], chain: &str, round: u64) -> std::io::Result<Sha256> {
+        let hash = crypto::sha256(chunk);
+        if !self.chunks.contains_key(&hash) {
+            let metadata = ChunkMetadata {
+                hash,
+                chain: chain.to_string(),
+                round,
+                size: chunk.len() as u64,
+            };
+            self.size += metadata.size;
+            self.chunks.insert(hash, (metadata, chunk.to_vec()));
+        }
+        Ok(hash)
+    }
+
+    fn remove(&mut self, hash: &Sha256) -> std::io::Result<bool> {
+        match self.chunks.remove(hash) {
+            Some((metadata, _)) => {
+                self.size -= metadata.size;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn get(&self, hash: &Sha256) -> std::io::Result<Option<Vec<u8>>> {
+        Ok(self.chunks.get(hash).map(|(_, chunk)| chunk.clone()))
+    }
+
+    fn contains(&self, hash: &Sha256) -> bool {
+        self.chunks.contains_key(hash)
+    }
+
+    fn metadata(&self) -> Vec<ChunkMetadata> {
+        self.chunks
+            .values()
+            .map(|(metadata, _)| metadata.clone())
+            .collect()
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// Header of a chunk file, followed by the chunk itself.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkHeader {
+    hash: Sha256,
+    chain: String,
+    round: u64,
+}
+
+/// Stores every chunk in its own file, named after the chunk's hash.
+#[derive(Debug)]
+pub struct DirChunkStore {
+    dir: PathBuf,
+    chunks: HashMap<Sha256, ChunkMetadata>,
+    size: u64,
+}
+
+impl DirChunkStore {
+    pub fn open(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let mut store = Self {
+            dir,
+            chunks: HashMap::new(),
+            size: 0,
+        };
+        for entry in std::fs::read_dir(&store.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                // left behind by an interrupted write
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+            let file_size = std::fs::metadata(&path)?.len();
+            let mut file = BufReader::new(File::open(&path)?);
+            let header: ChunkHeader =
+                bincode::deserialize_from(&mut file).map_err(std::io::Error::other)?;
+            let size = file_size - bincode::serialized_size(&header).unwrap();
+            store.size += size;
+            store.chunks.insert(
+                header.hash,
+                ChunkMetadata {
+                    hash: header.hash,
+                    chain: header.chain,
+                    round: header.round,
+                    size,
+                },
+            );
+        }
+        Ok(store)
+    }
+
+    fn path(&self, hash: &Sha256) -> PathBuf {
+        self.dir.join(hash.to_string())
+    }
+}
+
+impl ChunkStore for DirChunkStore {
+    fn insert(&mut self, chunk: &[u8], chain: &str, round: u64) -> std::io::Result<Sha256> {
+        let hash = crypto::sha256(chunk);
+        if self.chunks.contains_key(&hash) {
+            return Ok(hash);
+        }
+        let header = ChunkHeader {
+            hash,
+            chain: chain.to_string(),
+            round,
+        };
+        let mut data = bincode::serialize(&header).unwrap();
+        data.extend_from_slice(chunk);
+        // a partially written chunk must never be visible under its hash
+        let path = self.path(&hash);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &path)?;
+
+        self.size += chunk.len() as u64;
+        self.chunks.insert(
+            hash,
+            ChunkMetadata {
+                hash,
+                chain: header.chain,
+                round,
+                size: chunk.len() as u64,
+            },
+        );
+        Ok(hash)
+    }
+
+    fn remove(&mut self, hash: &Sha256) -> std::io::Result<bool> {
+        let Some(metadata) = self.chunks.get(hash) else {
+            return Ok(false);
+        };
+        std::fs::remove_file(self.path(hash))?;
+        self.size -= metadata.size;
+        self.chunks.remove(hash);
+        Ok(true)
+    }
+
+    fn get(&self, hash: &Sha256) -> std::io::Result<Option<Vec<u8>>> {
+        if !self.chunks.contains_key(hash) {
+            return Ok(None);
+        }
+        let data = std::fs::read(self.path(hash))?;
+        let mut chunk = data.as_slice();
+        let _: ChunkHeader =
+            bincode::deserialize_from(&mut chunk).map_err(std::io::Error::other)?;
+        Ok(Some(chunk.to_vec()))
+    }
+
+    fn contains(&self, hash: &Sha256) -> bool {
+        self.chunks.contains_key(hash)
+    }
+
+    fn metadata(&self) -> Vec<ChunkMetadata> {
+        self.chunks.values().cloned().collect()
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        document::{Document, DocumentDrand, DocumentKind},
+        protocol::Signed,
+        PuzzleAlgorithm,
+    };
+
+    fn create_document(key: &crypto::PrivateKey, data: &[u8]) -> SignedDocument {
+        create_topic_document(key, "topic", 1, data)
+    }
+
+    fn create_topic_document(
+        key: &crypto::PrivateKey,
+        topic: &str,
+        round: u64,
+        data: &[u8],
+    ) -> SignedDocument {
+        let drand = DocumentDrand {
+            chain: "chain".to_string(),
+            beacon: drand::Beacon {
+                round_number: round,
+                randomness: Default::default(),
+                signature: Default::default(),
+                previous_signature: Default::default(),
+            },
+            scheme: drand::SchemeId::UnchainedOnG1RFC9380,
+        };
+        let document = Document::plaintext(
+            topic,
+            DocumentKind::Inline,
+            data,
+            PuzzleAlgorithm::Sha256,
+            0,
+            crypto::sha256(b"key"),
+            drand,
+        );
+        Signed::sign(key, document)
+    }
+
+    fn check_topic_ids(store: &mut dyn DocumentStore) {
+        let (_, key) = crypto::generate();
+        let documents = [
+            create_topic_document(&key, "topic", 1, b"a"),
+            create_topic_document(&key, "topic", 5, b"b"),
+            create_topic_document(&key, "topic", 9, b"c"),
+            create_topic_document(&key, "other-topic", 5, b"d"),
+        ];
+        for document in documents.iter() {
+            store.insert(document.clone()).unwrap();
+        }
+        let topic_ids = |store: &dyn DocumentStore, topic, since_round| {
+            let mut ids = store.topic_ids(topic, since_round);
+            ids.sort_by_key(|id| id.round);
+            ids
+        };
+        let ids = |indices: &[usize]| {
+            indices
+                .iter()
+                .map(|i| documents[*i].content.id.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(topic_ids(store, "topic", 0), ids(&[0, 1, 2]));
+        assert_eq!(topic_ids(store, "topic", 5), ids(&[1, 2]));
+        assert_eq!(topic_ids(store, "topic", 6), ids(&[2]));
+        assert!(topic_ids(store, "topic", 10).is_empty());
+        assert_eq!(topic_ids(store, "other-topic", 0), ids(&[3]));
+        assert!(topic_ids(store, "missing-topic", 0).is_empty());
+
+        // removed documents leave the topic
+        store.remove(&documents[1].content.id).unwrap();
+        assert_eq!(topic_ids(store, "topic", 0), ids(&[0, 2]));
+    }
+
+    #[test]
+    fn memory_store_topic_ids() {
+        check_topic_ids(&mut MemoryStore::default());
+    }
+
+    #[test]
+    fn log_store_topic_ids() {
+        let path = std::env::temp_dir().join(format!(
+            "anonycast-log-store-topics-{}.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        check_topic_ids(&mut LogStore::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn log_store_reload() {
+        let path =
+            std::env::temp_dir().join(format!("anonycast-log-store-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (_, key) = crypto::generate();
+        let doc0 = create_document(&key, b"document 0");
+        let doc1 = create_document(&key, b"document 1");
+        {
+            let mut store = LogStore::open(&path).unwrap();
+            store.insert(doc0.clone()).unwrap();
+            store.insert(doc1.clone()).unwrap();
+        }
+
+        // simulate a crash in the middle of appending a record
+        {
+            use std::io::Write;
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&[0, 0, 1, 0, 42]).unwrap();
+        }
+
+        let mut store = LogStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        let stored = store.get(&doc1.content.id).unwrap().unwrap();
+        assert_eq!(stored.content.id, doc1.content.id);
+
+        let doc2 = create_document(&key, b"document 2");
+        store.insert(doc2.clone()).unwrap();
+        drop(store);
+
+        let store = LogStore::open(&path).unwrap();
+        assert_eq!(store.len(), 3);
+        assert!(store.contains(&doc0.content.id));
+        assert!(store.get(&doc2.content.id).unwrap().is_some());
+        assert_eq!(store.topic_ids("topic", 0).len(), 3);
+        assert!(store.topic_ids("other-topic", 0).is_empty());
+        assert!(store.topic_ids("topic", 2).is_empty());
+        drop(store);
+
+        let mut store = LogStore::open(&path).unwrap();
+        assert!(store.remove(&doc1.content.id).unwrap());
+        assert!(!store.remove(&doc1.content.id).unwrap());
+        drop(store);
+
+        let store = LogStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(!store.contains(&doc1.content.id));
+        assert!(store.get(&doc1.content.id).unwrap().is_none());
+        assert_eq!(store.topic_ids("topic", 0).len(), 2);
+        drop(store);
+
+        let mut store = LogStore::open(&path).unwrap();
+        let mut compaction = store.compaction();
+        compaction.run().unwrap();
+        // the store keeps changing while the compaction runs
+        let doc3 = create_document(&key, b"document 3");
+        store.insert(doc3.clone()).unwrap();
+        assert!(store.remove(&doc2.content.id).unwrap());
+        let size = std::fs::metadata(&path).unwrap().len();
+        store.finish_compaction(compaction).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < size);
+        assert!(store.get(&doc0.content.id).unwrap().is_some());
+        assert!(store.get(&doc3.content.id).unwrap().is_some());
+        assert!(store.get(&doc2.content.id).unwrap().is_none());
+        drop(store);
+
+        let store = LogStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(store.get(&doc3.content.id).unwrap().is_some());
+        assert!(!store.contains(&doc2.content.id));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dir_chunk_store_reload() {
+        let dir =
+            std::env::temp_dir().join(format!("anonycast-chunk-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut store = DirChunkStore::open(&dir).unwrap();
+        let hash0 = store.insert(b"chunk 0", "chain", 1).unwrap();
+        let hash1 = store.insert(b"chunk 1", "chain", 2).unwrap();
+        // storing a chunk again keeps its round
+        store.insert(b"chunk 1", "chain", 3).unwrap();
+        assert_eq!(store.size(), 14);
+        drop(store);
+
+        let mut store = DirChunkStore::open(&dir).unwrap();
+        assert_eq!(store.size(), 14);
+        assert_eq!(store.get(&hash1).unwrap().unwrap(), b"chunk 1");
+        let metadata = store
+            .metadata()
+            .into_iter()
+            .find(|chunk| chunk.hash == hash1)
+            .unwrap();
+        assert_eq!((metadata.chain.as_str(), metadata.round), ("chain", 2));
+
+        assert!(store.remove(&hash0).unwrap());
+        assert!(!store.remove(&hash0).unwrap());
+        assert_eq!(store.size(), 7);
+        drop(store);
+
+        let store = DirChunkStore::open(&dir).unwrap();
+        assert!(!store.contains(&hash0));
+        assert!(store.get(&hash0).unwrap().is_none());
+        assert_eq!(store.metadata().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -56,11 +56,33 @@ pub fn symmetric_encrypt(key: &SymmetricKey, data: &[u8]) -> SymmetricData {
     }
 }
 
+/// Encrypts with a caller provided nonce, which must never be reused with the same key.
+pub fn symmetric_encrypt_with_nonce(
+    key: &SymmetricKey,
+    nonce: &[u8; 12],
+    data: &[u8],
+) -> SymmetricData {
+    let key: Key<Aes256Gcm> = key.0.into();
+    let cipher = Aes256Gcm::new(&key);
+    let ciphertext = cipher.encrypt(From::from(nonce), data).unwrap();
+    SymmetricData {
+        nonce: nonce.to_vec(),
+        data: ciphertext,
+    }
+}
+
 pub fn symmetric_decrypt(key: &SymmetricKey, data: &SymmetricData) -> Vec<u8> {
+    symmetric_try_decrypt(key, data).unwrap()
+}
+
+/// Decrypts data, returning `None` if it was not encrypted with `key` or was tampered with.
+pub fn symmetric_try_decrypt(key: &SymmetricKey, data: &SymmetricData) -> Option<Vec<u8>> {
+    if data.nonce.len() != 12 {
+        return None;
+    }
     let key: Key<Aes256Gcm> = key.0.into();
     let cipher = Aes256Gcm::new(&key);
-    let plaintext = cipher
+    cipher
         .decrypt(From::from(data.nonce.as_slice()), data.data.as_slice())
-        .unwrap();
-    plaintext
+        .ok()
 }
@@ -1,14 +1,18 @@
+use std::collections::HashSet;
+
 use curve25519_dalek::{ristretto::CompressedRistretto, RistrettoPoint, Scalar};
 use nazgul::{
     blsag::BLSAG,
     traits::{Sign, Verify},
 };
-use rand::rngs::OsRng;
+use rand::{rngs::OsRng, Rng};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use sha2::Sha512;
+use sha2::{Digest, Sha512};
+
+use crate::random_bytes;
 
-// TODO: ???
-const SECRET_INDEX: usize = 1;
+/// Domain separation for the scalar derived from a signature context.
+const CONTEXT_DOMAIN: &[u8] = b"anonycast ring signature context";
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Ring(Vec<RingPublicKey>);
@@ -140,11 +144,12 @@ struct RingSignatureSerdeProxy {
     responses: Vec<[u8; 32]>,
     ring: Vec<[u8; 32]>,
     key_image: [u8; 32],
+    context: Vec<u8>,
 }
 
 impl From<RingSignatureSerdeProxy> for RingSignature {
     fn from(value: RingSignatureSerdeProxy) -> Self {
-        Self(BLSAG {
+        let signature = BLSAG {
             challenge: Scalar::from_bytes_mod_order(value.challenge),
             responses: value
                 .responses
@@ -166,43 +171,77 @@ impl From<RingSignatureSerdeProxy> for RingSignature {
                 .unwrap()
                 .decompress()
                 .unwrap(),
-        })
+        };
+        Self {
+            signature,
+            context: value.context,
+        }
     }
 }
 
 impl From<RingSignature> for RingSignatureSerdeProxy {
     fn from(value: RingSignature) -> Self {
         Self {
-            challenge: value.0.challenge.to_bytes(),
+            challenge: value.signature.challenge.to_bytes(),
             responses: value
-                .0
+                .signature
                 .responses
                 .iter()
                 .map(|s| s.to_bytes())
                 .collect::<Vec<_>>(),
             ring: value
-                .0
+                .signature
                 .ring
                 .iter()
                 .map(|r| r.compress().to_bytes())
                 .collect::<Vec<_>>(),
-            key_image: value.0.key_image.compress().to_bytes(),
+            key_image: value.signature.key_image.compress().to_bytes(),
+            context: value.context,
         }
     }
 }
 
+/// Links the ring signatures made by the same member with the same context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct KeyImage([u8; 32]);
+
+impl std::fmt::Display for KeyImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hex = hex::encode(self.0);
+        f.write_str(&hex)
+    }
+}
+
+/// bLSAG signature made over a ring blinded by the signature context. The key image of a member
+/// is then specific to the context, so signatures only link when they share a context.
 #[derive(Serialize, Deserialize)]
 #[serde(from = "RingSignatureSerdeProxy", into = "RingSignatureSerdeProxy")]
-pub struct RingSignature(BLSAG);
+pub struct RingSignature {
+    signature: BLSAG,
+    context: Vec<u8>,
+}
+
+impl RingSignature {
+    pub fn context(&self) -> &[u8] {
+        &self.context
+    }
+
+    pub fn key_image(&self) -> KeyImage {
+        KeyImage(self.signature.key_image.compress().to_bytes())
+    }
+}
 
 impl Clone for RingSignature {
     fn clone(&self) -> Self {
-        Self(BLSAG {
-            challenge: self.0.challenge,
-            responses: self.0.responses.clone(),
-            ring: self.0.ring.clone(),
-            key_image: self.0.key_image,
-        })
+        Self {
+            signature: BLSAG {
+                challenge: self.signature.challenge,
+                responses: self.signature.responses.clone(),
+                ring: self.signature.ring.clone(),
+                key_image: self.signature.key_image,
+            },
+            context: self.context.clone(),
+        }
     }
 }
 
@@ -221,37 +260,80 @@ pub fn ring_generate() -> (RingPublicKey, RingPrivateKey) {
     (public_key, private_key)
 }
 
-pub fn ring_sign(key: &RingPrivateKey, ring: &Ring, data: &[u8]) -> RingSignature {
-    let signer_pk = key.public_key().0;
-    let ring = ring
-        .0
-        .clone()
+/// Orders ring members by their compressed encoding, dropping duplicates, so the ordering does
+/// not depend on who lists the ring.
+fn canonical_ring(ring: impl IntoIterator<Item = RistrettoPoint>) -> Vec<RistrettoPoint> {
+    let mut ring = ring
         .into_iter()
-        .map(|pk| pk.0)
-        .filter(|pk| pk != &signer_pk)
-        .collect();
+        .map(|pk| (pk.compress().to_bytes(), pk))
+        .collect::<Vec<_>>();
+    ring.sort_by_key(|a| a.0);
+    ring.dedup_by(|a, b| a.0 == b.0);
+    ring.into_iter().map(|(_, pk)| pk).collect()
+}
+
+/// Scalar every key is multiplied by when signing with `context`.
+fn context_factor(context: &[u8]) -> Scalar {
+    let mut hasher = Sha512::default();
+    hasher.update(CONTEXT_DOMAIN);
+    hasher.update(context);
+    Scalar::from_hash(hasher)
+}
+
+/// Signs `data` as a member of `ring` with a random context, so the signature cannot be linked
+/// to any other.
+pub fn ring_sign(key: &RingPrivateKey, ring: &Ring, data: &[u8]) -> RingSignature {
+    ring_sign_linkable(key, ring, &random_bytes::<32>(), data)
+}
+
+/// Signs `data` as a member of `ring`, which is extended with the signer if it is not listed.
+/// Signatures made by the same member with the same `context` have the same key image.
+///
+/// The ring embedded in the signature is the canonical ring rotated by a random offset, so the
+/// signer is at a uniformly random index and the ordering is the same whoever signs.
+pub fn ring_sign_linkable(
+    key: &RingPrivateKey,
+    ring: &Ring,
+    context: &[u8],
+    data: &[u8],
+) -> RingSignature {
+    let factor = context_factor(context);
+    let key = key.0 * factor;
+    let signer_pk = key * curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+    let mut ring = canonical_ring(ring.0.iter().map(|pk| pk.0 * factor).chain([signer_pk]));
+    let offset = OsRng.gen_range(0..ring.len());
+    ring.rotate_left(offset);
+    let secret_index = ring
+        .iter()
+        .position(|pk| pk == &signer_pk)
+        .expect("signer was added to the ring");
+    ring.remove(secret_index);
     let data = data.to_owned();
-    RingSignature(BLSAG::sign::<Sha512, OsRng>(
-        key.0,
-        ring,
-        SECRET_INDEX,
-        &data,
-    ))
+    RingSignature {
+        signature: BLSAG::sign::<Sha512, OsRng>(key, ring, secret_index, &data),
+        context: context.to_vec(),
+    }
 }
 
+/// Verifies that `signature` was made by a member of `ring`, regardless of the order in which the
+/// ring lists its members.
 pub fn ring_verify(ring: &Ring, data: &[u8], signature: &RingSignature) -> bool {
-    if ring.0.len() != signature.0.ring.len()
-        || !ring
-            .0
-            .iter()
-            .map(|pk| pk.0)
-            .all(|pk| signature.0.ring.contains(&pk))
-    {
+    let factor = context_factor(&signature.context);
+    let signature_ring = signature.signature.ring.iter();
+    let signature_members = signature_ring
+        .map(|pk| pk.compress().to_bytes())
+        .collect::<HashSet<_>>();
+    let members = ring
+        .0
+        .iter()
+        .map(|pk| (pk.0 * factor).compress().to_bytes())
+        .collect::<HashSet<_>>();
+    if signature_members.len() != signature.signature.ring.len() || signature_members != members {
         return false;
     }
     let data = data.to_owned();
     let signature = signature.clone();
-    BLSAG::verify::<Sha512>(signature.0, &data)
+    BLSAG::verify::<Sha512>(signature.signature, &data)
 }
 
 #[cfg(test)]
@@ -293,6 +375,87 @@ mod test {
         }
     }
 
+    #[test]
+    fn ring_order_independent() {
+        let keys = (0..4).map(|_| ring_generate()).collect::<Vec<_>>();
+        let ring = Ring::from(keys.iter().map(|(pk, _)| *pk).collect::<Vec<_>>());
+        let mut reversed = Vec::from(ring.clone());
+        reversed.reverse();
+        let reversed = Ring::from(reversed);
+
+        let sig = ring_sign(&keys[2].1, &ring, DATA_0);
+        assert!(ring_verify(&reversed, DATA_0, &sig));
+        let sig = ring_sign(&keys[2].1, &reversed, DATA_0);
+        assert!(ring_verify(&ring, DATA_0, &sig));
+
+        // duplicated members do not count towards the ring
+        let mut duplicated = Vec::from(ring.clone());
+        duplicated.push(keys[0].0);
+        let duplicated = Ring::from(duplicated);
+        let sig = ring_sign(&keys[1].1, &duplicated, DATA_0);
+        assert!(ring_verify(&ring, DATA_0, &sig));
+    }
+
+    #[test]
+    fn signer_position_indistinguishable() {
+        const MEMBERS: usize = 4;
+        const SIGNATURES: usize = 80;
+
+        let keys = (0..MEMBERS).map(|_| ring_generate()).collect::<Vec<_>>();
+        let ring = Ring::from(keys.iter().map(|(pk, _)| *pk).collect::<Vec<_>>());
+        let factor = context_factor(DATA_1);
+        let canonical = canonical_ring(ring.0.iter().map(|pk| pk.0 * factor));
+
+        for (signer_pk, signer) in keys.iter().take(2) {
+            let mut positions = [0usize; MEMBERS];
+            for _ in 0..SIGNATURES {
+                let sig = ring_sign_linkable(signer, &ring, DATA_1, DATA_0);
+                let sig_ring = &sig.signature.ring;
+                // every signer produces a rotation of the same canonical ring
+                let offset = canonical.iter().position(|pk| pk == &sig_ring[0]).unwrap();
+                let mut rotated = canonical.clone();
+                rotated.rotate_left(offset);
+                assert_eq!(&rotated, sig_ring);
+
+                let signer_pk = signer_pk.0 * factor;
+                let position = sig_ring.iter().position(|pk| pk == &signer_pk).unwrap();
+                positions[position] += 1;
+            }
+            // each position is expected SIGNATURES / MEMBERS = 20 times
+            for count in positions {
+                assert!((4..=36).contains(&count), "{positions:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn key_image_context() {
+        let (pub0, priv0) = ring_generate();
+        let (pub1, priv1) = ring_generate();
+        let ring = Ring::from(vec![pub0, pub1]);
+
+        let sig0 = ring_sign_linkable(&priv0, &ring, b"epoch 1", DATA_0);
+        let sig1 = ring_sign_linkable(&priv0, &ring, b"epoch 1", DATA_1);
+        assert!(ring_verify(&ring, DATA_0, &sig0));
+        assert!(ring_verify(&ring, DATA_1, &sig1));
+        assert_eq!(sig0.key_image(), sig1.key_image());
+
+        // other members and other contexts do not link
+        let sig2 = ring_sign_linkable(&priv1, &ring, b"epoch 1", DATA_0);
+        let sig3 = ring_sign_linkable(&priv0, &ring, b"epoch 2", DATA_0);
+        assert_ne!(sig0.key_image(), sig2.key_image());
+        assert_ne!(sig0.key_image(), sig3.key_image());
+        assert_ne!(
+            ring_sign(&priv0, &ring, DATA_0).key_image(),
+            ring_sign(&priv0, &ring, DATA_0).key_image()
+        );
+
+        // the context cannot be swapped without invalidating the signature
+        let mut forged = sig0.clone();
+        forged.context = b"epoch 2".to_vec();
+        assert!(!ring_verify(&ring, DATA_0, &forged));
+    }
+
     #[test]
     fn from_str() {
         let (pub0, priv0) = ring_generate();
@@ -12,31 +12,62 @@ impl std::fmt::Display for InvalidPrivateKey {
 
 impl std::error::Error for InvalidPrivateKey {}
 
-#[derive(Debug)]
-pub struct PrivateKey(ring::signature::Ed25519KeyPair);
+/// Ed25519 key pair, kept with its PKCS#8 encoding since ring cannot export it.
+pub struct PrivateKey {
+    pkcs8: Vec<u8>,
+    key_pair: Ed25519KeyPair,
+}
 
 impl PrivateKey {
+    fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, InvalidPrivateKey> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|_| InvalidPrivateKey)?;
+        Ok(Self {
+            pkcs8: pkcs8.to_vec(),
+            key_pair,
+        })
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
-        let rand = ring::rand::SystemRandom::new();
-        Ed25519KeyPair::generate_pkcs8(&rand)
-            .unwrap()
-            .as_ref()
-            .to_vec()
+        self.pkcs8.clone()
     }
 
     pub fn public_key(&self) -> PublicKey {
         PublicKey(
-            TryFrom::try_from(self.0.public_key().as_ref())
+            TryFrom::try_from(self.key_pair.public_key().as_ref())
                 .expect("invalid ed25519 public key size"),
         )
     }
 }
 
+impl std::fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PrivateKey").field(&self.public_key()).finish()
+    }
+}
+
 impl Clone for PrivateKey {
     fn clone(&self) -> Self {
-        // UNSAFE: the type is just a bag of bytes that could implement clone/copy but for some
-        // reason doesn't
-        Self(unsafe { std::ptr::read(std::ptr::addr_of!(self.0)) })
+        Self::from_pkcs8(&self.pkcs8).expect("pkcs8 document was already parsed")
+    }
+}
+
+impl Serialize for PrivateKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.pkcs8.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PrivateKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let pkcs8 = <Vec<u8> as Deserialize<'de>>::deserialize(deserializer)?;
+        Self::from_pkcs8(&pkcs8).map_err(|_| D::Error::custom("invalid ed25519 private key"))
     }
 }
 
@@ -52,9 +83,7 @@ impl std::str::FromStr for PrivateKey {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let pkcs8 = hex::decode(s).map_err(|_| InvalidPrivateKey)?;
-        Ed25519KeyPair::from_pkcs8(&pkcs8)
-            .map_err(|_| InvalidPrivateKey)
-            .map(PrivateKey)
+        Self::from_pkcs8(&pkcs8)
     }
 }
 
@@ -118,89 +147,32 @@ impl std::str::FromStr for PublicKey {
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct Signature(ring::signature::Signature);
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature(Vec<u8>);
 
 impl std::fmt::Debug for Signature {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let hex = hex::encode(self.0.as_ref());
+        let hex = hex::encode(self.0.as_slice());
         f.debug_tuple("Signature").field(&hex).finish()
     }
 }
 
-impl std::cmp::PartialEq for Signature {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.as_ref() == other.0.as_ref()
-    }
-}
-
-impl std::cmp::Eq for Signature {}
-
-impl Serialize for Signature {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        self.as_bytes().serialize(serializer)
-    }
-}
-
-impl<'de> Deserialize<'de> for Signature {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        use serde::de::Error;
-        let data = <Vec<u8> as Deserialize<'de>>::deserialize(deserializer)?;
-
-        let sig = unsafe {
-            type S = ring::signature::Signature;
-
-            let sig_size = std::mem::size_of::<S>();
-            let sig_value_max_size = sig_size - std::mem::size_of::<usize>();
-
-            if data.len() > sig_value_max_size {
-                return Err(D::Error::custom("invalid signature size"));
-            }
-
-            let mut sig = std::mem::zeroed::<S>();
-            let sig_value: &[u8] = sig.as_ref();
-            let sig_value_ptr = sig_value.as_ptr() as *mut u8;
-            // this is fragile
-            // this command reports the len field comes first
-            // cargo +nightly rustc -- -Zprint-type-sizes
-            let sig_len_ptr = ((&mut sig) as *mut S) as *mut usize;
-            let sig_value_patched = std::slice::from_raw_parts_mut(sig_value_ptr, data.len());
-            sig_value_patched.copy_from_slice(&data);
-            *sig_len_ptr = data.len();
-
-            assert_eq!(sig.as_ref().len(), data.len());
-            assert_eq!(sig.as_ref(), &data);
-
-            sig
-        };
-
-        Ok(Self(sig))
-    }
-}
-
 impl Signature {
     pub fn as_bytes(&self) -> &[u8] {
-        self.0.as_ref()
+        self.0.as_slice()
     }
 }
 
 pub fn generate() -> (PublicKey, PrivateKey) {
     let rng = ring::rand::SystemRandom::new();
-    let key_pair = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
-    let key_pair = ring::signature::Ed25519KeyPair::from_pkcs8(key_pair.as_ref()).unwrap();
-    let private_key = PrivateKey(key_pair);
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    let private_key = PrivateKey::from_pkcs8(pkcs8.as_ref()).unwrap();
     let public_key = private_key.public_key();
     (public_key, private_key)
 }
 
 pub fn sign(key: &PrivateKey, data: &[u8]) -> Signature {
-    Signature(key.0.sign(data))
+    Signature(key.key_pair.sign(data).as_ref().to_vec())
 }
 
 pub fn verify(key: &PublicKey, data: &[u8], sig: &Signature) -> bool {
@@ -0,0 +1,244 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{ed25519, rsa, x25519};
+
+#[derive(Debug)]
+pub struct InvalidAlgorithm;
+
+impl std::fmt::Display for InvalidAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("invalid algorithm")
+    }
+}
+
+impl std::error::Error for InvalidAlgorithm {}
+
+/// Algorithms used by an asymmetric key to sign and to wrap symmetric keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Algorithm {
+    /// RSA-2048 with PKCS#1 v1.5 signatures and encryption, kept for existing keys.
+    LegacyRsa,
+    /// Ed25519 signatures and X25519 key encapsulation with HKDF-SHA256 and AES-256-GCM.
+    #[default]
+    Ed25519X25519,
+}
+
+impl std::fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Algorithm::LegacyRsa => "legacy-rsa",
+            Algorithm::Ed25519X25519 => "ed25519-x25519",
+        })
+    }
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = InvalidAlgorithm;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "legacy-rsa" => Ok(Self::LegacyRsa),
+            "ed25519-x25519" => Ok(Self::Ed25519X25519),
+            _ => Err(InvalidAlgorithm),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidPrivateKey;
+
+impl std::fmt::Display for InvalidPrivateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("invalid private key")
+    }
+}
+
+impl std::error::Error for InvalidPrivateKey {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PrivateKey {
+    LegacyRsa(rsa::PrivateKey),
+    Ed25519X25519 {
+        signing: ed25519::PrivateKey,
+        encryption: x25519::PrivateKey,
+    },
+}
+
+impl PrivateKey {
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            PrivateKey::LegacyRsa(_) => Algorithm::LegacyRsa,
+            PrivateKey::Ed25519X25519 { .. } => Algorithm::Ed25519X25519,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        match self {
+            PrivateKey::LegacyRsa(key) => PublicKey::LegacyRsa(key.public_key()),
+            PrivateKey::Ed25519X25519 {
+                signing,
+                encryption,
+            } => PublicKey::Ed25519X25519 {
+                signing: signing.public_key(),
+                encryption: encryption.public_key(),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for PrivateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hex = hex::encode(self.to_bytes());
+        f.write_str(&hex)
+    }
+}
+
+impl std::str::FromStr for PrivateKey {
+    type Err = InvalidPrivateKey;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|_| InvalidPrivateKey)?;
+        if let Ok(key) = bincode::deserialize(&bytes) {
+            return Ok(key);
+        }
+        // keys generated before algorithms were versioned are bare rsa keys
+        s.parse::<rsa::PrivateKey>()
+            .map(PrivateKey::LegacyRsa)
+            .map_err(|_| InvalidPrivateKey)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidPublicKey;
+
+impl std::fmt::Display for InvalidPublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("invalid public key")
+    }
+}
+
+impl std::error::Error for InvalidPublicKey {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PublicKey {
+    LegacyRsa(rsa::PublicKey),
+    Ed25519X25519 {
+        signing: ed25519::PublicKey,
+        encryption: x25519::PublicKey,
+    },
+}
+
+impl PublicKey {
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            PublicKey::LegacyRsa(_) => Algorithm::LegacyRsa,
+            PublicKey::Ed25519X25519 { .. } => Algorithm::Ed25519X25519,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+}
+
+impl std::fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hex = hex::encode(self.to_bytes());
+        f.write_str(&hex)
+    }
+}
+
+impl std::str::FromStr for PublicKey {
+    type Err = InvalidPublicKey;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|_| InvalidPublicKey)?;
+        if let Ok(key) = bincode::deserialize(&bytes) {
+            return Ok(key);
+        }
+        s.parse::<rsa::PublicKey>()
+            .map(PublicKey::LegacyRsa)
+            .map_err(|_| InvalidPublicKey)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Signature {
+    LegacyRsa(rsa::Signature),
+    Ed25519(ed25519::Signature),
+}
+
+impl Signature {
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            Signature::LegacyRsa(_) => Algorithm::LegacyRsa,
+            Signature::Ed25519(_) => Algorithm::Ed25519X25519,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Signature::LegacyRsa(signature) => signature.as_bytes(),
+            Signature::Ed25519(signature) => signature.as_bytes(),
+        }
+    }
+}
+
+/// Generates a key pair using the default algorithm.
+pub fn generate() -> (PublicKey, PrivateKey) {
+    generate_with(Algorithm::default())
+}
+
+pub fn generate_with(algorithm: Algorithm) -> (PublicKey, PrivateKey) {
+    let private_key = match algorithm {
+        Algorithm::LegacyRsa => PrivateKey::LegacyRsa(rsa::generate().1),
+        Algorithm::Ed25519X25519 => PrivateKey::Ed25519X25519 {
+            signing: ed25519::generate().1,
+            encryption: x25519::generate().1,
+        },
+    };
+    (private_key.public_key(), private_key)
+}
+
+pub fn sign(key: &PrivateKey, data: &[u8]) -> Signature {
+    match key {
+        PrivateKey::LegacyRsa(key) => Signature::LegacyRsa(rsa::sign(key, data)),
+        PrivateKey::Ed25519X25519 { signing, .. } => {
+            Signature::Ed25519(ed25519::sign(signing, data))
+        }
+    }
+}
+
+/// Verifies a signature, which must have been made with the algorithm of `key`.
+pub fn verify(key: &PublicKey, data: &[u8], sig: &Signature) -> bool {
+    match (key, sig) {
+        (PublicKey::LegacyRsa(key), Signature::LegacyRsa(sig)) => rsa::verify(key, data, sig),
+        (PublicKey::Ed25519X25519 { signing, .. }, Signature::Ed25519(sig)) => {
+            ed25519::verify(signing, data, sig)
+        }
+        _ => false,
+    }
+}
+
+/// Encrypts a small payload, such as a symmetric key, to `key`. Fails for keys that cannot be
+/// encrypted to securely.
+pub fn encrypt(key: &PublicKey, data: &[u8]) -> Result<Vec<u8>, InvalidPublicKey> {
+    match key {
+        PublicKey::LegacyRsa(key) => Ok(rsa::encrypt(key, data)),
+        PublicKey::Ed25519X25519 { encryption, .. } => {
+            x25519::seal(encryption, data).ok_or(InvalidPublicKey)
+        }
+    }
+}
+
+/// Decrypts data encrypted with [`encrypt`], returning `None` if it was not encrypted to `key`.
+pub fn decrypt(key: &PrivateKey, data: &[u8]) -> Option<Vec<u8>> {
+    match key {
+        PrivateKey::LegacyRsa(key) => rsa::decrypt(key, data),
+        PrivateKey::Ed25519X25519 { encryption, .. } => x25519::open(encryption, data),
+    }
+}
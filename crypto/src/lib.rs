@@ -1,13 +1,16 @@
 mod aes;
 mod blsag;
 mod ed25519;
+mod keys;
+mod random;
 mod rsa;
 mod sha256;
+mod x25519;
 
 pub use aes::*;
 pub use blsag::*;
-// pub use ed25519::*;
-pub use rsa::*;
+pub use keys::*;
+pub use random::*;
 pub use sha256::*;
 
 #[cfg(test)]
@@ -16,11 +19,48 @@ mod test {
 
     #[test]
     fn test_encrypt_decrypt() {
-        let (pubkey, privkey) = generate();
+        for algorithm in [Algorithm::LegacyRsa, Algorithm::Ed25519X25519] {
+            let (pubkey, privkey) = generate_with(algorithm);
+            let (_, other_privkey) = generate_with(algorithm);
+            let data = b"hello";
+            let encrypted = encrypt(&pubkey, data).unwrap();
+            let decrypted = decrypt(&privkey, &encrypted).unwrap();
+            assert_eq!(&data[..], decrypted.as_slice());
+            assert_ne!(decrypt(&other_privkey, &encrypted).as_deref(), Some(&data[..]));
+        }
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let (rsa_pubkey, rsa_privkey) = generate_with(Algorithm::LegacyRsa);
+        let (pubkey, privkey) = generate_with(Algorithm::Ed25519X25519);
         let data = b"hello";
-        let encrypted = encrypt(&pubkey, data);
-        let decrypted = decrypt(&privkey, &encrypted);
-        assert_eq!(&data[..], decrypted.as_slice());
+
+        let signature = sign(&privkey, data);
+        assert_eq!(signature.algorithm(), Algorithm::Ed25519X25519);
+        assert!(verify(&pubkey, data, &signature));
+        assert!(!verify(&pubkey, b"world", &signature));
+        assert!(!verify(&rsa_pubkey, data, &signature));
+
+        let signature = sign(&rsa_privkey, data);
+        assert!(verify(&rsa_pubkey, data, &signature));
+        assert!(!verify(&pubkey, data, &signature));
+    }
+
+    #[test]
+    fn test_key_from_str() {
+        let (pubkey, privkey) = generate();
+        let parsed = privkey.to_string().parse::<PrivateKey>().unwrap();
+        assert_eq!(parsed.public_key(), pubkey);
+        assert_eq!(pubkey.to_string().parse::<PublicKey>().unwrap(), pubkey);
+
+        // bare rsa keys are still accepted
+        let (rsa_pubkey, rsa_privkey) = rsa::generate();
+        let parsed = rsa_privkey.to_string().parse::<PrivateKey>().unwrap();
+        assert_eq!(parsed.algorithm(), Algorithm::LegacyRsa);
+        assert_eq!(parsed.public_key(), PublicKey::LegacyRsa(rsa_pubkey.clone()));
+        let parsed = rsa_pubkey.to_string().parse::<PublicKey>().unwrap();
+        assert_eq!(parsed, PublicKey::LegacyRsa(rsa_pubkey));
     }
 
     #[test]
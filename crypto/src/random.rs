@@ -0,0 +1,7 @@
+use rand::{rngs::OsRng, RngCore};
+
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
@@ -38,8 +38,10 @@ impl std::str::FromStr for PrivateKey {
     type Err = InvalidPrivateKey;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let hex = hex::decode(s).unwrap();
-        Ok(Self(bincode::deserialize(&hex).unwrap()))
+        let hex = hex::decode(s).map_err(|_| InvalidPrivateKey)?;
+        Ok(Self(
+            bincode::deserialize(&hex).map_err(|_| InvalidPrivateKey)?,
+        ))
     }
 }
 
@@ -135,6 +137,7 @@ pub fn encrypt(key: &PublicKey, data: &[u8]) -> Vec<u8> {
     let mut rng = rand::thread_rng();
     key.0.encrypt(&mut rng, Pkcs1v15Encrypt, data).unwrap()
 }
-pub fn decrypt(key: &PrivateKey, data: &[u8]) -> Vec<u8> {
-    key.0.decrypt(Pkcs1v15Encrypt, data).unwrap()
+
+pub fn decrypt(key: &PrivateKey, data: &[u8]) -> Option<Vec<u8>> {
+    key.0.decrypt(Pkcs1v15Encrypt, data).ok()
 }
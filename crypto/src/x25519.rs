@@ -0,0 +1,108 @@
+use curve25519_dalek::MontgomeryPoint;
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    random_bytes, symmetric_encrypt_with_nonce, symmetric_try_decrypt, SymmetricData,
+    SymmetricKey,
+};
+
+/// Domain separation for the keys derived from a shared secret.
+const KDF_INFO: &[u8] = b"anonycast x25519-hkdf-sha256-aes256gcm v1";
+
+/// Every derived key encrypts a single message, so the nonce can be fixed.
+const NONCE: [u8; 12] = [0; 12];
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PrivateKey([u8; 32]);
+
+impl PrivateKey {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(MontgomeryPoint::mul_base_clamped(self.0).to_bytes())
+    }
+}
+
+impl std::fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PrivateKey").field(&self.public_key()).finish()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hex = hex::encode(self.0);
+        f.debug_tuple("PublicKey").field(&hex).finish()
+    }
+}
+
+pub fn generate() -> (PublicKey, PrivateKey) {
+    let private_key = PrivateKey(random_bytes());
+    let public_key = private_key.public_key();
+    (public_key, private_key)
+}
+
+/// Derives the key encrypting a message from the shared secret, bound to both public keys.
+/// Returns `None` if the shared secret is zero, which happens for low order public keys.
+fn derive_key(
+    shared: MontgomeryPoint,
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> Option<SymmetricKey> {
+    if shared.to_bytes() == [0; 32] {
+        return None;
+    }
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(&ephemeral.0);
+    salt[32..].copy_from_slice(&recipient.0);
+    let hkdf = Hkdf::<sha2::Sha256>::new(Some(&salt), shared.as_bytes());
+    let mut key = [0u8; 32];
+    hkdf.expand(KDF_INFO, &mut key)
+        .expect("32 bytes is a valid hkdf output length");
+    SymmetricKey::try_from(&key[..]).ok()
+}
+
+/// Encrypts `data` to `key` with an ephemeral key pair, returning the ephemeral public key
+/// followed by the ciphertext. Returns `None` if `key` is a low order point.
+pub fn seal(key: &PublicKey, data: &[u8]) -> Option<Vec<u8>> {
+    let (ephemeral_public, ephemeral_private) = generate();
+    let shared = MontgomeryPoint(key.0).mul_clamped(ephemeral_private.0);
+    let symmetric_key = derive_key(shared, &ephemeral_public, key)?;
+    let encrypted = symmetric_encrypt_with_nonce(&symmetric_key, &NONCE, data);
+
+    let mut sealed = Vec::with_capacity(32 + encrypted.data.len());
+    sealed.extend_from_slice(&ephemeral_public.0);
+    sealed.extend_from_slice(&encrypted.data);
+    Some(sealed)
+}
+
+/// Decrypts data produced by [`seal`], returning `None` if it was not sealed to `key` or was
+/// tampered with.
+pub fn open(key: &PrivateKey, data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 32 {
+        return None;
+    }
+    let (ephemeral, ciphertext) = data.split_at(32);
+    let ephemeral = PublicKey(TryFrom::try_from(ephemeral).ok()?);
+    let shared = MontgomeryPoint(ephemeral.0).mul_clamped(key.0);
+    let symmetric_key = derive_key(shared, &ephemeral, &key.public_key())?;
+    symmetric_try_decrypt(
+        &symmetric_key,
+        &SymmetricData {
+            nonce: NONCE.to_vec(),
+            data: ciphertext.to_vec(),
+        },
+    )
+}
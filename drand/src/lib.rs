@@ -1,8 +1,10 @@
 use std::{
     borrow::Cow,
     collections::HashMap,
+    future::Future,
+    pin::Pin,
     sync::Arc,
-    time::{Instant, SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use drand_client_rs::verify::{verify_on_g1, verify_on_g2};
@@ -12,6 +14,12 @@ use sha2::{Digest, Sha256};
 pub use drand_client_rs::verify::VerificationError;
 use tokio::sync::{Mutex, MutexGuard};
 
+pub use local::{LocalBeaconSource, LOCAL_GENESIS_TIME};
+pub use relay::{RelayOptions, RelayPool, DEFAULT_RELAY_TIMEOUT};
+
+mod local;
+mod relay;
+
 pub const DEFAULT_API_URL: &'static str = "https://api.drand.sh";
 
 const DST_G1: &str = "BLS_SIG_BLS12381G1_XMD:SHA-256_SSWU_RO_NUL_";
@@ -25,6 +33,18 @@ pub enum SchemeId {
     UnchainedOnG1RFC9380,
 }
 
+impl SchemeId {
+    /// Name of the scheme in the drand API.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SchemeId::PedersenBlsChained => "pedersen-bls-chained",
+            SchemeId::PedersenBlsUnchained => "pedersen-bls-unchained",
+            SchemeId::UnchainedOnG1 => "bls-unchained-on-g1",
+            SchemeId::UnchainedOnG1RFC9380 => "bls-unchained-g1-rfc9380",
+        }
+    }
+}
+
 impl From<drand_client_rs::verify::SchemeID> for SchemeId {
     fn from(value: drand_client_rs::verify::SchemeID) -> Self {
         match value {
@@ -40,7 +60,7 @@ impl From<drand_client_rs::verify::SchemeID> for SchemeId {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ChainInfoMetadata {
     pub beacon_id: String,
 }
@@ -53,7 +73,7 @@ impl From<drand_client_rs::chain_info::ChainInfoMetadata> for ChainInfoMetadata
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ChainInfo {
     pub scheme_id: SchemeId,
     pub public_key: Vec<u8>,
@@ -64,6 +84,41 @@ pub struct ChainInfo {
     pub metadata: ChainInfoMetadata,
 }
 
+impl ChainInfo {
+    /// Round produced at `unix_time`, 0 before the genesis.
+    pub fn round_at(&self, unix_time: u64) -> u64 {
+        match unix_time.checked_sub(self.genesis_time) {
+            Some(elapsed) => elapsed / u64::from(self.period_seconds.max(1)) + 1,
+            None => 0,
+        }
+    }
+
+    /// Unix time at which `round` is produced.
+    pub fn round_time(&self, round: u64) -> u64 {
+        self.genesis_time + round.saturating_sub(1) * u64::from(self.period_seconds.max(1))
+    }
+
+    /// Whether this is the default beacon of its network, whose hash leaves out the scheme.
+    pub fn is_default_beacon(&self) -> bool {
+        matches!(self.metadata.beacon_id.as_str(), "" | "default")
+    }
+
+    /// Chain hash computed the way drand does, over the period, genesis time, public key, group
+    /// hash and, unless this is the default beacon, the scheme and beacon id.
+    pub fn hash(&self) -> Vec<u8> {
+        let mut hasher = Sha256::default();
+        hasher.update(self.period_seconds.to_be_bytes());
+        hasher.update(self.genesis_time.to_be_bytes());
+        hasher.update(&self.public_key);
+        hasher.update(&self.group_hash);
+        if !self.is_default_beacon() {
+            hasher.update(self.scheme_id.name());
+            hasher.update(&self.metadata.beacon_id);
+        }
+        hasher.finalize().to_vec()
+    }
+}
+
 impl std::fmt::Debug for ChainInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ChainInfo")
@@ -92,7 +147,7 @@ impl From<drand_client_rs::chain_info::ChainInfo> for ChainInfo {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Beacon {
     pub round_number: u64,
     pub randomness: Vec<u8>,
@@ -166,6 +221,111 @@ impl Beacon {
     }
 }
 
+/// Chain hashes and public keys of the drand mainnet chains: default, fastnet and quicknet.
+const WELL_KNOWN_CHAINS: [(&str, &str); 3] = [
+    (
+        "8990e7a9aaed2ffed73dbd7092123d6f289930540d7651336225dc172e51b2ce",
+        "868f005eb8e6e4ca0a47c8a77ceaa5309a47978a7c71bc5cce96366b5d7a569937c529eeda66c7293784a9402801af31",
+    ),
+    (
+        "dbd506d6ef76e5f386f41c651dcb808c5bcbd75471cc4eafa3f4df7ad4e4c493",
+        "a0b862a7527fee3a731bcb59280ab6abd62d5c0b6ea03dc4ddf6612fdfc9d01f01c31542541771903475eb1ec6615f8d0df0b8b6dce385811d6dcf8cbefb8759e5e616a3dfd054c928940766d9a5b9db91e3b697e5d70a975181e007f87fca5e",
+    ),
+    (
+        "52db9ba70e0cc0f6eaf7803dd07447a1f5477735fd3f661792ba94600c84e971",
+        "83cf0f2896adee7eb8b5f01fcad3912212c437e0073e911fb90022d3e760183c8c4b450b6a0a6c3ac6a5776a2d1064510d1fec758c921cc22b0e17e63aaf4bcb5ed66304de9cf809bd274ca73bab4af5a6e9c76a4bc09e76eae8991ef5ece45a",
+    ),
+];
+
+/// Chain whose info is only trusted if it has this hash and public key. As the hash is recomputed
+/// from the info, this pins the whole chain info.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PinnedChain {
+    pub chain_hash: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+impl PinnedChain {
+    pub fn well_known() -> Vec<PinnedChain> {
+        WELL_KNOWN_CHAINS
+            .iter()
+            .map(|(chain_hash, public_key)| PinnedChain {
+                chain_hash: hex::decode(chain_hash).unwrap(),
+                public_key: hex::decode(public_key).unwrap(),
+            })
+            .collect()
+    }
+
+    /// Name of the chain in the relay API.
+    pub fn chain(&self) -> String {
+        hex::encode(&self.chain_hash)
+    }
+
+    fn check(&self, info: &ChainInfo) -> Result<(), ClientError> {
+        // default beacons predate the other schemes, which is why drand does not hash theirs
+        if info.chain_hash != self.chain_hash
+            || info.hash() != self.chain_hash
+            || info.public_key != self.public_key
+            || (info.is_default_beacon() && info.scheme_id != SchemeId::PedersenBlsChained)
+        {
+            return Err(ClientError(
+                format!("chain info does not match pinned chain {}", self.chain()).into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl From<&ChainInfo> for PinnedChain {
+    fn from(info: &ChainInfo) -> Self {
+        Self {
+            chain_hash: info.chain_hash.clone(),
+            public_key: info.public_key.clone(),
+        }
+    }
+}
+
+impl std::fmt::Debug for PinnedChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl std::fmt::Display for PinnedChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.chain(), hex::encode(&self.public_key))
+    }
+}
+
+/// Parses `<chain hash>:<public key>`, both hex encoded.
+impl std::str::FromStr for PinnedChain {
+    type Err = ClientError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ClientError(format!("invalid pinned chain {s}").into());
+        let (chain_hash, public_key) = s.split_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            chain_hash: hex::decode(chain_hash).map_err(|_| invalid())?,
+            public_key: hex::decode(public_key).map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Source of the current time, which tests replace.
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    /// Time elapsed since the unix epoch.
+    fn unix_time(&self) -> Duration;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn unix_time(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+}
+
 #[derive(Debug)]
 pub struct ClientError(Box<dyn std::error::Error + Send + Sync>);
 
@@ -181,12 +341,113 @@ impl std::error::Error for ClientError {
     }
 }
 
+impl From<VerificationError> for ClientError {
+    fn from(value: VerificationError) -> Self {
+        Self(Box::new(value))
+    }
+}
+
 impl From<reqwest::Error> for ClientError {
     fn from(value: reqwest::Error) -> Self {
         Self(Box::new(value))
     }
 }
 
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Provider of drand chains and their beacons.
+pub trait BeaconSource: std::fmt::Debug + Send + Sync {
+    fn chain_list(&self) -> BoxFuture<'_, Result<Vec<String>, ClientError>>;
+
+    fn chain_info<'a>(&'a self, chain: &'a str) -> BoxFuture<'a, Result<ChainInfo, ClientError>>;
+
+    fn chain_randomness<'a>(
+        &'a self,
+        chain: &'a str,
+        round: u64,
+    ) -> BoxFuture<'a, Result<Beacon, ClientError>>;
+
+    fn chain_latest_randomness<'a>(
+        &'a self,
+        chain: &'a str,
+    ) -> BoxFuture<'a, Result<Beacon, ClientError>>;
+}
+
+/// Where beacons come from, as given on the command line: comma separated URLs of drand HTTP
+/// relays, or `local` or `local:<period>` for a [`LocalBeaconSource`] with the built-in seed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BeaconSourceConfig {
+    Relays(Vec<String>),
+    Local { period_seconds: u32 },
+}
+
+impl BeaconSourceConfig {
+    pub const DEFAULT_LOCAL_PERIOD: u32 = 3;
+
+    /// Source given by the `DRAND_SOURCE` environment variable, or the default relay.
+    pub fn from_env() -> Result<Self, ClientError> {
+        match std::env::var("DRAND_SOURCE") {
+            Ok(source) => source.parse(),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Client of the source, `options` only apply to relays. The local chain is pinned along
+    /// with the well-known ones.
+    pub fn caching_client(&self, options: RelayOptions) -> CachingClient {
+        match self {
+            BeaconSourceConfig::Relays(urls) => CachingClient::with_relays(urls.clone(), options),
+            BeaconSourceConfig::Local { period_seconds } => {
+                let source = LocalBeaconSource::from_period(*period_seconds);
+                let pinned = PinnedChain::from(source.info());
+                CachingClient::with_source(source).pin(pinned)
+            }
+        }
+    }
+}
+
+impl Default for BeaconSourceConfig {
+    fn default() -> Self {
+        BeaconSourceConfig::Relays(vec![DEFAULT_API_URL.to_string()])
+    }
+}
+
+impl std::fmt::Display for BeaconSourceConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BeaconSourceConfig::Relays(urls) => f.write_str(&urls.join(",")),
+            BeaconSourceConfig::Local { period_seconds } => write!(f, "local:{period_seconds}"),
+        }
+    }
+}
+
+impl std::str::FromStr for BeaconSourceConfig {
+    type Err = ClientError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            _ if s == "local" => Ok(BeaconSourceConfig::Local {
+                period_seconds: Self::DEFAULT_LOCAL_PERIOD,
+            }),
+            Some(("local", period)) => match period.parse() {
+                Ok(period_seconds) if period_seconds > 0 => {
+                    Ok(BeaconSourceConfig::Local { period_seconds })
+                }
+                _ => Err(ClientError(format!("invalid local period {period}").into())),
+            },
+            Some(("http" | "https", _)) => s
+                .split(',')
+                .map(|url| match url.split_once(':') {
+                    Some(("http" | "https", _)) => Ok(url.trim_end_matches('/').to_string()),
+                    _ => Err(ClientError(format!("invalid relay url {url}").into())),
+                })
+                .collect::<Result<_, _>>()
+                .map(BeaconSourceConfig::Relays),
+            _ => Err(ClientError(format!("invalid beacon source {s}").into())),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BasicClient {
     base_url: Cow<'static, str>,
@@ -232,11 +493,40 @@ impl BasicClient {
     }
 }
 
+impl BeaconSource for BasicClient {
+    fn chain_list(&self) -> BoxFuture<'_, Result<Vec<String>, ClientError>> {
+        Box::pin(BasicClient::chain_list(self))
+    }
+
+    fn chain_info<'a>(&'a self, chain: &'a str) -> BoxFuture<'a, Result<ChainInfo, ClientError>> {
+        Box::pin(BasicClient::chain_info(self, chain))
+    }
+
+    fn chain_randomness<'a>(
+        &'a self,
+        chain: &'a str,
+        round: u64,
+    ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+        Box::pin(BasicClient::chain_randomness(self, chain, round))
+    }
+
+    fn chain_latest_randomness<'a>(
+        &'a self,
+        chain: &'a str,
+    ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+        Box::pin(BasicClient::chain_latest_randomness(self, chain))
+    }
+}
+
+/// Delay before asking again for the beacon of a round that started but that the source did not
+/// return yet.
+const BEACON_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
 #[derive(Debug, Clone)]
 struct CacheBeaconEntry {
-    timestamp: Instant,
     beacon: Beacon,
-    ttl: u64,
+    /// Unix time at which the beacon was fetched.
+    fetched_at: Duration,
 }
 
 #[derive(Debug, Clone)]
@@ -252,20 +542,60 @@ struct Cache {
 
 #[derive(Debug, Clone)]
 pub struct CachingClient {
-    client: BasicClient,
+    client: Arc<dyn BeaconSource>,
     cache: Arc<Mutex<Cache>>,
+    /// Chains that can be used, by name.
+    pinned: Arc<HashMap<String, PinnedChain>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl CachingClient {
     pub fn new(base_url: impl Into<Cow<'static, str>>) -> Self {
+        Self::with_source(BasicClient::new(base_url))
+    }
+
+    /// Client of `source` trusting the [well-known](PinnedChain::well_known) chains.
+    pub fn with_source(source: impl BeaconSource + 'static) -> Self {
+        let pinned = PinnedChain::well_known()
+            .into_iter()
+            .map(|pinned| (pinned.chain(), pinned))
+            .collect();
         Self {
-            client: BasicClient::new(base_url),
+            client: Arc::new(source),
             cache: Default::default(),
+            pinned: Arc::new(pinned),
+            clock: Arc::new(SystemClock),
         }
     }
 
+    /// Uses `clock` to tell which round is the latest.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Also trusts `pinned`, replacing the pin of the same chain.
+    pub fn pin(mut self, pinned: PinnedChain) -> Self {
+        Arc::make_mut(&mut self.pinned).insert(pinned.chain(), pinned);
+        self
+    }
+
+    pub fn is_pinned(&self, chain: &str) -> bool {
+        self.pinned.contains_key(chain)
+    }
+
+    pub fn with_relays<U>(urls: impl IntoIterator<Item = U>, options: RelayOptions) -> Self
+    where
+        U: Into<Cow<'static, str>>,
+    {
+        Self::with_source(RelayPool::from_urls(urls, options))
+    }
+
+    /// Chains of the source that are pinned.
     pub async fn chain_list(&self) -> Result<Vec<String>, ClientError> {
-        self.client.chain_list().await
+        let mut chains = self.client.chain_list().await?;
+        chains.retain(|chain| self.is_pinned(chain));
+        Ok(chains)
     }
 
     pub async fn chain_info(&self, chain: &str) -> Result<ChainInfo, ClientError> {
@@ -274,37 +604,81 @@ impl CachingClient {
     }
 
     pub async fn chain_randomness(&self, chain: &str, round: u64) -> Result<Beacon, ClientError> {
-        self.client.chain_randomness(chain, round).await
+        let info = self.chain_info(chain).await?;
+        let beacon = self.client.chain_randomness(chain, round).await?;
+        if beacon.round_number != round {
+            return Err(ClientError(
+                format!("expected round {round}, got {}", beacon.round_number).into(),
+            ));
+        }
+        beacon.verify(info.scheme_id, &info.public_key)?;
+        Ok(beacon)
     }
 
+    /// Latest beacon of `chain`, cached until the next round starts.
     pub async fn chain_latest_randomness(&self, chain: &str) -> Result<Beacon, ClientError> {
         let mut cache = self.cache.lock().await;
+        let info = self.chain_info_lk(chain, &mut cache).await?;
+        let now = self.clock.unix_time();
+        let expected_round = info.round_at(now.as_secs());
         if let Some(entry) = cache.beacon.get(chain) {
-            if entry.timestamp.elapsed().as_secs() > entry.ttl {
-                cache.beacon.remove(chain);
-            } else {
+            // sources publish a round a moment after it starts, they are not asked for it again
+            // right away
+            let round_start = Duration::from_secs(info.round_time(expected_round));
+            let retried =
+                entry.fetched_at >= round_start && now < entry.fetched_at + BEACON_RETRY_INTERVAL;
+            if entry.beacon.round_number >= expected_round || retried {
                 return Ok(entry.beacon.clone());
             }
         }
 
-        let info = self.chain_info_lk(chain, &mut cache).await?;
         let beacon = self.client.chain_latest_randomness(chain).await?;
-        let unix_now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let ttl = (unix_now - info.genesis_time) % info.period_seconds as u64;
+        beacon.verify(info.scheme_id, &info.public_key)?;
+        if beacon.round_number < expected_round {
+            tracing::debug!(
+                "chain {chain} is at round {}, expected {expected_round}",
+                beacon.round_number
+            );
+        }
         cache.beacon.insert(
             chain.to_string(),
             CacheBeaconEntry {
-                timestamp: Instant::now(),
                 beacon: beacon.clone(),
-                ttl,
+                fetched_at: now,
             },
         );
         Ok(beacon)
     }
 
+    /// Fetches the latest beacon of `chain` as each round starts, so that requests find it in
+    /// the cache. Never returns, it is meant to be spawned.
+    pub async fn prefetch(&self, chain: &str) {
+        loop {
+            let wait = match self.prefetch_once(chain).await {
+                Ok(wait) => wait,
+                Err(err) => {
+                    tracing::warn!("failed to prefetch beacon of chain {chain}: {err}");
+                    BEACON_RETRY_INTERVAL
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Refreshes the cached beacon of `chain`, returning the time until it must be refreshed
+    /// again.
+    async fn prefetch_once(&self, chain: &str) -> Result<Duration, ClientError> {
+        let beacon = self.chain_latest_randomness(chain).await?;
+        let info = self.chain_info(chain).await?;
+        let next_round = Duration::from_secs(info.round_time(beacon.round_number + 1));
+        let now = self.clock.unix_time();
+        if next_round <= now {
+            Ok(BEACON_RETRY_INTERVAL)
+        } else {
+            Ok(next_round - now)
+        }
+    }
+
     async fn chain_info_lk(
         &self,
         chain: &str,
@@ -314,11 +688,25 @@ impl CachingClient {
             return Ok(entry.info.clone());
         }
 
+        let pinned = self
+            .pinned
+            .get(chain)
+            .ok_or_else(|| ClientError(format!("chain {chain} is not pinned").into()))?;
         let info = self.client.chain_info(chain).await?;
+        pinned.check(&info)?;
         let entry = CachedInfoEntry { info: info.clone() };
         cache.info.insert(chain.to_string(), entry);
         Ok(info)
     }
+
+    pub async fn get_beacon_from_first_chain(&self) -> Result<Beacon, ClientError> {
+        let mut chains = self.chain_list().await?;
+        chains.sort();
+        let chain = chains
+            .first()
+            .ok_or_else(|| ClientError("no chain".into()))?;
+        self.chain_latest_randomness(chain).await
+    }
 }
 
 pub async fn chain_list() -> Result<Vec<String>, ClientError> {
@@ -350,3 +738,190 @@ pub async fn get_beacon_from_first_chain() -> Result<Beacon, ClientError> {
         .ok_or_else(|| ClientError("no chain".into()))?;
     client.chain_latest_randomness(chain).await
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct MockClock(std::sync::Mutex<Duration>);
+
+    impl MockClock {
+        fn set(&self, unix_time: Duration) {
+            *self.0.lock().unwrap() = unix_time;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn unix_time(&self) -> Duration {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    /// Counts the latest beacon requests, and answers them with the previous round when lagging.
+    #[derive(Debug)]
+    struct CountingSource {
+        source: LocalBeaconSource,
+        clock: Arc<MockClock>,
+        fetches: Arc<AtomicUsize>,
+        lagging: Arc<AtomicBool>,
+    }
+
+    impl BeaconSource for CountingSource {
+        fn chain_list(&self) -> BoxFuture<'_, Result<Vec<String>, ClientError>> {
+            self.source.chain_list()
+        }
+
+        fn chain_info<'a>(
+            &'a self,
+            chain: &'a str,
+        ) -> BoxFuture<'a, Result<ChainInfo, ClientError>> {
+            self.source.chain_info(chain)
+        }
+
+        fn chain_randomness<'a>(
+            &'a self,
+            chain: &'a str,
+            round: u64,
+        ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+            self.source.chain_randomness(chain, round)
+        }
+
+        fn chain_latest_randomness<'a>(
+            &'a self,
+            _: &'a str,
+        ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+            self.fetches.fetch_add(1, Ordering::Relaxed);
+            let mut round = self
+                .source
+                .info()
+                .round_at(self.clock.unix_time().as_secs());
+            if self.lagging.load(Ordering::Relaxed) {
+                round -= 1;
+            }
+            let beacon = self.source.beacon(round);
+            Box::pin(async move { Ok(beacon) })
+        }
+    }
+
+    #[tokio::test]
+    async fn round_aware_cache() {
+        let clock = Arc::new(MockClock::default());
+        let at = |seconds: f64| Duration::from_secs_f64(LOCAL_GENESIS_TIME as f64 + seconds);
+        let local = LocalBeaconSource::from_period(3).with_clock(clock.clone());
+        let chain = local.chain().to_string();
+        let pinned = PinnedChain::from(local.info());
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let lagging = Arc::new(AtomicBool::new(false));
+        let source = CountingSource {
+            source: local,
+            clock: clock.clone(),
+            fetches: fetches.clone(),
+            lagging: lagging.clone(),
+        };
+        let client = CachingClient::with_source(source)
+            .pin(pinned)
+            .with_clock(clock.clone());
+        let latest = || async { client.chain_latest_randomness(&chain).await.unwrap() };
+        let fetches = || fetches.load(Ordering::Relaxed);
+
+        // round 11 lasts from 30 to 33 seconds after the genesis
+        clock.set(at(30.0));
+        assert_eq!(latest().await.round_number, 11);
+        clock.set(at(32.9));
+        assert_eq!(latest().await.round_number, 11);
+        assert_eq!(fetches(), 1);
+
+        clock.set(at(33.0));
+        assert_eq!(latest().await.round_number, 12);
+        assert_eq!(fetches(), 2);
+        assert_eq!(
+            client.prefetch_once(&chain).await.unwrap(),
+            Duration::from_secs(3)
+        );
+        assert_eq!(fetches(), 2);
+
+        // the source is late with round 13, it is asked again after a while
+        lagging.store(true, Ordering::Relaxed);
+        clock.set(at(36.0));
+        assert_eq!(latest().await.round_number, 12);
+        clock.set(at(36.2));
+        assert_eq!(latest().await.round_number, 12);
+        assert_eq!(fetches(), 3);
+        assert_eq!(
+            client.prefetch_once(&chain).await.unwrap(),
+            BEACON_RETRY_INTERVAL
+        );
+
+        lagging.store(false, Ordering::Relaxed);
+        clock.set(at(36.6));
+        assert_eq!(latest().await.round_number, 13);
+        assert_eq!(fetches(), 4);
+        clock.set(at(36.7));
+        let wait = client.prefetch_once(&chain).await.unwrap();
+        assert!(wait > Duration::from_millis(2200) && wait <= Duration::from_millis(2300));
+    }
+
+    #[tokio::test]
+    async fn pinned_chains() {
+        let source = LocalBeaconSource::from_period(3);
+        let chain = source.chain().to_string();
+        let pinned = PinnedChain::from(source.info());
+
+        let unpinned = CachingClient::with_source(source.clone());
+        assert!(unpinned.chain_list().await.unwrap().is_empty());
+        assert!(unpinned.chain_info(&chain).await.is_err());
+        assert!(unpinned.chain_latest_randomness(&chain).await.is_err());
+
+        let client = CachingClient::with_source(source.clone()).pin(pinned.clone());
+        assert_eq!(client.chain_list().await.unwrap(), vec![chain.clone()]);
+        assert_eq!(client.chain_info(&chain).await.unwrap(), *source.info());
+        assert!(client.chain_randomness(&chain, 1).await.is_ok());
+
+        let other = LocalBeaconSource::new(b"other", LOCAL_GENESIS_TIME, 3);
+        let wrong_key = PinnedChain {
+            public_key: other.info().public_key.clone(),
+            ..pinned.clone()
+        };
+        let client = CachingClient::with_source(source.clone()).pin(wrong_key);
+        assert!(client.chain_info(&chain).await.is_err());
+
+        // info keeping the pinned hash and key but changing what they were computed from
+        let mut info = source.info().clone();
+        assert!(pinned.check(&info).is_ok());
+        info.period_seconds = 30;
+        assert!(pinned.check(&info).is_err());
+        let mut info = source.info().clone();
+        info.genesis_time += 1;
+        assert!(pinned.check(&info).is_err());
+        let mut info = source.info().clone();
+        info.scheme_id = SchemeId::UnchainedOnG1;
+        assert!(pinned.check(&info).is_err());
+
+        assert_eq!(pinned.to_string().parse::<PinnedChain>().unwrap(), pinned);
+        assert_eq!(PinnedChain::well_known().len(), WELL_KNOWN_CHAINS.len());
+    }
+
+    #[test]
+    fn mainnet_chain_hash() {
+        let [(chain_hash, public_key), ..] = WELL_KNOWN_CHAINS;
+        let info = ChainInfo {
+            scheme_id: SchemeId::PedersenBlsChained,
+            public_key: hex::decode(public_key).unwrap(),
+            chain_hash: hex::decode(chain_hash).unwrap(),
+            group_hash: hex::decode(
+                "176f93498eac9ca337150b46d21dd58673ea4e3581185f869672e59fa4cb390a",
+            )
+            .unwrap(),
+            genesis_time: 1595431050,
+            period_seconds: 30,
+            metadata: ChainInfoMetadata {
+                beacon_id: "default".to_string(),
+            },
+        };
+        assert_eq!(info.hash(), info.chain_hash);
+        assert!(PinnedChain::well_known()[0].check(&info).is_ok());
+    }
+}
@@ -0,0 +1,187 @@
+use std::sync::Arc;
+
+use bls12_381::{
+    hash_to_curve::{ExpandMsgXmd, HashToCurve},
+    G1Affine, G1Projective, G2Affine, Scalar,
+};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::{
+    Beacon, BeaconSource, BoxFuture, ChainInfo, ChainInfoMetadata, ClientError, Clock, SchemeId,
+    SystemClock,
+};
+
+/// Genesis time of the chains generated by [`LocalBeaconSource::from_period`].
+pub const LOCAL_GENESIS_TIME: u64 = 1_700_000_000;
+
+/// Seed of the key of the chains generated by [`LocalBeaconSource::from_period`].
+const LOCAL_SEED: &[u8] = b"anonycast local beacon";
+
+/// Generates the beacons of an unchained BLS chain from a local key, so the system can run without
+/// reaching a drand relay. Sources created from the same seed, genesis time and period produce
+/// the same chain. Anyone knowing the seed can forge beacons, so this is only meant for testing
+/// and offline use.
+#[derive(Debug, Clone)]
+pub struct LocalBeaconSource {
+    secret_key: Scalar,
+    info: ChainInfo,
+    chain: String,
+    clock: Arc<dyn Clock>,
+}
+
+impl LocalBeaconSource {
+    pub fn new(seed: &[u8], genesis_time: u64, period_seconds: u32) -> Self {
+        let secret_key = Scalar::from_bytes_wide(&Sha512::digest(seed).into());
+        let public_key = G2Affine::from(G2Affine::generator() * secret_key)
+            .to_compressed()
+            .to_vec();
+
+        let mut info = ChainInfo {
+            scheme_id: SchemeId::UnchainedOnG1RFC9380,
+            group_hash: Sha256::digest(&public_key).to_vec(),
+            public_key,
+            chain_hash: Vec::new(),
+            genesis_time,
+            period_seconds: period_seconds.max(1),
+            metadata: ChainInfoMetadata {
+                beacon_id: "local".to_string(),
+            },
+        };
+        info.chain_hash = info.hash();
+        Self {
+            secret_key,
+            chain: hex::encode(&info.chain_hash),
+            info,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Uses `clock` to tell which rounds were produced.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Chain with the built-in seed and genesis time, producing a beacon every `period_seconds`.
+    pub fn from_period(period_seconds: u32) -> Self {
+        Self::new(LOCAL_SEED, LOCAL_GENESIS_TIME, period_seconds)
+    }
+
+    /// Hash of the generated chain, the only one listed by the source.
+    pub fn chain(&self) -> &str {
+        &self.chain
+    }
+
+    pub fn info(&self) -> &ChainInfo {
+        &self.info
+    }
+
+    /// Round produced at `unix_time`, the first round being produced at the genesis time.
+    pub fn round_at(&self, unix_time: u64) -> Option<u64> {
+        Some(self.info.round_at(unix_time)).filter(|round| *round > 0)
+    }
+
+    pub fn beacon(&self, round: u64) -> Beacon {
+        let mut beacon = Beacon {
+            round_number: round,
+            randomness: Vec::new(),
+            signature: Vec::new(),
+            previous_signature: Vec::new(),
+        };
+        let message = beacon.unchained_message();
+        let point = <G1Projective as HashToCurve<ExpandMsgXmd<sha2_09::Sha256>>>::hash_to_curve(
+            message,
+            crate::DST_G1.as_bytes(),
+        );
+        let signature = G1Affine::from(point * self.secret_key).to_compressed();
+        beacon.randomness = Sha256::digest(signature).to_vec();
+        beacon.signature = signature.to_vec();
+        beacon
+    }
+
+    fn check_chain(&self, chain: &str) -> Result<(), ClientError> {
+        if chain == self.chain {
+            Ok(())
+        } else {
+            Err(ClientError(format!("unknown local chain {chain}").into()))
+        }
+    }
+
+    fn current_round(&self) -> Result<u64, ClientError> {
+        self.round_at(self.clock.unix_time().as_secs())
+            .ok_or_else(|| ClientError("local chain has not started".into()))
+    }
+}
+
+impl BeaconSource for LocalBeaconSource {
+    fn chain_list(&self) -> BoxFuture<'_, Result<Vec<String>, ClientError>> {
+        Box::pin(async move { Ok(vec![self.chain.clone()]) })
+    }
+
+    fn chain_info<'a>(&'a self, chain: &'a str) -> BoxFuture<'a, Result<ChainInfo, ClientError>> {
+        Box::pin(async move {
+            self.check_chain(chain)?;
+            Ok(self.info.clone())
+        })
+    }
+
+    fn chain_randomness<'a>(
+        &'a self,
+        chain: &'a str,
+        round: u64,
+    ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+        Box::pin(async move {
+            self.check_chain(chain)?;
+            if round == 0 || round > self.current_round()? {
+                return Err(ClientError(
+                    format!("round {round} was not produced").into(),
+                ));
+            }
+            Ok(self.beacon(round))
+        })
+    }
+
+    fn chain_latest_randomness<'a>(
+        &'a self,
+        chain: &'a str,
+    ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+        Box::pin(async move {
+            self.check_chain(chain)?;
+            Ok(self.beacon(self.current_round()?))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn local_beacons_verify() {
+        let source = LocalBeaconSource::from_period(3);
+        let info = source.info();
+        let beacon = source.beacon(42);
+        assert!(beacon.verify(info.scheme_id, &info.public_key).is_ok());
+
+        // the chain is deterministic but rounds cannot be swapped
+        let mut forged = LocalBeaconSource::from_period(3).beacon(43);
+        assert_eq!(forged.signature, source.beacon(43).signature);
+        forged.round_number = 42;
+        assert!(forged.verify(info.scheme_id, &info.public_key).is_err());
+
+        let other = LocalBeaconSource::new(b"other", LOCAL_GENESIS_TIME, 3);
+        assert_ne!(other.chain(), source.chain());
+        assert!(beacon
+            .verify(info.scheme_id, &other.info().public_key)
+            .is_err());
+    }
+
+    #[test]
+    fn local_rounds() {
+        let source = LocalBeaconSource::from_period(3);
+        assert_eq!(source.round_at(LOCAL_GENESIS_TIME - 1), None);
+        assert_eq!(source.round_at(LOCAL_GENESIS_TIME), Some(1));
+        assert_eq!(source.round_at(LOCAL_GENESIS_TIME + 2), Some(1));
+        assert_eq!(source.round_at(LOCAL_GENESIS_TIME + 3), Some(2));
+    }
+}
@@ -0,0 +1,363 @@
+use std::{borrow::Cow, sync::Arc, time::Duration};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+
+use crate::{BasicClient, Beacon, BeaconSource, BoxFuture, ChainInfo, ClientError};
+
+pub const DEFAULT_RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How a [`RelayPool`] queries its relays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayOptions {
+    /// Time after which a relay that has not answered is skipped.
+    pub timeout: Duration,
+    /// Relays that must return the same chain info and beacons, at least 1.
+    pub agreement: usize,
+}
+
+impl Default for RelayOptions {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_RELAY_TIMEOUT,
+            agreement: 1,
+        }
+    }
+}
+
+/// Several relays of the same chains. Requests go to every relay at once and the first
+/// `agreement` answers are kept, so failing relays only delay requests if too few others answer.
+/// The chain info and beacons of these answers must be identical, so a single relay cannot
+/// mislead the client.
+#[derive(Debug)]
+pub struct RelayPool {
+    relays: Vec<Arc<dyn BeaconSource>>,
+    options: RelayOptions,
+}
+
+impl RelayPool {
+    pub fn new(relays: Vec<Arc<dyn BeaconSource>>, options: RelayOptions) -> Self {
+        assert!(!relays.is_empty(), "at least one relay is required");
+        let agreement = options.agreement.clamp(1, relays.len());
+        if agreement != options.agreement {
+            tracing::warn!("only {agreement} relays can agree on drand beacons");
+        }
+        Self {
+            relays,
+            options: RelayOptions {
+                agreement,
+                ..options
+            },
+        }
+    }
+
+    pub fn from_urls<U>(urls: impl IntoIterator<Item = U>, options: RelayOptions) -> Self
+    where
+        U: Into<Cow<'static, str>>,
+    {
+        let relays = urls
+            .into_iter()
+            .map(|url| Arc::new(BasicClient::new(url)) as Arc<dyn BeaconSource>)
+            .collect();
+        Self::new(relays, options)
+    }
+
+    /// Answers of the first `count` relays to answer `request`, which is sent to all of them at
+    /// once. The other requests are dropped once enough relays answered.
+    async fn query<'a, T>(
+        &'a self,
+        count: usize,
+        request: impl Fn(&'a dyn BeaconSource) -> BoxFuture<'a, Result<T, ClientError>>,
+    ) -> Result<Vec<T>, ClientError> {
+        let mut pending = self
+            .relays
+            .iter()
+            .enumerate()
+            .map(|(index, relay)| {
+                let answer = tokio::time::timeout(self.options.timeout, request(&**relay));
+                async move { (index, answer.await) }
+            })
+            .collect::<FuturesUnordered<_>>();
+        let mut answers = Vec::with_capacity(count);
+        let mut last_error = None;
+        while let Some((index, answer)) = pending.next().await {
+            match answer {
+                Ok(Ok(answer)) => {
+                    answers.push(answer);
+                    if answers.len() == count {
+                        return Ok(answers);
+                    }
+                }
+                Ok(Err(err)) => {
+                    tracing::warn!("drand relay {index} failed: {err}");
+                    last_error = Some(err.to_string());
+                }
+                Err(_) => {
+                    tracing::warn!("drand relay {index} timed out");
+                    last_error = Some("timed out".to_string());
+                }
+            }
+        }
+        Err(ClientError(
+            format!(
+                "{} of {count} drand relays answered, last error: {}",
+                answers.len(),
+                last_error.unwrap_or_default()
+            )
+            .into(),
+        ))
+    }
+
+    async fn query_agreed<'a, T: PartialEq>(
+        &'a self,
+        what: &str,
+        request: impl Fn(&'a dyn BeaconSource) -> BoxFuture<'a, Result<T, ClientError>>,
+    ) -> Result<T, ClientError> {
+        let mut answers = self.query(self.options.agreement, request).await?;
+        if answers.iter().any(|answer| *answer != answers[0]) {
+            return Err(ClientError(
+                format!("drand relays disagree on {what}").into(),
+            ));
+        }
+        Ok(answers.swap_remove(0))
+    }
+}
+
+impl BeaconSource for RelayPool {
+    /// Chain list of a single relay, the chains themselves are checked by the other requests.
+    fn chain_list(&self) -> BoxFuture<'_, Result<Vec<String>, ClientError>> {
+        Box::pin(async move {
+            let mut answers = self.query(1, |relay| relay.chain_list()).await?;
+            Ok(answers.swap_remove(0))
+        })
+    }
+
+    fn chain_info<'a>(&'a self, chain: &'a str) -> BoxFuture<'a, Result<ChainInfo, ClientError>> {
+        Box::pin(self.query_agreed("chain info", move |relay| relay.chain_info(chain)))
+    }
+
+    fn chain_randomness<'a>(
+        &'a self,
+        chain: &'a str,
+        round: u64,
+    ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+        Box::pin(self.query_agreed("beacon", move |relay| relay.chain_randomness(chain, round)))
+    }
+
+    /// Latest beacon of the first relay to answer, fetched from the others by its round since
+    /// relays may be a round apart. The previous round is agreed on instead if the others do not
+    /// have that round yet.
+    fn chain_latest_randomness<'a>(
+        &'a self,
+        chain: &'a str,
+    ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+        Box::pin(async move {
+            let mut answers = self
+                .query(1, |relay| relay.chain_latest_randomness(chain))
+                .await?;
+            let latest = answers.swap_remove(0);
+            if self.options.agreement == 1 {
+                return Ok(latest);
+            }
+            let err = match self.chain_randomness(chain, latest.round_number).await {
+                Ok(beacon) if beacon == latest => return Ok(beacon),
+                Ok(_) => ClientError("drand relays disagree on beacon".into()),
+                Err(err) => err,
+            };
+            if latest.round_number <= 1 {
+                return Err(err);
+            }
+            tracing::debug!("round {} is not agreed on: {err}", latest.round_number);
+            self.chain_randomness(chain, latest.round_number - 1).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Clock, LocalBeaconSource, LOCAL_GENESIS_TIME};
+
+    #[derive(Debug)]
+    struct FailingSource;
+
+    impl BeaconSource for FailingSource {
+        fn chain_list(&self) -> BoxFuture<'_, Result<Vec<String>, ClientError>> {
+            Box::pin(async { Err(ClientError("down".into())) })
+        }
+
+        fn chain_info<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<ChainInfo, ClientError>> {
+            Box::pin(async { Err(ClientError("down".into())) })
+        }
+
+        fn chain_randomness<'a>(
+            &'a self,
+            _: &'a str,
+            _: u64,
+        ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+            Box::pin(async { Err(ClientError("down".into())) })
+        }
+
+        fn chain_latest_randomness<'a>(
+            &'a self,
+            _: &'a str,
+        ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    /// Serves its own chain under any name.
+    #[derive(Debug)]
+    struct ForgedSource(LocalBeaconSource);
+
+    impl BeaconSource for ForgedSource {
+        fn chain_list(&self) -> BoxFuture<'_, Result<Vec<String>, ClientError>> {
+            self.0.chain_list()
+        }
+
+        fn chain_info<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<ChainInfo, ClientError>> {
+            self.0.chain_info(self.0.chain())
+        }
+
+        fn chain_randomness<'a>(
+            &'a self,
+            _: &'a str,
+            round: u64,
+        ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+            self.0.chain_randomness(self.0.chain(), round)
+        }
+
+        fn chain_latest_randomness<'a>(
+            &'a self,
+            _: &'a str,
+        ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+            self.0.chain_latest_randomness(self.0.chain())
+        }
+    }
+
+    /// Answers like its source after a delay.
+    #[derive(Debug)]
+    struct DelayedSource(LocalBeaconSource);
+
+    impl DelayedSource {
+        async fn delay(&self) {
+            tokio::time::sleep(Duration::from_millis(10)).await
+        }
+    }
+
+    impl BeaconSource for DelayedSource {
+        fn chain_list(&self) -> BoxFuture<'_, Result<Vec<String>, ClientError>> {
+            Box::pin(async move {
+                self.delay().await;
+                self.0.chain_list().await
+            })
+        }
+
+        fn chain_info<'a>(
+            &'a self,
+            chain: &'a str,
+        ) -> BoxFuture<'a, Result<ChainInfo, ClientError>> {
+            Box::pin(async move {
+                self.delay().await;
+                self.0.chain_info(chain).await
+            })
+        }
+
+        fn chain_randomness<'a>(
+            &'a self,
+            chain: &'a str,
+            round: u64,
+        ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+            Box::pin(async move {
+                self.delay().await;
+                self.0.chain_randomness(chain, round).await
+            })
+        }
+
+        fn chain_latest_randomness<'a>(
+            &'a self,
+            chain: &'a str,
+        ) -> BoxFuture<'a, Result<Beacon, ClientError>> {
+            Box::pin(async move {
+                self.delay().await;
+                self.0.chain_latest_randomness(chain).await
+            })
+        }
+    }
+
+    #[derive(Debug)]
+    struct FixedClock(Duration);
+
+    impl Clock for FixedClock {
+        fn unix_time(&self) -> Duration {
+            self.0
+        }
+    }
+
+    fn pool(relays: Vec<Arc<dyn BeaconSource>>, agreement: usize) -> RelayPool {
+        let options = RelayOptions {
+            timeout: Duration::from_millis(100),
+            agreement,
+        };
+        RelayPool::new(relays, options)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn relay_failover() {
+        let local = Arc::new(LocalBeaconSource::from_period(3));
+        let chain = local.chain().to_string();
+        let failing = Arc::new(FailingSource);
+        let pool = pool(
+            vec![failing.clone(), failing.clone(), failing, local.clone()],
+            1,
+        );
+
+        assert_eq!(pool.chain_list().await.unwrap(), vec![chain.clone()]);
+        assert_eq!(pool.chain_info(&chain).await.unwrap(), *local.info());
+        // the latest beacon requests to the failing relays never complete, but are not waited for
+        let start = tokio::time::Instant::now();
+        let beacon = pool.chain_latest_randomness(&chain).await.unwrap();
+        assert_eq!(beacon, local.beacon(beacon.round_number));
+        assert!(start.elapsed() < pool.options.timeout);
+    }
+
+    #[tokio::test]
+    async fn relay_agreement() {
+        let local = Arc::new(LocalBeaconSource::from_period(3));
+        let chain = local.chain().to_string();
+
+        let agreeing = pool(
+            vec![local.clone(), Arc::new(FailingSource), local.clone()],
+            2,
+        );
+        assert!(agreeing.chain_info(&chain).await.is_ok());
+        assert!(agreeing.chain_latest_randomness(&chain).await.is_ok());
+
+        let short = pool(vec![local.clone(), Arc::new(FailingSource)], 2);
+        assert!(short.chain_randomness(&chain, 1).await.is_err());
+
+        // a relay serving another chain under the same name is caught
+        let forged = ForgedSource(LocalBeaconSource::new(b"forged", 0, 3));
+        let disagreeing = pool(vec![local.clone(), Arc::new(forged)], 2);
+        assert!(disagreeing.chain_info(&chain).await.is_err());
+        assert!(disagreeing.chain_randomness(&chain, 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn relay_round_behind() {
+        let at = |seconds| {
+            let clock = Arc::new(FixedClock(Duration::from_secs(
+                LOCAL_GENESIS_TIME + seconds,
+            )));
+            LocalBeaconSource::from_period(3).with_clock(clock)
+        };
+        let ahead = Arc::new(at(30));
+        let chain = ahead.chain().to_string();
+        let latest = ahead.chain_latest_randomness(&chain).await.unwrap();
+
+        // the relay ahead answers first, the others do not have its latest round yet
+        let behind = Arc::new(DelayedSource(at(27)));
+        let pool = pool(vec![behind.clone(), ahead.clone(), behind], 2);
+        let beacon = pool.chain_latest_randomness(&chain).await.unwrap();
+        assert_eq!(beacon, ahead.beacon(latest.round_number - 1));
+    }
+}